@@ -1,3 +1,8 @@
+.macro pulse port
+out \port, 1
+out \port, 0
+.endm
+
 mov A, 2
 mov B, 3
 mov C, 5
@@ -10,6 +15,5 @@ out C, 1
 clock:
 out B, E
 inc E
-out D, 1
-out D, 0
+pulse D
 jmp clock
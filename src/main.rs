@@ -12,8 +12,11 @@ pub mod particle;
 
 use std::io::{Write, Read};
 use std::fs::File;
+use std::path::Path;
 use std::io::Cursor;
 use std::f32;
+use std::rc::Rc;
+use std::cell::Cell;
 
 use glium::{Surface};
 use glium::glutin::{self, Event, WindowEvent, KeyboardInput};
@@ -24,6 +27,12 @@ use tile::TileAtlas;
 use tile::TileCollide;
 use tile::Door;
 
+/// Clock speed of the ship's ZPU, in cycles per second. Faster CPUs raise this.
+const SHIP_CPU_HZ: u64 = 10_000;
+/// The ship raises this IRQ line every `TIMER_PERIOD_NS` while the ZPU runs.
+const TIMER_IRQ: u32 = 0;
+const TIMER_PERIOD_NS: u64 = 100_000_000;
+
 #[derive(Copy, Clone)]
 struct Vert {
     position: [f32; 2],
@@ -32,6 +41,109 @@ struct Vert {
 
 implement_vertex!(Vert, position, tex_coords);
 
+/// Writes the terminal contents back to programs/hello.asm.
+fn save_source(terminal: &[String]) {
+    let mut file = File::create("programs/hello.asm").unwrap();
+    for line in terminal.iter() {
+        let mut nline = line.clone();
+        nline.push('\n');
+        file.write_all(nline.as_bytes()).unwrap();
+    }
+    file.sync_data().unwrap();
+}
+
+/// Assembles the terminal contents and, if that worked, swaps the program into the ZPU,
+/// optionally keeping the register values of the old one.
+fn load_source(zpu: &mut zpu::zpu::ZPU, source: &str, keep_registers: bool) -> zpu::assembler::AResult {
+    match zpu::assembler::assemble_with(source, &mut |file| zpu::assembler::read_include(Path::new("programs"), file)) {
+        Ok(program) => {
+            if keep_registers {
+                zpu.load_keeping_registers(&program);
+            } else {
+                zpu.load(&program);
+            }
+            zpu::assembler::AResult::new(Vec::new())
+        },
+        Err(errors) => zpu::assembler::AResult::new(errors),
+    }
+}
+
+/// The label defined on a terminal line, if any, so breakpoints can be set from the editor.
+fn label_on_line(line: &str) -> Option<String> {
+    let code = line.split(';').next().unwrap_or("");
+    let colon = code.find(':')?;
+    Some(code[..colon].trim().to_lowercase())
+}
+
+/// A watch on the first operand of the instruction on a terminal line: its register, or the
+/// memory word it addresses.
+fn watch_on_line(zpu: &zpu::zpu::ZPU, line: &str) -> Option<zpu::debugger::Watch> {
+    use zpu::assembler::Operand;
+    use zpu::debugger::Watch;
+
+    let parsed = zpu::assembler::parse_line(line, 1).ok()?;
+    match parsed.inst()?.args.first()?.operand {
+        Operand::Register(reg) => Some(Watch::Register(reg)),
+        Operand::Memory(ref inner) => match **inner {
+            Operand::Immediate(addr) => Some(Watch::Memory(addr)),
+            Operand::Label(ref label) => zpu.program.symbol(label).map(|sym| Watch::Memory(sym.addr)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Lines for the debug panel, shown in place of the reference guide.
+fn debug_panel(zpu: &zpu::zpu::ZPU, paused: bool) -> Vec<String> {
+    let mut lines: Vec<String> = zpu.dump().lines().map(String::from).collect();
+    lines.push(String::new());
+    for addr in zpu.debugger.breakpoints.iter() {
+        let name = match zpu.program.symbols.iter().find(|sym| sym.addr == *addr && sym.section == zpu::encoding::Section::Code) {
+            Some(sym) => sym.name.clone(),
+            None => format!("{:04x}", addr),
+        };
+        lines.push(format!("BREAK {}", name));
+    }
+    for watch in zpu.debugger.watchpoints.iter() {
+        lines.push(match *watch {
+            zpu::debugger::Watch::Register(reg) => format!("WATCH {}", reg.name().to_uppercase()),
+            zpu::debugger::Watch::Memory(addr) => format!("WATCH [{}]", addr),
+        });
+    }
+    lines.push(String::new());
+    lines.push(String::from(if paused { "PAUSED" } else { "" }));
+    if let Some(ref tracer) = zpu.tracer {
+        lines.push(format!("TRACING {} steps", tracer.steps()));
+    }
+    lines.push(String::from("F2 save  F3 restore  F4 reload"));
+    lines.push(String::from("F5 run  F6 pause  F7 trace  F8 watch"));
+    lines.push(String::from("F9 break  F10 over  F11 step"));
+    lines
+}
+
+/// Passes a debugger stop on to the rest of the frame: port output is handled as usual, and
+/// anything worth looking at pauses the ZPU.
+fn handle_stop(stop: zpu::debugger::Stop, result: &mut zpu::zpu::ZResult, paused: &mut bool) {
+    use zpu::debugger::Stop;
+
+    match stop {
+        Stop::Stepped(Some(output)) | Stop::Output(output) => result.output = Some(output),
+        Stop::Breakpoint(_) | Stop::Watchpoint(..) | Stop::Fault(_) => *paused = true,
+        Stop::Stepped(None) | Stop::Halted | Stop::Limit => (),
+    }
+}
+
+/// The same for a run of the ZPU.
+fn handle_run(outcome: zpu::zpu::RunOutcome, result: &mut zpu::zpu::ZResult, paused: &mut bool) {
+    use zpu::zpu::RunOutcome;
+
+    match outcome {
+        RunOutcome::Output(output) => result.output = Some(output),
+        RunOutcome::Breakpoint(_) | RunOutcome::Watchpoint(..) | RunOutcome::Fault(_) => *paused = true,
+        RunOutcome::Budget | RunOutcome::Halted => (),
+    }
+}
+
 fn identity() -> [[f32; 4]; 4] {
     [
         [1.0, 0.0, 0.0, 0.0],
@@ -408,7 +520,7 @@ fn main() {
     let mut camera = Entity::new(10.0, 5.0);
 
     let mut dt = 0.0;
-    let mut acc_time = 0.0;
+    let mut frame_ns = 0;
     let speed = 0.125;
 
     let mut collided = false;
@@ -417,6 +529,9 @@ fn main() {
 
     let mut term_ui = false;
     let mut ship_ui = false;
+    let mut debug_ui = false;
+    let mut paused = false;
+    let mut timer_ns = 0;
 
     let mut shift = false;
 
@@ -443,8 +558,13 @@ fn main() {
     let mut eng_id = off_engine_id;
     let mut tur_id = off_turret_id;
 
-    let mut err = zpu::assembler::assemble_program("programs/hello.asm", "programs/zpu.bin");
-    let mut zpu = zpu::zpu::ZPU::new("programs/zpu.bin");
+    let mut zpu = zpu::zpu::ZPU::from_program(&zpu::encoding::Program::new());
+    let mut err = load_source(&mut zpu, &term_string, false);
+
+    zpu.clock.hz = SHIP_CPU_HZ;
+
+    let power_port = Rc::new(Cell::new(0));
+    zpu.bus.attach(&[7], Box::new(zpu::bus::Latch::new(power_port.clone())));
 
     let params = glium::DrawParameters {
         blend: glium::Blend::alpha_blending(),
@@ -464,9 +584,13 @@ fn main() {
 
         let mut result = zpu::zpu::ZResult::new(false, None);
 
-        if acc_time > 5.0 {
-            result = zpu.step();
-            acc_time = 0.0;
+        if !paused {
+            timer_ns += frame_ns;
+            if timer_ns >= TIMER_PERIOD_NS {
+                timer_ns %= TIMER_PERIOD_NS;
+                zpu.raise_irq(TIMER_IRQ);
+            }
+            handle_run(zpu.advance(frame_ns), &mut result, &mut paused);
         }
 
         events_loop.poll_events(|event| {
@@ -544,15 +668,11 @@ fn main() {
                             },
                             glium::glutin::VirtualKeyCode::Return => {
                                 if shift {
-                                    let mut file = File::create("programs/hello.asm").unwrap();
-                                    for line in terminal.iter() {
-                                        let mut nline = line.clone();
-                                        nline.push('\n');
-                                        file.write_all(nline.as_bytes()).unwrap();
+                                    save_source(&terminal);
+                                    err = load_source(&mut zpu, &terminal.join("\n"), false);
+                                    if err.is_ok() {
+                                        paused = false;
                                     }
-                                    file.sync_data().unwrap();
-                                    err = zpu::assembler::assemble_program("programs/hello.asm", "programs/zpu.bin");
-                                    zpu.load_program("programs/zpu.bin");
                                 } else {
                                     if terminal[cur_y].len() > cur_x {
                                         println!("cursor: {},{}", cur_x, cur_y);
@@ -566,6 +686,63 @@ fn main() {
                                 term_ui = false;
                                 term_collide = false;
                             },
+                            glium::glutin::VirtualKeyCode::F1 => { debug_ui = !debug_ui; },
+                            glium::glutin::VirtualKeyCode::F2 => {
+                                if let Err(e) = zpu.snapshot().save("programs/zpu.snap") {
+                                    println!("can't save snapshot: {}", e);
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F3 => {
+                                let mut bytes = Vec::new();
+                                match File::open("programs/zpu.snap").and_then(|mut file| file.read_to_end(&mut bytes)) {
+                                    Ok(_) => match zpu::snapshot::ZpuSnapshot::from_bytes(&bytes) {
+                                        Ok(snapshot) => zpu.restore(&snapshot),
+                                        Err(e) => println!("can't load snapshot: {}", e),
+                                    },
+                                    Err(e) => println!("can't load snapshot: {}", e),
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F4 => {
+                                save_source(&terminal);
+                                err = load_source(&mut zpu, &terminal.join("\n"), true);
+                                if err.is_ok() {
+                                    paused = false;
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F5 => { paused = false; },
+                            glium::glutin::VirtualKeyCode::F6 => { paused = true; },
+                            glium::glutin::VirtualKeyCode::F7 => {
+                                match zpu.stop_trace() {
+                                    Some(tracer) => {
+                                        if let Err(e) = tracer.save("programs/zpu.trace") {
+                                            println!("can't save trace: {}", e);
+                                        }
+                                    },
+                                    None => zpu.start_trace(),
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F8 => {
+                                if let Some(watch) = watch_on_line(&zpu, &terminal[cur_y]) {
+                                    if !zpu.debugger.unwatch(watch) {
+                                        zpu.debugger.watch(watch);
+                                    }
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F9 => {
+                                if let Some(label) = label_on_line(&terminal[cur_y]) {
+                                    if let Some(addr) = zpu.program.symbol(&label).map(|sym| sym.addr) {
+                                        zpu.debugger.toggle_breakpoint(addr);
+                                    }
+                                }
+                            },
+                            glium::glutin::VirtualKeyCode::F10 => {
+                                paused = true;
+                                handle_stop(zpu.step_over(10000), &mut result, &mut paused);
+                            },
+                            glium::glutin::VirtualKeyCode::F11 => {
+                                paused = true;
+                                handle_stop(zpu.debug_step(), &mut result, &mut paused);
+                            },
                             glium::glutin::VirtualKeyCode::Up => {
                                 if cur_y > 0 {
                                     cur_y -= 1;
@@ -672,6 +849,8 @@ fn main() {
             }
         }
 
+        power_port.set((ship_power * 100.0) as u32);
+
         let mut cy = 0.0;
         let mut cx = 0.0;
         if inputs.has_update() && !term_ui && !ship_ui {
@@ -1028,8 +1207,19 @@ fn main() {
                     [-0.99, 0.95 - ((i as f32) * 0.05), 0.0, 1.0],
                 ];
 
+                let breakpoint = label_on_line(line)
+                    .and_then(|label| zpu.program.symbol(&label).map(|sym| zpu.debugger.is_breakpoint(sym.addr)))
+                    .unwrap_or(false);
+                let color = if err.errors.iter().any(|e| e.line() == i + 1) {
+                    (1.0, 0.0, 0.0, 1.0)
+                } else if breakpoint {
+                    (1.0, 1.0, 0.0, 1.0)
+                } else {
+                    (0.0, 1.0, 0.0, 1.0)
+                };
+
                 let console_text = glium_text::TextDisplay::new(&text_system, &font, line.as_str());
-                glium_text::draw(&console_text, &text_system, &mut target, console_matrix, (0.0, 1.0, 0.0, 1.0));
+                glium_text::draw(&console_text, &text_system, &mut target, console_matrix, color);
             }
 
             let cursor_buffer = &termui_buffer;
@@ -1045,7 +1235,14 @@ fn main() {
 
             target.draw(cursor_buffer, &indices, &cursor_program, &cursor_uniform, &params).unwrap();
 
-            for (i, line) in guide.iter().enumerate() {
+            let debug_lines;
+            let panel = if debug_ui {
+                debug_lines = debug_panel(&zpu, paused);
+                &debug_lines
+            } else {
+                &guide
+            };
+            for (i, line) in panel.iter().enumerate() {
                 let console_matrix = [
                     [0.035 * ratio, 0.0, 0.0, 0.0],
                     [0.0, 0.035, 0.0, 0.0],
@@ -1067,7 +1264,7 @@ fn main() {
             glium_text::draw(&console_text, &text_system, &mut target, console_matrix, (1.0, 1.0, 1.0, 1.0));
         }
 
-        if !err.compile_err.is_empty() {
+        if let Some(first_err) = err.errors.first() {
             let console_matrix = [
                 [0.035 * ratio, 0.0, 0.0, 0.0],
                 [0.0, 0.035, 0.0, 0.0],
@@ -1075,15 +1272,30 @@ fn main() {
                 [-1.0, -0.95, 0.0, 1.0],
             ];
             {
-                let console_text = glium_text::TextDisplay::new(&text_system, &font, err.compile_err.as_str());
+                let err_string = if err.errors.len() > 1 {
+                    format!("{} (+{} more)", first_err, err.errors.len() - 1)
+                } else {
+                    format!("{}", first_err)
+                };
+                let console_text = glium_text::TextDisplay::new(&text_system, &font, err_string.as_str());
                 glium_text::draw(&console_text, &text_system, &mut target, console_matrix, (1.0, 0.0, 0.0, 1.0));
             }
+        } else if let Some(fault) = zpu.fault {
+            let console_matrix = [
+                [0.035 * ratio, 0.0, 0.0, 0.0],
+                [0.0, 0.035, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [-1.0, -0.95, 0.0, 1.0],
+            ];
+            let fault_string = format!("ZPU fault {}: {}", fault.code(), fault);
+            let console_text = glium_text::TextDisplay::new(&text_system, &font, fault_string.as_str());
+            glium_text::draw(&console_text, &text_system, &mut target, console_matrix, (1.0, 0.0, 0.0, 1.0));
         }
 
         target.finish().unwrap();
 
         let end_time = time::precise_time_ns();
 		dt = ((end_time - start_time) as f32 / 1e6) / 60.0;
-        acc_time += dt;
+        frame_ns = end_time - start_time;
     }
 }
@@ -0,0 +1,75 @@
+//! Compares fetching and decoding every instruction as it runs, the way `step` used to
+//! (seeking a byte cursor over the image), against the decoded copy of the program used by
+//! `step` and `run`. Run with `cargo bench`.
+
+extern crate byteorder;
+extern crate zpu;
+
+use std::io::{Cursor, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use zpu::assembler;
+use zpu::encoding;
+use zpu::zpu::{RunOutcome, ZPU};
+
+const INSTRUCTIONS: usize = 2_000_000;
+
+const WORKLOAD: &str = "
+loop:
+    mov b, a
+    mul b, 3
+    add c, b
+    xor c, a
+    inc a
+    cmp a, 0
+    jmp loop
+";
+
+fn load() -> ZPU {
+    ZPU::from_program(&assembler::assemble(WORKLOAD).unwrap())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{:<24} {:>8.1} ms  {:>8.1} M instructions/s", name, secs * 1e3, INSTRUCTIONS as f64 / secs / 1e6);
+}
+
+fn main() {
+    let mut zpu = load();
+    let mut bytes = Vec::new();
+    for word in zpu.program.code.iter() {
+        bytes.write_u32::<LittleEndian>(*word).unwrap();
+    }
+    let mut cursor = Cursor::new(bytes);
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        cursor.seek(SeekFrom::Start(zpu.pc as u64 * 4)).unwrap();
+        let mut words = vec![cursor.read_u32::<LittleEndian>().unwrap()];
+        words.extend(cursor.read_u32::<LittleEndian>().ok());
+        let inst = encoding::decode(&words, 0).unwrap();
+        zpu.execute(inst.opcode, inst.reg1, inst.reg2, inst.imm).unwrap();
+    }
+    report("seek and decode", start.elapsed());
+
+    let mut zpu = load();
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        let inst = encoding::decode(&zpu.program.code, zpu.pc).unwrap();
+        zpu.execute(inst.opcode, inst.reg1, inst.reg2, inst.imm).unwrap();
+    }
+    report("decode from words", start.elapsed());
+
+    let mut zpu = load();
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        zpu.step();
+    }
+    report("step", start.elapsed());
+
+    let mut zpu = load();
+    let start = Instant::now();
+    assert_eq!(zpu.run(INSTRUCTIONS), RunOutcome::Budget);
+    report("run", start.elapsed());
+}
@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use encoding::{self, Instruction, Program, Section};
+use zpu::{Opcode, Operands, Register};
+
+/// A run of instructions that always execute together: control only enters at `start`
+/// and only leaves after the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u32,
+    pub insts: Vec<(u32, Instruction)>,
+    /// Starts of the blocks control can go to next.
+    pub succs: Vec<u32>,
+}
+
+impl Block {
+    /// The address after the last instruction.
+    pub fn end(&self) -> u32 {
+        self.insts.last().map_or(self.start, |&(addr, ref inst)| addr + inst.size())
+    }
+}
+
+/// The control-flow graph of the reachable part of a program's code.
+///
+/// Code is reachable from the entry point, from any data word that holds the address of a
+/// code label (such as the interrupt vectors), and from any code label whose address is
+/// taken by an instruction other than a jump, since it could be stored as a vector at run
+/// time. A CALL is assumed to return to the instruction after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u32, Block>,
+    pub entry: u32,
+    /// Blocks entered from outside the program's own jumps: handlers and the like.
+    pub roots: Vec<u32>,
+}
+
+fn is_branch(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::IfEqual | Opcode::IfNotEqual | Opcode::IfZero | Opcode::IfGreater |
+             Opcode::IfLess | Opcode::IfGreaterSigned | Opcode::IfLessSigned)
+}
+
+/// Whether the instruction can send control somewhere other than the next instruction.
+fn ends_block(opcode: Opcode) -> bool {
+    is_branch(opcode) || matches!(opcode, Opcode::Jump | Opcode::Call | Opcode::Return |
+                                  Opcode::InterruptReturn | Opcode::Halt)
+}
+
+/// The jump target, when it is an immediate rather than a register.
+fn target(inst: &Instruction) -> Option<u32> {
+    if inst.opcode.operands() == Operands::Target { inst.imm } else { None }
+}
+
+/// Where control can go after the instruction at `addr`.
+fn successors(addr: u32, inst: &Instruction) -> Vec<u32> {
+    let next = addr + inst.size();
+    match inst.opcode {
+        Opcode::Jump => target(inst).into_iter().collect(),
+        Opcode::Return | Opcode::InterruptReturn | Opcode::Halt => Vec::new(),
+        opcode if is_branch(opcode) || opcode == Opcode::Call => target(inst).into_iter().chain(Some(next)).collect(),
+        _ => vec![next],
+    }
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Cfg {
+        let code_labels: BTreeSet<u32> = program.symbols.iter()
+            .filter(|sym| sym.section == Section::Code)
+            .map(|sym| sym.addr)
+            .collect();
+        let mut roots: Vec<u32> = program.data.iter().cloned().filter(|word| code_labels.contains(word)).collect();
+
+        let mut reached = BTreeMap::new();
+        let mut leaders: BTreeSet<u32> = roots.iter().cloned().chain(Some(program.entry)).collect();
+        let mut work: Vec<u32> = leaders.iter().cloned().collect();
+        while let Some(addr) = work.pop() {
+            if reached.contains_key(&addr) {
+                continue;
+            }
+            let inst = match encoding::decode(&program.code, addr) {
+                Ok(inst) => inst,
+                Err(_) => continue,
+            };
+            if target(&inst).is_none() {
+                if let Some(imm) = inst.imm.filter(|imm| code_labels.contains(imm)) {
+                    if leaders.insert(imm) {
+                        roots.push(imm);
+                        work.push(imm);
+                    }
+                }
+            }
+            let succs = successors(addr, &inst);
+            if ends_block(inst.opcode) {
+                leaders.extend(succs.iter().cloned());
+            }
+            work.extend(succs);
+            reached.insert(addr, inst);
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|addr| reached.contains_key(addr)) {
+            let mut insts = Vec::new();
+            let mut addr = start;
+            while let Some(inst) = reached.get(&addr) {
+                insts.push((addr, *inst));
+                addr += inst.size();
+                if ends_block(inst.opcode) || leaders.contains(&addr) {
+                    break;
+                }
+            }
+            let &(last, ref inst) = insts.last().unwrap();
+            let succs = successors(last, inst).into_iter().filter(|succ| reached.contains_key(succ)).collect();
+            blocks.insert(start, Block { start, insts, succs });
+        }
+
+        roots.sort();
+        roots.dedup();
+        Cfg { blocks, entry: program.entry, roots }
+    }
+
+    /// Every reachable instruction.
+    pub fn insts(&self) -> impl Iterator<Item = &(u32, Instruction)> {
+        self.blocks.values().flat_map(|block| block.insts.iter())
+    }
+
+    /// Every code word that is part of a reachable instruction.
+    fn covered(&self) -> BTreeSet<u32> {
+        self.insts().flat_map(|&(start, ref inst)| start..start + inst.size()).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintKind {
+    /// The code from here up to this address can never run.
+    Unreachable(u32),
+    /// The register is read on some path before anything has been written to it.
+    ReadBeforeWrite(Register),
+    /// A jump to the first address, which is inside the instruction starting at the second.
+    JumpIntoInstruction(u32, u32),
+    /// A loop starting here that can never be left, and never does IN, OUT or HLT.
+    InfiniteLoop,
+    /// An OUT to a port with nothing to receive it.
+    UnmappedPort(u32),
+}
+
+/// A likely mistake found in a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub addr: u32,
+    /// The nearest code label at or before `addr`, such as `loop` or `loop+2`.
+    pub label: Option<String>,
+    pub kind: LintKind,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}", self.addr)?;
+        if let Some(ref label) = self.label {
+            write!(f, " ({})", label)?;
+        }
+        match self.kind {
+            LintKind::Unreachable(end) => write!(f, ": unreachable code, up to {:04x}", end),
+            LintKind::ReadBeforeWrite(reg) => write!(f, ": `{}` is read before anything is written to it", reg.name()),
+            LintKind::JumpIntoInstruction(target, inst) =>
+                write!(f, ": jump to {:04x} lands inside the instruction at {:04x}", target, inst),
+            LintKind::InfiniteLoop => write!(f, ": loop never exits and never does IN, OUT or HLT"),
+            LintKind::UnmappedPort(port) => write!(f, ": OUT to port {}, which has nothing mapped to it", port),
+        }
+    }
+}
+
+/// What's known about a register at some point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    /// Not written to on at least one path here.
+    Unwritten,
+    Const(u32),
+    Unknown,
+}
+
+type State = [Value; 8];
+
+fn index(reg: Register) -> usize {
+    reg.hex_value() as usize - 1
+}
+
+fn meet(a: &State, b: &State) -> State {
+    let mut out = *a;
+    for (out, b) in out.iter_mut().zip(b.iter()) {
+        *out = match (*out, *b) {
+            (Value::Unwritten, _) | (_, Value::Unwritten) => Value::Unwritten,
+            (Value::Const(x), Value::Const(y)) if x == y => Value::Const(x),
+            _ => Value::Unknown,
+        };
+    }
+    out
+}
+
+/// Whether the instruction uses the value already in its first register.
+fn reads_reg1(opcode: Opcode) -> bool {
+    match opcode {
+        Opcode::Move | Opcode::MemoryMove | Opcode::In | Opcode::Pop => false,
+        opcode => opcode.operands().uses_reg1(),
+    }
+}
+
+fn writes_reg1(opcode: Opcode) -> bool {
+    !matches!(opcode, Opcode::Compare | Opcode::MemorySet | Opcode::Push | Opcode::Out) && opcode.operands().uses_reg1()
+}
+
+/// Applies an instruction to the state, returning the registers it read before they were
+/// written. Those count as written afterwards, so each is only reported once per path.
+fn step(state: &mut State, inst: &Instruction) -> Vec<Register> {
+    let mut unwritten = Vec::new();
+    let reads = [(reads_reg1(inst.opcode), inst.reg1), (true, inst.reg2)];
+    for &(_, reg) in reads.iter().filter(|&&(read, reg)| read && reg != Register::Null) {
+        if state[index(reg)] == Value::Unwritten {
+            unwritten.push(reg);
+            state[index(reg)] = Value::Unknown;
+        }
+    }
+    if writes_reg1(inst.opcode) && inst.reg1 != Register::Null {
+        state[index(inst.reg1)] = match (inst.opcode, inst.imm, inst.reg2) {
+            (Opcode::Move, Some(value), _) => Value::Const(value),
+            (Opcode::Move, None, Register::Null) => Value::Const(0),
+            (Opcode::Move, None, reg2) => state[index(reg2)],
+            _ => Value::Unknown,
+        };
+    }
+    unwritten
+}
+
+/// The state at the start of each block: registers are unwritten at the entry point, and
+/// anything at all on entry to a handler or after a CALL returns.
+fn dataflow(cfg: &Cfg) -> HashMap<u32, State> {
+    let mut states: HashMap<u32, State> = HashMap::new();
+    for &root in &cfg.roots {
+        states.insert(root, [Value::Unknown; 8]);
+    }
+    if !cfg.roots.contains(&cfg.entry) {
+        states.insert(cfg.entry, [Value::Unwritten; 8]);
+    }
+
+    let mut work: BTreeSet<u32> = states.keys().cloned().collect();
+    while let Some(start) = work.iter().next().cloned() {
+        work.remove(&start);
+        let block = match cfg.blocks.get(&start) {
+            Some(block) => block,
+            None => continue,
+        };
+        let mut state = states[&start];
+        for (_, inst) in &block.insts {
+            step(&mut state, inst);
+        }
+        let &(last, ref inst) = block.insts.last().unwrap();
+        for &succ in &block.succs {
+            let edge = if inst.opcode == Opcode::Call && succ == last + inst.size() { [Value::Unknown; 8] } else { state };
+            let merged = states.get(&succ).map_or(edge, |old| meet(old, &edge));
+            if states.get(&succ) != Some(&merged) {
+                states.insert(succ, merged);
+                work.insert(succ);
+            }
+        }
+    }
+    states
+}
+
+/// Strongly connected components of the graph, each as a list of block starts. This is
+/// Tarjan's algorithm, keeping its own stack of blocks being visited so that a long chain
+/// of blocks can't overflow the thread's.
+fn components(cfg: &Cfg) -> Vec<Vec<u32>> {
+    let mut index = HashMap::new();
+    let mut low = HashMap::new();
+    let mut stack = Vec::new();
+    let mut on_stack = HashSet::new();
+    let mut components = Vec::new();
+    for &start in cfg.blocks.keys() {
+        if index.contains_key(&start) {
+            continue;
+        }
+        // Each block being visited, and how many of its successors have been looked at.
+        let mut visiting = vec![(start, 0)];
+        while let Some(&(node, next)) = visiting.last() {
+            if next == 0 {
+                let idx = index.len();
+                index.insert(node, idx);
+                low.insert(node, idx);
+                stack.push(node);
+                on_stack.insert(node);
+            }
+            if let Some(&succ) = cfg.blocks[&node].succs.get(next) {
+                visiting.last_mut().unwrap().1 += 1;
+                if !index.contains_key(&succ) {
+                    visiting.push((succ, 0));
+                } else if on_stack.contains(&succ) {
+                    let lowest = low[&node].min(index[&succ]);
+                    low.insert(node, lowest);
+                }
+                continue;
+            }
+
+            visiting.pop();
+            if low[&node] == index[&node] {
+                let pos = stack.iter().rposition(|&other| other == node).unwrap();
+                let component = stack.split_off(pos);
+                for block in &component {
+                    on_stack.remove(block);
+                }
+                components.push(component);
+            }
+            if let Some(&(parent, _)) = visiting.last() {
+                let lowest = low[&parent].min(low[&node]);
+                low.insert(parent, lowest);
+            }
+        }
+    }
+    components
+}
+
+/// Whether the instruction lets a loop do something observable, or leave in a way the
+/// graph can't follow.
+fn escapes(inst: &Instruction) -> bool {
+    match inst.opcode {
+        Opcode::In | Opcode::Out | Opcode::Halt | Opcode::Call | Opcode::Return | Opcode::InterruptReturn => true,
+        opcode => (opcode == Opcode::Jump || is_branch(opcode)) && target(inst).is_none(),
+    }
+}
+
+fn label_for(program: &Program, addr: u32) -> Option<String> {
+    program.symbols.iter()
+        .filter(|sym| sym.section == Section::Code && sym.addr <= addr)
+        .max_by_key(|sym| sym.addr)
+        .map(|sym| if sym.addr == addr { sym.name.clone() } else { format!("{}+{}", sym.name, addr - sym.addr) })
+}
+
+/// Checks a program for likely mistakes, returning them in address order.
+///
+/// OUT is checked against `ports` when given: the ports with a device on the bus, plus any
+/// the host handles itself. Only ports held in a register as a known constant are checked.
+/// Loops waiting on an interrupt aren't reported as infinite if the program ever runs EI.
+pub fn lint(program: &Program, ports: Option<&[u32]>) -> Vec<Lint> {
+    let cfg = Cfg::build(program);
+    let mut lints = Vec::new();
+    let mut push = |addr: u32, kind: LintKind| lints.push(Lint { addr, label: label_for(program, addr), kind });
+
+    // Instruction boundaries, as the disassembler would find them.
+    let mut layout = Vec::new();
+    let mut pc = 0;
+    while (pc as usize) < program.code.len() {
+        let inst = encoding::decode(&program.code, pc).ok();
+        layout.push((pc, inst));
+        pc += inst.map_or(1, |inst| inst.size());
+    }
+
+    let covered = cfg.covered();
+    let last = layout.len().saturating_sub(1);
+    let mut unreachable: Option<(u32, u32)> = None;
+    for (idx, &(addr, inst)) in layout.iter().enumerate() {
+        let dead = match inst {
+            // The HLT the assembler adds after the last instruction.
+            Some(inst) if idx == last && inst.opcode == Opcode::Halt => false,
+            Some(inst) => !(addr..addr + inst.size()).any(|word| covered.contains(&word)),
+            None => false,
+        };
+        match (dead, unreachable) {
+            (true, Some((start, _))) => unreachable = Some((start, addr + inst.unwrap().size())),
+            (true, None) => unreachable = Some((addr, addr + inst.unwrap().size())),
+            (false, Some((start, end))) => {
+                push(start, LintKind::Unreachable(end));
+                unreachable = None;
+            },
+            (false, None) => (),
+        }
+    }
+    if let Some((start, end)) = unreachable {
+        push(start, LintKind::Unreachable(end));
+    }
+
+    for &(addr, ref inst) in cfg.insts() {
+        if let Some(target) = target(inst) {
+            let inside = layout.iter()
+                .filter_map(|&(start, inst)| inst.map(|inst| (start, inst.size())))
+                .find(|&(start, size)| start < target && target < start + size);
+            if let Some((start, _)) = inside {
+                push(addr, LintKind::JumpIntoInstruction(target, start));
+            }
+        }
+    }
+
+    let states = dataflow(&cfg);
+    for block in cfg.blocks.values() {
+        let mut state = match states.get(&block.start) {
+            Some(state) => *state,
+            None => continue,
+        };
+        for &(addr, ref inst) in &block.insts {
+            if let (Opcode::Out, Some(ports), true) = (inst.opcode, ports, inst.reg1 != Register::Null) {
+                match state[index(inst.reg1)] {
+                    Value::Const(port) if !ports.contains(&port) => push(addr, LintKind::UnmappedPort(port)),
+                    _ => (),
+                }
+            }
+            for reg in step(&mut state, inst) {
+                push(addr, LintKind::ReadBeforeWrite(reg));
+            }
+        }
+    }
+
+    let interrupts = cfg.insts().any(|(_, inst)| inst.opcode == Opcode::EnableInterrupts);
+    for component in components(&cfg) {
+        let blocks: Vec<&Block> = component.iter().map(|start| &cfg.blocks[start]).collect();
+        let cycle = blocks.len() > 1 || blocks[0].succs.contains(&blocks[0].start);
+        let closed = blocks.iter().all(|block| block.succs.iter().all(|succ| component.contains(succ)));
+        let silent = !blocks.iter().any(|block| block.insts.iter().any(|(_, inst)| escapes(inst)));
+        if cycle && closed && silent && !interrupts {
+            push(*component.iter().min().unwrap(), LintKind::InfiniteLoop);
+        }
+    }
+
+    lints.sort_by_key(|lint| lint.addr);
+    lints
+}
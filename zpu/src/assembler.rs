@@ -1,207 +1,1147 @@
-use std::u32;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use encoding::{self, Instruction, Section, Symbol};
+use interrupt::DEFAULT_VECTOR_BASE;
+use zpu::{Opcode, Operands, Register, DEFAULT_MEMORY_SIZE};
 
-use zpu::{Opcode, Register};
+pub use encoding::Program;
+
+/// The most words the code or data section can hold. The data section has to fit in
+/// memory, and this also keeps a stray `.org` from asking for a huge image.
+pub const MAX_SECTION_WORDS: u32 = DEFAULT_MEMORY_SIZE as u32;
+
+/// Where in the source an error was found. `line` and `column` start at 1, `len` is in characters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Span {
+        Span {
+            line,
+            column,
+            len,
+        }
+    }
+
+    /// A span covering both `self` and `other`, which must be on the same line.
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.line, self.column, other.column + other.len - self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssembleError {
+    UnknownMnemonic(Span, String),
+    ExpectedRegister(Span, String),
+    ExpectedMemoryOperand(Span, String),
+    InvalidOperand(Span, String),
+    InvalidLabel(Span, String),
+    /// The mnemonic, and how many operands it takes.
+    OperandCount(Span, String, usize),
+    UndefinedLabel(Span, String),
+    DuplicateLabel(Span, String),
+    UnknownDirective(Span, String),
+    /// `.org` pointing below the current address.
+    OrgBackwards(Span, u32),
+    /// `.org`, or the line's words, reaching past `MAX_SECTION_WORDS` to this address.
+    SectionTooLarge(Span, u32),
+    /// Data running into the interrupt vector table, which starts at this address, without
+    /// an `.org` putting it there.
+    IntoVectorTable(Span, u32),
+    IncludeNotFound(Span, String),
+    /// An error inside an included file. The span is the `.include` line.
+    InInclude(Span, String, Box<AssembleError>),
+    InvalidMacroName(Span, String),
+    DuplicateMacro(Span, String),
+    /// A `.macro` with no `.endm`.
+    UnterminatedMacro(Span, String),
+    /// A macro that calls itself, directly or not.
+    RecursiveMacro(Span, String),
+    /// An error inside a macro expansion. The spans are the call site, then the `.macro` line.
+    InMacro(Span, Span, String, Box<AssembleError>),
+}
+
+impl AssembleError {
+    pub fn span(&self) -> Span {
+        match *self {
+            AssembleError::UnknownMnemonic(span, _) |
+            AssembleError::ExpectedRegister(span, _) |
+            AssembleError::ExpectedMemoryOperand(span, _) |
+            AssembleError::InvalidOperand(span, _) |
+            AssembleError::InvalidLabel(span, _) |
+            AssembleError::OperandCount(span, _, _) |
+            AssembleError::UndefinedLabel(span, _) |
+            AssembleError::DuplicateLabel(span, _) |
+            AssembleError::UnknownDirective(span, _) |
+            AssembleError::OrgBackwards(span, _) |
+            AssembleError::SectionTooLarge(span, _) |
+            AssembleError::IntoVectorTable(span, _) |
+            AssembleError::IncludeNotFound(span, _) |
+            AssembleError::InInclude(span, _, _) |
+            AssembleError::InvalidMacroName(span, _) |
+            AssembleError::DuplicateMacro(span, _) |
+            AssembleError::UnterminatedMacro(span, _) |
+            AssembleError::RecursiveMacro(span, _) |
+            AssembleError::InMacro(span, _, _, _) => span,
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        self.span().line
+    }
+
+    pub fn column(&self) -> usize {
+        self.span().column
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: ", span.line, span.column)?;
+        match *self {
+            AssembleError::UnknownMnemonic(_, ref name) => write!(f, "unknown instruction `{}`", name),
+            AssembleError::ExpectedRegister(_, ref found) => write!(f, "expected a register, found `{}`", found),
+            AssembleError::ExpectedMemoryOperand(_, ref found) => write!(f, "expected `[addr]` or `[reg]`, found `{}`", found),
+            AssembleError::InvalidOperand(_, ref found) => write!(f, "invalid operand `{}`", found),
+            AssembleError::InvalidLabel(_, ref label) => write!(f, "`{}` is not a valid label name", label),
+            AssembleError::OperandCount(_, ref name, 0) => write!(f, "`{}` takes no operands", name),
+            AssembleError::OperandCount(_, ref name, 1) => write!(f, "`{}` takes 1 operand", name),
+            AssembleError::OperandCount(_, ref name, count) => write!(f, "`{}` takes {} operands", name, count),
+            AssembleError::UndefinedLabel(_, ref label) => write!(f, "label `{}` is never defined", label),
+            AssembleError::DuplicateLabel(_, ref label) => write!(f, "label `{}` is already defined", label),
+            AssembleError::UnknownDirective(_, ref name) => write!(f, "unknown directive `{}`", name),
+            AssembleError::OrgBackwards(_, addr) => write!(f, "`.org {}` is below the current address", addr),
+            AssembleError::SectionTooLarge(_, addr) =>
+                write!(f, "address {} is past the end of the section, which holds {} words", addr, MAX_SECTION_WORDS),
+            AssembleError::IntoVectorTable(_, base) =>
+                write!(f, "data runs into the interrupt vector table at {}, use `.org {}` to fill it in", base, base),
+            AssembleError::IncludeNotFound(_, ref file) => write!(f, "can't read include file \"{}\"", file),
+            AssembleError::InInclude(_, ref file, ref err) => write!(f, "in \"{}\": {}", file, err),
+            AssembleError::InvalidMacroName(_, ref name) => write!(f, "`{}` can't be used as a macro name", name),
+            AssembleError::DuplicateMacro(_, ref name) => write!(f, "macro `{}` is already defined", name),
+            AssembleError::UnterminatedMacro(_, ref name) => write!(f, "macro `{}` has no `.endm`", name),
+            AssembleError::RecursiveMacro(_, ref name) => write!(f, "macro `{}` expands itself", name),
+            AssembleError::InMacro(_, def, ref name, ref err) =>
+                write!(f, "in macro `{}` (defined at {}:{}): {}", name, def.line, def.column, err),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct AResult {
-    pub compile_err: String,
+    pub errors: Vec<AssembleError>,
 }
 
 impl AResult {
-    pub fn new(err: Option<String>) -> AResult {
-        let compile_err;
-        if err.is_some() {
-            compile_err = err.unwrap();
+    pub fn new(errors: Vec<AssembleError>) -> AResult {
+        AResult {
+            errors,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// One operand as written in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(u32),
+    Label(String),
+    /// `[addr]`, `[reg]` or `[label]`.
+    Memory(Box<Operand>),
+}
+
+impl Operand {
+    /// Whether the operand ends up in the immediate word rather than a register field.
+    fn is_immediate(&self) -> bool {
+        match *self {
+            Operand::Register(_) => false,
+            Operand::Immediate(_) | Operand::Label(_) => true,
+            Operand::Memory(ref inner) => inner.is_immediate(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub operand: Operand,
+    pub span: Span,
+}
+
+/// A parsed instruction whose labels have not been resolved yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceInst {
+    pub opcode: Opcode,
+    /// Span of the mnemonic.
+    pub span: Span,
+    pub args: Vec<Arg>,
+}
+
+impl SourceInst {
+    /// Size in words once encoded. Labels are always encoded as immediates, so this is
+    /// known before any of them are resolved.
+    pub fn size(&self) -> u32 {
+        if self.args.iter().any(|arg| arg.operand.is_immediate()) {
+            2
         } else {
-            compile_err = String::new();
+            1
         }
-        AResult {
-            compile_err: compile_err,
+    }
+}
+
+/// An assembler directive, such as `.word` or `.org`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// `.equ name, value` or `.define name value`.
+    Equ(String, Arg),
+    /// `.word a, b, ...`, one word per value.
+    Word(Vec<Arg>),
+    /// `.string "text"`, one word per character, then a 0.
+    Str(String),
+    Org(Arg),
+    Code,
+    Data,
+}
+
+impl Directive {
+    /// Words the directive emits into the current section.
+    pub fn size(&self) -> u32 {
+        match *self {
+            Directive::Word(ref values) => values.len() as u32,
+            Directive::Str(ref text) => text.chars().count() as u32 + 1,
+            Directive::Equ(..) | Directive::Org(_) | Directive::Code | Directive::Data => 0,
         }
     }
 }
 
-fn write_inst(buffer: &mut Vec<u8>, op: Opcode, reg1: u8, reg2: u8, data: u32) {
-    let write_v = ((op.hex_value() as u32) << 16) | ((reg1 as u32) << 8) | reg2 as u32;
-//    println!("[BIN-WRITE] {}, {:?}, {}, {}", write_v, op, reg1, reg2);
-    buffer.write_u32::<LittleEndian>(write_v).unwrap();
-    if reg2 == 0 {
-//        println!("[BIN-WRITE-DATA] {}", data);
-        buffer.write_u32::<LittleEndian>(data).unwrap();
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Inst(SourceInst),
+    Directive(Directive, Span),
+}
+
+/// Where a line came from when it was not written directly in the assembled text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// Pulled in by the `.include` at this span, from this file.
+    Include(Span, String),
+    /// Expanded from the call at the first span, of the macro defined at the second.
+    Macro(Span, Span, String),
+}
+
+/// A source line: any labels defined on it, then an optional instruction or directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub number: usize,
+    pub labels: Vec<(String, Span)>,
+    pub item: Option<Item>,
+    /// Empty for lines from the top level source, outermost first otherwise.
+    pub origin: Vec<Origin>,
+}
+
+impl SourceLine {
+    pub fn inst(&self) -> Option<&SourceInst> {
+        match self.item {
+            Some(Item::Inst(ref inst)) => Some(inst),
+            _ => None,
+        }
+    }
+
+    fn wrap_error(&self, err: AssembleError) -> AssembleError {
+        wrap_error(&self.origin, err)
     }
 }
 
-fn write_program(filename: &str, program: &Vec<u8>) {
-    let mut file = File::create(filename).unwrap();
-    file.write_all(&program).unwrap();
+/// Wraps an error found in expanded source so it points at the top level source.
+fn wrap_error(origin: &[Origin], err: AssembleError) -> AssembleError {
+    origin.iter().rev().fold(err, |err, origin| match *origin {
+        Origin::Include(span, ref file) => AssembleError::InInclude(span, file.clone(), Box::new(err)),
+        Origin::Macro(call, def, ref name) => AssembleError::InMacro(call, def, name.clone(), Box::new(err)),
+    })
 }
 
-pub fn assemble_program(file_in: &str, file_out: &str) -> AResult {
-    let mut program = Vec::new();
-    let mut instructions = Vec::new();
-    let mut label_map = HashMap::new();
-    let mut pc = 0;
+struct Token {
+    text: String,
+    span: Span,
+}
 
-    let mut file = File::open(file_in).unwrap();
-    let mut text = String::new();
-    file.read_to_string(&mut text).unwrap();
+/// Splits a line on whitespace and commas, remembering where each token started.
+/// Everything after a `;` is a comment. Quoted strings and characters are kept whole,
+/// with their case; everything else is lowercased.
+fn tokenize(line: &str, line_num: usize) -> Vec<Token> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
 
-    for line in text.lines() {
-        let line = line.to_lowercase();
-        if line.is_empty() || line.contains(';') {
+    while idx < chars.len() {
+        let c = chars[idx];
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() || c == ',' {
+            idx += 1;
             continue;
         }
 
-        let line = line.replace(',', "");
-        let tokens: Vec<&str> = line.split_whitespace().collect();
-        if tokens[0].contains(":") {
-            let label = tokens[0].replace(':', "");
-            label_map.insert(label, pc);
-        } else if tokens.len() > 1 {
-            let opcode = match tokens[0] {
-                "nop" => Some(Opcode::NoOp),
-                "jmp" => Some(Opcode::Jump),
-                "hlt" => Some(Opcode::Halt),
-                "inc" => Some(Opcode::Increment),
-                "shr" => Some(Opcode::ShiftRight),
-                "shl" => Some(Opcode::ShiftLeft),
-                "mov" => Some(Opcode::Move),
-                "add" => Some(Opcode::Add),
-                "sub" => Some(Opcode::Subtract),
-                "mul" => Some(Opcode::Multiply),
-                "div" => Some(Opcode::Divide),
-                "je" => Some(Opcode::IfEqual),
-                "jn" => Some(Opcode::IfNotEqual),
-                "mmov" => Some(Opcode::MemoryMove),
-                "mset" => Some(Opcode::MemorySet),
-                "xor" => Some(Opcode::XOr),
-                "in" => Some(Opcode::In),
-                "out" => Some(Opcode::Out),
-                "push" => Some(Opcode::Push),
-                "pop" => Some(Opcode::Pop),
-                "jz" => Some(Opcode::IfZero),
-                "jg" => Some(Opcode::IfGreater),
-                "jl" => Some(Opcode::IfLess),
-                "cmp" => Some(Opcode::Compare),
-                _ => None,
-            };
-
-            let mut triggered = false;
-            if opcode.is_some() {
-                let opcode = opcode.unwrap();
-                if opcode == Opcode::Jump || opcode == Opcode::IfGreater || opcode == Opcode::IfLess ||
-                    opcode == Opcode::IfEqual || opcode == Opcode::IfNotEqual || opcode == Opcode::IfZero {
-                    triggered = true;
-                    let label = tokens[1].to_owned();
-                    let data = label_map.get(&label);
-                    pc += 1;
-                    if data.is_some() {
-                        let data = data.unwrap();
-                        instructions.push((opcode, 0, 0, *data, String::new()));
-                    } else {
-                        instructions.push((opcode, 0, 0, u32::MAX, label));
+        let start = idx;
+        if c == '"' || c == '\'' {
+            idx += 1;
+            while idx < chars.len() && chars[idx] != c {
+                if chars[idx] == '\\' {
+                    idx += 1;
+                }
+                idx += 1;
+            }
+            idx = (idx + 1).min(chars.len());
+            let text: String = chars[start..idx].iter().collect();
+            tokens.push(Token { text, span: Span::new(line_num, start + 1, idx - start) });
+        } else {
+            while idx < chars.len() && !chars[idx].is_whitespace() && chars[idx] != ',' && chars[idx] != ';' {
+                idx += 1;
+            }
+            let text: String = chars[start..idx].iter().collect();
+            tokens.push(Token { text: text.to_lowercase(), span: Span::new(line_num, start + 1, idx - start) });
+        }
+    }
+
+    tokens
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == '.' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Strips the quotes from a `"string"` or `'c'` token and handles `\n`, `\t`, `\r`,
+/// `\0`, `\\`, `\"` and `\'`.
+fn unquote(text: &str, quote: char) -> Option<String> {
+    if text.len() < 2 || !text.starts_with(quote) || !text.ends_with(quote) {
+        return None;
+    }
+
+    let mut out = String::new();
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                other => other,
+            });
+        } else {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
+
+/// Decimal, `0x` hex, `0b` binary, or a `'c'` character literal. A leading `-` gives the
+/// two's complement of a number, down to -0x80000000.
+pub fn parse_number(text: &str) -> Option<u32> {
+    if let Some(magnitude) = text.strip_prefix('-') {
+        return match parse_number(magnitude) {
+            Some(value) if value <= 0x8000_0000 && !magnitude.starts_with('-') => Some(value.wrapping_neg()),
+            _ => None,
+        };
+    }
+    if text.starts_with('\'') {
+        let inner = unquote(text, '\'')?;
+        let mut chars = inner.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c as u32),
+            _ => None,
+        };
+    }
+
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_register(token: &Token) -> Result<Arg, AssembleError> {
+    match Register::from_name(&token.text) {
+        Some(reg) => Ok(Arg { operand: Operand::Register(reg), span: token.span }),
+        None => Err(AssembleError::ExpectedRegister(token.span, token.text.clone())),
+    }
+}
+
+/// A register, a number, or a label.
+fn parse_value(token: &Token) -> Result<Arg, AssembleError> {
+    let operand = if let Some(reg) = Register::from_name(&token.text) {
+        Operand::Register(reg)
+    } else if let Some(value) = parse_number(&token.text) {
+        Operand::Immediate(value)
+    } else if is_identifier(&token.text) {
+        Operand::Label(token.text.clone())
+    } else {
+        return Err(AssembleError::InvalidOperand(token.span, token.text.clone()));
+    };
+
+    Ok(Arg { operand, span: token.span })
+}
+
+/// A number or a label, but not a register.
+fn parse_constant(token: &Token) -> Result<Arg, AssembleError> {
+    let arg = parse_value(token)?;
+    match arg.operand {
+        Operand::Register(_) => Err(AssembleError::InvalidOperand(token.span, token.text.clone())),
+        _ => Ok(arg),
+    }
+}
+
+/// Memory operands are written as `[addr]`, `[reg]` or `[label]`.
+fn parse_memory(token: &Token) -> Result<Arg, AssembleError> {
+    let text = &token.text;
+    if !text.starts_with('[') || !text.ends_with(']') || text.len() < 3 {
+        return Err(AssembleError::ExpectedMemoryOperand(token.span, text.clone()));
+    }
+
+    let inner = Token {
+        text: text[1..text.len() - 1].to_owned(),
+        span: Span::new(token.span.line, token.span.column + 1, token.span.len - 2),
+    };
+    let inner = parse_value(&inner)?;
+    Ok(Arg { operand: Operand::Memory(Box::new(inner.operand)), span: token.span })
+}
+
+fn expect_operands(name: &Token, operands: &[Token], expected: usize) -> Result<(), AssembleError> {
+    if operands.len() != expected {
+        let end = operands.last().map(|token| token.span).unwrap_or(name.span);
+        return Err(AssembleError::OperandCount(name.span.to(end), name.text.clone(), expected));
+    }
+    Ok(())
+}
+
+/// What a line turned out to be before includes and macros are expanded.
+enum Parsed {
+    Line(SourceLine),
+    Include(String, Span),
+    /// A macro call: the labels on the line, the macro, its arguments and the span of the call.
+    Call(SourceLine, String, Vec<String>, Span),
+}
+
+fn parse_directive(name: &Token, operands: &[Token]) -> Result<Directive, AssembleError> {
+    match name.text.as_str() {
+        ".equ" | ".define" => {
+            expect_operands(name, operands, 2)?;
+            if !is_identifier(&operands[0].text) || Register::from_name(&operands[0].text).is_some() {
+                return Err(AssembleError::InvalidLabel(operands[0].span, operands[0].text.clone()));
+            }
+            Ok(Directive::Equ(operands[0].text.clone(), parse_constant(&operands[1])?))
+        },
+        ".word" => {
+            if operands.is_empty() {
+                return Err(AssembleError::OperandCount(name.span, name.text.clone(), 1));
+            }
+            let mut values = Vec::new();
+            for token in operands {
+                values.push(parse_constant(token)?);
+            }
+            Ok(Directive::Word(values))
+        },
+        ".string" => {
+            expect_operands(name, operands, 1)?;
+            match unquote(&operands[0].text, '"') {
+                Some(text) => Ok(Directive::Str(text)),
+                None => Err(AssembleError::InvalidOperand(operands[0].span, operands[0].text.clone())),
+            }
+        },
+        ".org" => {
+            expect_operands(name, operands, 1)?;
+            Ok(Directive::Org(parse_constant(&operands[0])?))
+        },
+        ".code" => {
+            expect_operands(name, operands, 0)?;
+            Ok(Directive::Code)
+        },
+        ".data" => {
+            expect_operands(name, operands, 0)?;
+            Ok(Directive::Data)
+        },
+        _ => Err(AssembleError::UnknownDirective(name.span, name.text.clone())),
+    }
+}
+
+fn parse_tokens(tokens: &[Token], number: usize, macros: &HashMap<String, Rc<Macro>>) -> Result<Parsed, AssembleError> {
+    let mut labels = Vec::new();
+    let mut idx = 0;
+
+    while idx < tokens.len() && tokens[idx].text.ends_with(':') {
+        let token = &tokens[idx];
+        let label = token.text.trim_end_matches(':').to_owned();
+        if !is_identifier(&label) || Register::from_name(&label).is_some() {
+            return Err(AssembleError::InvalidLabel(token.span, label));
+        }
+        labels.push((label, token.span));
+        idx += 1;
+    }
+
+    if idx == tokens.len() {
+        return Ok(Parsed::Line(SourceLine { number, labels, item: None, origin: Vec::new() }));
+    }
+
+    let name = &tokens[idx];
+    let operands = &tokens[idx + 1..];
+
+    if macros.contains_key(&name.text) {
+        let span = name.span.to(operands.last().map(|token| token.span).unwrap_or(name.span));
+        let args = operands.iter().map(|token| token.text.clone()).collect();
+        return Ok(Parsed::Call(SourceLine { number, labels, item: None, origin: Vec::new() }, name.text.clone(), args, span));
+    }
+
+    if name.text == ".include" {
+        expect_operands(name, operands, 1)?;
+        return match unquote(&operands[0].text, '"') {
+            Some(file) => Ok(Parsed::Include(file, name.span.to(operands[0].span))),
+            None => Err(AssembleError::InvalidOperand(operands[0].span, operands[0].text.clone())),
+        };
+    }
+
+    if name.text.starts_with('.') {
+        let directive = parse_directive(name, operands)?;
+        let span = name.span.to(operands.last().map(|token| token.span).unwrap_or(name.span));
+        return Ok(Parsed::Line(SourceLine { number, labels, item: Some(Item::Directive(directive, span)), origin: Vec::new() }));
+    }
+
+    let opcode = match Opcode::from_mnemonic(&name.text) {
+        Some(opcode) => opcode,
+        None => return Err(AssembleError::UnknownMnemonic(name.span, name.text.clone())),
+    };
+
+    let expected = match opcode.operands() {
+        Operands::None => 0,
+        Operands::Target | Operands::Reg => 1,
+        Operands::RegValue | Operands::RegMem | Operands::MemReg => 2,
+    };
+    expect_operands(name, operands, expected)?;
+
+    let args = match opcode.operands() {
+        Operands::None => vec![],
+        Operands::Target => vec![parse_value(&operands[0])?],
+        Operands::Reg => vec![parse_register(&operands[0])?],
+        Operands::RegValue => vec![parse_register(&operands[0])?, parse_value(&operands[1])?],
+        Operands::RegMem => vec![parse_register(&operands[0])?, parse_memory(&operands[1])?],
+        Operands::MemReg => vec![parse_memory(&operands[0])?, parse_register(&operands[1])?],
+    };
+
+    Ok(Parsed::Line(SourceLine {
+        number,
+        labels,
+        item: Some(Item::Inst(SourceInst { opcode, span: name.span, args })),
+        origin: Vec::new(),
+    }))
+}
+
+/// Parses a single line of source. `number` is the 1-based line number used in spans.
+/// `.include` lines can't be expanded here and are reported as not found, and no macros
+/// are defined.
+pub fn parse_line(line: &str, number: usize) -> Result<SourceLine, AssembleError> {
+    match parse_tokens(&tokenize(line, number), number, &HashMap::new())? {
+        Parsed::Line(line) => Ok(line),
+        Parsed::Include(file, span) => Err(AssembleError::IncludeNotFound(span, file)),
+        Parsed::Call(_, name, _, span) => Err(AssembleError::UnknownMnemonic(span, name)),
+    }
+}
+
+/// How deep `.include`s and macro calls may nest before we assume one is expanding itself.
+const MAX_NESTING_DEPTH: usize = 16;
+
+/// A `.macro` definition. The body is kept as text and parsed again at every call, once the
+/// arguments and local labels have been substituted into it.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(usize, String)>,
+    span: Span,
+}
+
+/// Replaces `\param` with its argument, and `%%` with a prefix unique to this expansion.
+fn substitute(line: &str, params: &[String], args: &[String], local: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+
+    while let Some(pos) = rest.find(['\\', '%']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(after) = tail.strip_prefix("%%") {
+            out.push_str(local);
+            rest = after;
+        } else if let Some(name) = tail.strip_prefix('\\') {
+            let len = name.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(name.len());
+            match params.iter().position(|param| *param == name[..len].to_lowercase()) {
+                Some(idx) => {
+                    out.push_str(&args[idx]);
+                    rest = &name[len..];
+                },
+                None => {
+                    out.push('\\');
+                    rest = name;
+                },
+            }
+        } else {
+            out.push('%');
+            rest = &tail[1..];
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+struct Parser<'a> {
+    include: &'a mut dyn FnMut(&str) -> Option<String>,
+    macros: HashMap<String, Rc<Macro>>,
+    /// Number of macro calls expanded so far, used to keep local labels unique.
+    expansions: usize,
+    lines: Vec<SourceLine>,
+    errors: Vec<AssembleError>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_text(&mut self, text: &str, origin: &[Origin]) {
+        let lines: Vec<(usize, String)> = text.lines().enumerate().map(|(idx, line)| (idx + 1, line.to_owned())).collect();
+        self.parse_lines(&lines, origin);
+    }
+
+    fn parse_lines(&mut self, lines: &[(usize, String)], origin: &[Origin]) {
+        let mut idx = 0;
+        while idx < lines.len() {
+            let (number, ref text) = lines[idx];
+            idx += 1;
+            let tokens = tokenize(text, number);
+
+            if tokens.first().is_some_and(|token| token.text == ".macro") {
+                // Nested definitions are kept in the body, and defined when it is expanded.
+                let mut body = Vec::new();
+                let mut depth = 1;
+                while idx < lines.len() {
+                    let (number, ref text) = lines[idx];
+                    idx += 1;
+                    match tokenize(text, number).first().map(|token| token.text.as_str()) {
+                        Some(".macro") => depth += 1,
+                        Some(".endm") => depth -= 1,
+                        _ => (),
                     }
-                } else if opcode == Opcode::Increment {
-                    triggered = true;
-                    let reg = match tokens[1] {
-                        "a" => Some(Register::A),
-                        "b" => Some(Register::B),
-                        "c" => Some(Register::C),
-                        "d" => Some(Register::D),
-                        "e" => Some(Register::E),
-                        "x" => Some(Register::X),
-                        "y" => Some(Register::Y),
-                        "z" => Some(Register::Z),
-                        _ => None,
-                    };
-                    if reg.is_some() {
-                        let reg = reg.unwrap();
-                        instructions.push((opcode, reg.hex_value(), 0, 0, String::new()));
-                    } else {
-                        return AResult::new(Some(format!("Not a register: {}", tokens[1])));
+                    if depth == 0 {
+                        break;
                     }
+                    body.push((number, text.clone()));
+                }
+                if let Err(err) = self.define(&tokens, body, depth == 0) {
+                    self.errors.push(wrap_error(origin, err));
                 }
+                continue;
             }
-            if !triggered {
-                let reg1 = match tokens[1] {
-                    "a" => Some(Register::A),
-                    "b" => Some(Register::B),
-                    "c" => Some(Register::C),
-                    "d" => Some(Register::D),
-                    "e" => Some(Register::E),
-                    "x" => Some(Register::X),
-                    "y" => Some(Register::Y),
-                    "z" => Some(Register::Z),
-                    _ => None,
-                };
 
-                if tokens.len() == 3 {
-                    let mut data: Option<u32> = None;
-                    let mut reg2 = None;
-                    match tokens[2] {
-                        "a" => { reg2 = Some(Register::A); },
-                        "b" => { reg2 = Some(Register::B); },
-                        "c" => { reg2 = Some(Register::C); },
-                        "d" => { reg2 = Some(Register::D); },
-                        "e" => { reg2 = Some(Register::E); },
-                        "x" => { reg2 = Some(Register::X); },
-                        "y" => { reg2 = Some(Register::Y); },
-                        "z" => { reg2 = Some(Register::Z); },
-                        _ => {
-                            data = tokens[2].parse().ok();
+            match parse_tokens(&tokens, number, &self.macros) {
+                Ok(Parsed::Line(mut line)) => {
+                    line.origin = origin.to_vec();
+                    self.lines.push(line);
+                },
+                Ok(Parsed::Include(file, span)) => {
+                    match (self.include)(&file) {
+                        Some(ref source) if origin.len() < MAX_NESTING_DEPTH => {
+                            let mut inner = origin.to_vec();
+                            inner.push(Origin::Include(span, file.clone()));
+                            self.parse_text(source, &inner);
                         },
+                        _ => self.errors.push(wrap_error(origin, AssembleError::IncludeNotFound(span, file))),
                     }
-
-                    if opcode.is_some() && reg1.is_some() && reg2.is_some() {
-                        let opcode = opcode.unwrap();
-                        let reg1 = reg1.unwrap();
-                        let reg2 = reg2.unwrap();
-                        pc += 1;
-                        instructions.push((opcode, reg1.hex_value(), reg2.hex_value(), 0, String::new()));
-                    } else if opcode.is_some() && reg1.is_some() && data.is_some() {
-                        let opcode = opcode.unwrap();
-                        let reg1 = reg1.unwrap();
-                        let data = data.unwrap();
-                        pc += 2;
-                        instructions.push((opcode, reg1.hex_value(), 0, data, String::new()));
-                    } else {
-                        return AResult::new(Some(format!("invalid line: {:?}", line)));
+                },
+                Ok(Parsed::Call(mut line, name, args, span)) => {
+                    if !line.labels.is_empty() {
+                        line.origin = origin.to_vec();
+                        self.lines.push(line);
                     }
-                } else {
-                    return AResult::new(Some(format!("invalid line: {:?}", line)));
+                    self.expand(&name, &args, span, origin);
+                },
+                Err(err) => self.errors.push(wrap_error(origin, err)),
+            }
+        }
+    }
+
+    /// Records a `.macro name params...` line and the body lines up to its `.endm`.
+    fn define(&mut self, tokens: &[Token], body: Vec<(usize, String)>, terminated: bool) -> Result<(), AssembleError> {
+        let keyword = &tokens[0];
+        if tokens.len() < 2 {
+            return Err(AssembleError::OperandCount(keyword.span, keyword.text.clone(), 1));
+        }
+        let name = &tokens[1];
+        let span = keyword.span.to(tokens[tokens.len() - 1].span);
+        if !terminated {
+            return Err(AssembleError::UnterminatedMacro(span, name.text.clone()));
+        }
+        if !is_identifier(&name.text) || name.text.starts_with('.') ||
+            Register::from_name(&name.text).is_some() || Opcode::from_mnemonic(&name.text).is_some() {
+            return Err(AssembleError::InvalidMacroName(name.span, name.text.clone()));
+        }
+
+        let mut params = Vec::new();
+        for token in &tokens[2..] {
+            if !is_identifier(&token.text) || params.contains(&token.text) {
+                return Err(AssembleError::InvalidOperand(token.span, token.text.clone()));
+            }
+            params.push(token.text.clone());
+        }
+
+        match self.macros.entry(name.text.clone()) {
+            Entry::Occupied(_) => Err(AssembleError::DuplicateMacro(name.span, name.text.clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(Rc::new(Macro { params, body, span }));
+                Ok(())
+            },
+        }
+    }
+
+    fn expand(&mut self, name: &str, args: &[String], call: Span, origin: &[Origin]) {
+        let mac = self.macros[name].clone();
+        if args.len() != mac.params.len() {
+            self.errors.push(wrap_error(origin, AssembleError::OperandCount(call, name.to_owned(), mac.params.len())));
+            return;
+        }
+        if origin.len() >= MAX_NESTING_DEPTH {
+            self.errors.push(wrap_error(origin, AssembleError::RecursiveMacro(call, name.to_owned())));
+            return;
+        }
+
+        self.expansions += 1;
+        let local = format!("{}__{}_", name, self.expansions);
+        let body: Vec<(usize, String)> = mac.body.iter()
+            .map(|&(number, ref line)| (number, substitute(line, &mac.params, args, &local)))
+            .collect();
+
+        let mut inner = origin.to_vec();
+        inner.push(Origin::Macro(call, mac.span, name.to_owned()));
+        self.parse_lines(&body, &inner);
+    }
+}
+
+/// Parses every line of `text`, collecting errors instead of stopping at the first one.
+/// Included files are spliced in where their `.include` was, and macro calls are replaced
+/// by their expansion.
+pub fn parse_source_with(text: &str, include: &mut dyn FnMut(&str) -> Option<String>) -> (Vec<SourceLine>, Vec<AssembleError>) {
+    let mut parser = Parser {
+        include,
+        macros: HashMap::new(),
+        expansions: 0,
+        lines: Vec::new(),
+        errors: Vec::new(),
+    };
+    parser.parse_text(text, &[]);
+    (parser.lines, parser.errors)
+}
+
+pub fn parse_source(text: &str) -> (Vec<SourceLine>, Vec<AssembleError>) {
+    parse_source_with(text, &mut |_| None)
+}
+
+/// Reads an `.include` relative to `dir`, for use with `assemble_with`.
+pub fn read_include(dir: &Path, file: &str) -> Option<String> {
+    let mut text = String::new();
+    File::open(dir.join(file)).ok()?.read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+fn resolve(arg: &Operand, span: Span, labels: &HashMap<String, u32>) -> Result<(Register, Option<u32>), AssembleError> {
+    match *arg {
+        Operand::Register(reg) => Ok((reg, None)),
+        Operand::Immediate(value) => Ok((Register::Null, Some(value))),
+        Operand::Label(ref label) => match labels.get(label) {
+            Some(addr) => Ok((Register::Null, Some(*addr))),
+            None => Err(AssembleError::UndefinedLabel(span, label.clone())),
+        },
+        Operand::Memory(ref inner) => resolve(inner, span, labels),
+    }
+}
+
+fn resolve_value(arg: &Arg, labels: &HashMap<String, u32>) -> Result<u32, AssembleError> {
+    Ok(resolve(&arg.operand, arg.span, labels)?.1.unwrap_or(0))
+}
+
+/// Turns a parsed instruction into its encodable form, looking up any labels it uses.
+fn lower(inst: &SourceInst, labels: &HashMap<String, u32>) -> Result<Instruction, AssembleError> {
+    let args = &inst.args;
+    let (reg1, (reg2, imm)) = match inst.opcode.operands() {
+        Operands::None => (Register::Null, (Register::Null, None)),
+        Operands::Target => (Register::Null, resolve(&args[0].operand, args[0].span, labels)?),
+        Operands::Reg => (resolve(&args[0].operand, args[0].span, labels)?.0, (Register::Null, None)),
+        Operands::RegValue | Operands::RegMem => {
+            let reg1 = resolve(&args[0].operand, args[0].span, labels)?.0;
+            (reg1, resolve(&args[1].operand, args[1].span, labels)?)
+        },
+        Operands::MemReg => {
+            let reg1 = resolve(&args[1].operand, args[1].span, labels)?.0;
+            (reg1, resolve(&args[0].operand, args[0].span, labels)?)
+        },
+    };
+
+    Ok(Instruction::new(inst.opcode, reg1, reg2, imm))
+}
+
+/// A change made by the peephole optimiser. The span is the line changed, or the `.include`
+/// or macro call it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rewrite {
+    /// `mov r, 0` then `add r, x`, replaced by `mov r, x`.
+    FoldedMoveAdd(Span),
+    /// A jump to the instruction right after it, removed.
+    JumpToNext(Span),
+    /// A `cmp` repeating the one before it, with only conditional jumps in between, removed.
+    RepeatedCompare(Span),
+}
+
+impl Rewrite {
+    pub fn span(&self) -> Span {
+        match *self {
+            Rewrite::FoldedMoveAdd(span) | Rewrite::JumpToNext(span) | Rewrite::RepeatedCompare(span) => span,
+        }
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: ", span.line, span.column)?;
+        match *self {
+            Rewrite::FoldedMoveAdd(_) => write!(f, "folded `mov` of 0 and `add` into one `mov`"),
+            Rewrite::JumpToNext(_) => write!(f, "removed jump to the next instruction"),
+            Rewrite::RepeatedCompare(_) => write!(f, "removed repeated `cmp`"),
+        }
+    }
+}
+
+/// Settings for `assemble_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Options {
+    /// Run the peephole optimiser before encoding.
+    pub optimise: bool,
+}
+
+fn is_conditional_jump(opcode: Opcode) -> bool {
+    opcode.operands() == Operands::Target && opcode != Opcode::Jump && opcode != Opcode::Call
+}
+
+/// Where a rewrite should be reported: the line itself, or where it was pulled in from.
+fn report_span(line: &SourceLine, span: Span) -> Span {
+    match line.origin.first() {
+        Some(&Origin::Include(span, _)) | Some(&Origin::Macro(span, _, _)) => span,
+        None => span,
+    }
+}
+
+/// The next line after `idx` that emits or changes something, and whether any line up to
+/// and including it has a label.
+fn next_item(lines: &[SourceLine], idx: usize) -> (Option<usize>, bool) {
+    let mut labelled = false;
+    for (next, line) in lines.iter().enumerate().skip(idx + 1) {
+        labelled |= !line.labels.is_empty();
+        if line.item.is_some() {
+            return (Some(next), labelled);
+        }
+    }
+    (None, labelled)
+}
+
+/// Tries each rewrite on the instruction at `idx`.
+fn rewrite_at(lines: &mut [SourceLine], idx: usize) -> Option<Rewrite> {
+    let inst = lines[idx].inst()?.clone();
+    let (next, labelled) = next_item(lines, idx);
+
+    // Labels between the two would let other code jump to the `add` alone.
+    if let (Opcode::Move, Some(next), false) = (inst.opcode, next, labelled) {
+        if let (Operand::Register(reg), Operand::Immediate(0)) = (&inst.args[0].operand, &inst.args[1].operand) {
+            let fold = lines[next].inst().filter(|add| {
+                add.opcode == Opcode::Add && add.args[0].operand == Operand::Register(*reg) &&
+                    add.args[1].operand != Operand::Register(*reg)
+            }).map(|add| add.args[1].clone());
+            if let Some(value) = fold {
+                if let Some(Item::Inst(ref mut mov)) = lines[idx].item {
+                    mov.args[1] = value;
                 }
+                lines[next].item = None;
+                return Some(Rewrite::FoldedMoveAdd(report_span(&lines[idx], inst.span)));
             }
-        } else {
-            return AResult::new(Some(format!("invalid line: {:?}", line)));
         }
     }
-    for inst in instructions.iter() {
-        let opcode = inst.0;
-        let r1 = inst.1;
-        let r2 = inst.2;
-        let data = inst.3;
-        let label = inst.4.clone();
 
-        if (opcode == Opcode::Jump || opcode == Opcode::IfGreater || opcode == Opcode::IfLess ||
-            opcode == Opcode::IfEqual || opcode == Opcode::IfNotEqual || opcode == Opcode::IfZero) && data == u32::MAX {
-            let data = label_map.get(&label);
-            if data.is_some() {
-                let data = data.unwrap();
-                write_inst(&mut program, opcode, 0, 0, *data + 1);
-            } else {
-                return AResult::new(Some(format!("Label not found! {}", label)));
+    if inst.opcode.operands() == Operands::Target && inst.opcode != Opcode::Call {
+        if let Operand::Label(ref target) = inst.args[0].operand {
+            let end = next.map_or(lines.len(), |next| next + 1);
+            if lines[idx + 1..end].iter().any(|line| line.labels.iter().any(|(label, _)| label == target)) {
+                lines[idx].item = None;
+                return Some(Rewrite::JumpToNext(report_span(&lines[idx], inst.span)));
             }
+        }
+    }
+
+    if inst.opcode == Opcode::Compare {
+        let mut at = idx;
+        while let (Some(next), false) = next_item(lines, at) {
+            match lines[next].inst() {
+                Some(other) if other.opcode == Opcode::Compare => {
+                    let same = other.args.iter().map(|arg| &arg.operand).eq(inst.args.iter().map(|arg| &arg.operand));
+                    if !same {
+                        return None;
+                    }
+                    let span = report_span(&lines[next], other.span);
+                    lines[next].item = None;
+                    return Some(Rewrite::RepeatedCompare(span));
+                },
+                Some(other) if is_conditional_jump(other.opcode) => at = next,
+                _ => return None,
+            }
+        }
+    }
+
+    None
+}
+
+/// Rewrites redundant instruction sequences in the code section, before any addresses are
+/// laid out. A removed instruction leaves its labels behind, so they point at whatever
+/// follows, which is where control would have gone anyway. Sequences with a label part way
+/// through are left alone, since something else could jump into the middle.
+///
+/// Folding `mov r, 0` into an `add` leaves carry and overflow as they were rather than
+/// clearing them. No instruction tests either, but an interrupt handler could see them.
+pub fn optimise(lines: &mut [SourceLine]) -> Vec<Rewrite> {
+    let mut rewrites = Vec::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut section = Section::Code;
+        for idx in 0..lines.len() {
+            match lines[idx].item {
+                Some(Item::Directive(Directive::Code, _)) => section = Section::Code,
+                Some(Item::Directive(Directive::Data, _)) => section = Section::Data,
+                Some(Item::Inst(_)) if section == Section::Code => {
+                    if let Some(rewrite) = rewrite_at(lines, idx) {
+                        rewrites.push(rewrite);
+                        changed = true;
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+    rewrites.sort_by_key(|rewrite| (rewrite.span().line, rewrite.span().column));
+    rewrites
+}
+
+fn write_inst(buffer: &mut Vec<u32>, inst: &Instruction) {
+//    println!("[BIN-WRITE] {:?}", inst);
+    encoding::encode(inst, buffer);
+}
+
+/// Writes `words` at `addr`, padding with zeroes if `.org` left a gap.
+fn emit(buffer: &mut Vec<u32>, addr: u32, words: &[u32]) {
+    let addr = addr as usize;
+    if buffer.len() < addr + words.len() {
+        buffer.resize(addr + words.len(), 0);
+    }
+    buffer[addr..addr + words.len()].copy_from_slice(words);
+}
+
+/// Assembles `file_in` and writes the binary to `file_out`. Nothing is written if there are errors.
+/// `.include` paths are relative to the directory `file_in` is in. Failing to read `file_in` or
+/// write `file_out` is an `io::Error` rather than an assembler error.
+pub fn assemble_program(file_in: &str, file_out: &str) -> io::Result<AResult> {
+    let mut text = String::new();
+    File::open(file_in)?.read_to_string(&mut text)?;
+
+    let dir = Path::new(file_in).parent().unwrap_or_else(|| Path::new("."));
+    match assemble_with(&text, &mut |file| read_include(dir, file)) {
+        Ok(program) => {
+            File::create(file_out)?.write_all(&program.to_bytes())?;
+            Ok(AResult::new(Vec::new()))
+        },
+        Err(errors) => Ok(AResult::new(errors)),
+    }
+}
+
+/// Assembles source text straight from memory. `.include` is not available, use
+/// `assemble_with` for that.
+pub fn assemble(text: &str) -> Result<Program, Vec<AssembleError>> {
+    assemble_with(text, &mut |_| None)
+}
+
+/// Assembles source text, asking `include` for the contents of each `.include`d file.
+///
+/// The first pass lays out every instruction and data word and records label addresses,
+/// the second resolves label operands and encodes. A `hlt` is appended after the last
+/// instruction in the code section.
+pub fn assemble_with(text: &str, include: &mut dyn FnMut(&str) -> Option<String>) -> Result<Program, Vec<AssembleError>> {
+    assemble_with_options(text, include, Options::default()).map(|(program, _)| program)
+}
+
+/// `assemble_with`, optionally running `optimise` on the parsed source first. Returns what
+/// the optimiser changed along with the program.
+pub fn assemble_with_options(text: &str, include: &mut dyn FnMut(&str) -> Option<String>, options: Options)
+                             -> Result<(Program, Vec<Rewrite>), Vec<AssembleError>> {
+    let (mut lines, mut errors) = parse_source_with(text, include);
+    let rewrites = if options.optimise && errors.is_empty() { optimise(&mut lines) } else { Vec::new() };
+
+    let mut label_map = HashMap::new();
+    let mut symbols = Vec::new();
+    let mut section = Section::Code;
+    let mut code_pc = 0;
+    let mut data_pc = 0;
+    // Data reaches the vector table on purpose with an `.org`, otherwise only the first
+    // line to run into it is reported.
+    let mut into_vectors = false;
+    for line in lines.iter() {
+        {
+            let pc = if section == Section::Code { code_pc } else { data_pc };
+            for &(ref label, span) in line.labels.iter() {
+                match label_map.entry(label.clone()) {
+                    Entry::Occupied(_) => errors.push(line.wrap_error(AssembleError::DuplicateLabel(span, label.clone()))),
+                    Entry::Vacant(entry) => {
+                        entry.insert(pc);
+                        symbols.push(Symbol { name: label.clone(), section, addr: pc });
+                    },
+                }
+            }
+        }
+
+        let data = section == Section::Data;
+        let pc = if section == Section::Code { &mut code_pc } else { &mut data_pc };
+        // Only the first line past the end is reported, PC stays past it after that.
+        let mut advance = |pc: &mut u32, size: u32, span: Span| {
+            let end = pc.saturating_add(size);
+            if end > MAX_SECTION_WORDS && *pc <= MAX_SECTION_WORDS {
+                errors.push(line.wrap_error(AssembleError::SectionTooLarge(span, end)));
+            } else if data && !into_vectors && end > DEFAULT_VECTOR_BASE {
+                errors.push(line.wrap_error(AssembleError::IntoVectorTable(span, DEFAULT_VECTOR_BASE)));
+                into_vectors = true;
+            }
+            *pc = end;
+        };
+        match line.item {
+            Some(Item::Inst(ref inst)) => advance(pc, inst.size(), inst.span),
+            Some(Item::Directive(Directive::Equ(ref name, ref value), span)) => {
+                let value = match resolve_value(value, &label_map) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        errors.push(line.wrap_error(err));
+                        continue;
+                    },
+                };
+                if label_map.insert(name.clone(), value).is_some() {
+                    errors.push(line.wrap_error(AssembleError::DuplicateLabel(span, name.clone())));
+                }
+            },
+            Some(Item::Directive(Directive::Org(ref addr), span)) => {
+                match resolve_value(addr, &label_map) {
+                    Ok(addr) if addr < *pc => errors.push(line.wrap_error(AssembleError::OrgBackwards(span, addr))),
+                    Ok(addr) if addr > MAX_SECTION_WORDS => {
+                        errors.push(line.wrap_error(AssembleError::SectionTooLarge(span, addr)))
+                    },
+                    Ok(addr) => {
+                        into_vectors |= data && addr >= DEFAULT_VECTOR_BASE;
+                        *pc = addr;
+                    },
+                    Err(err) => errors.push(line.wrap_error(err)),
+                }
+            },
+            Some(Item::Directive(Directive::Code, _)) => section = Section::Code,
+            Some(Item::Directive(Directive::Data, _)) => section = Section::Data,
+            Some(Item::Directive(ref directive, span)) => advance(pc, directive.size(), span),
+            None => (),
+        }
+    }
+
+    let mut code = Vec::new();
+    let mut data = Vec::new();
+    section = Section::Code;
+    code_pc = 0;
+    data_pc = 0;
+    for line in lines.iter() {
+        let (buffer, pc) = if section == Section::Code {
+            (&mut code, &mut code_pc)
         } else {
-            write_inst(&mut program, opcode, r1, r2, data);
+            (&mut data, &mut data_pc)
+        };
+
+        let mut words = Vec::new();
+        match line.item {
+            Some(Item::Inst(ref inst)) => match lower(inst, &label_map) {
+                Ok(inst) => write_inst(&mut words, &inst),
+                Err(err) => errors.push(line.wrap_error(err)),
+            },
+            Some(Item::Directive(Directive::Word(ref values), _)) => {
+                for value in values {
+                    match resolve_value(value, &label_map) {
+                        Ok(value) => words.push(value),
+                        Err(err) => {
+                            errors.push(line.wrap_error(err));
+                            words.push(0);
+                        },
+                    }
+                }
+            },
+            Some(Item::Directive(Directive::Str(ref text), _)) => {
+                words.extend(text.chars().map(|c| c as u32));
+                words.push(0);
+            },
+            Some(Item::Directive(Directive::Org(ref addr), _)) => {
+                if let Ok(addr) = resolve_value(addr, &label_map) {
+                    *pc = addr.max(*pc).min(MAX_SECTION_WORDS);
+                }
+            },
+            Some(Item::Directive(Directive::Code, _)) => section = Section::Code,
+            Some(Item::Directive(Directive::Data, _)) => section = Section::Data,
+            Some(Item::Directive(Directive::Equ(..), _)) | None => (),
+        }
+
+        // Anything past the end of the section was reported in the first pass.
+        if let Some(end) = pc.checked_add(words.len() as u32).filter(|end| *end <= MAX_SECTION_WORDS) {
+            emit(buffer, *pc, &words);
+            *pc = end;
         }
     }
-    write_inst(&mut program, Opcode::Halt, 0, 0, 0);
+    let end = code_pc.max(code.len() as u32);
+    let mut halt = Vec::new();
+    write_inst(&mut halt, &Instruction::new(Opcode::Halt, Register::Null, Register::Null, None));
+    emit(&mut code, end, &halt);
 
-    write_program(file_out, &program);
-    return AResult::new(None);
+    if errors.is_empty() {
+        symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then(a.name.cmp(&b.name)));
+        let entry = symbols.iter()
+            .find(|sym| sym.name == "start" && sym.section == Section::Code)
+            .map(|sym| sym.addr)
+            .unwrap_or(0);
+
+        Ok((Program {
+            entry,
+            code,
+            data,
+            symbols,
+        }, rewrites))
+    } else {
+        errors.sort_by_key(|err| (err.line(), err.column()));
+        Err(errors)
+    }
 }
@@ -0,0 +1,87 @@
+extern crate zpu;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+
+use zpu::analysis;
+use zpu::assembler::{self, AssembleError, Options};
+
+fn usage(name: &str) -> ! {
+    eprintln!("usage: {} <in.asm> [-o <out.bin>] [--ports PORT,PORT,...] [-O | --optimise] [--report]", name);
+    process::exit(2);
+}
+
+/// Prints an error as `file:line:column: message`, then the line it's on with the span underlined.
+fn report(file: &str, source: &str, err: &AssembleError) {
+    eprintln!("{}:{}", file, err);
+    let span = err.span();
+    if let Some(line) = source.lines().nth(span.line.saturating_sub(1)) {
+        eprintln!("    {}", line);
+        eprintln!("    {}{}", " ".repeat(span.column.saturating_sub(1)), "^".repeat(span.len.max(1)));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut input = None;
+    let mut output = None;
+    let mut ports = None;
+    let mut options = Options { optimise: false };
+    let mut show_rewrites = false;
+    let mut idx = 1;
+    while idx < args.len() {
+        match args[idx].as_str() {
+            "-o" if idx + 1 < args.len() && output.is_none() => {
+                output = Some(args[idx + 1].clone());
+                idx += 1;
+            },
+            "--ports" if idx + 1 < args.len() && ports.is_none() => {
+                let list: Option<Vec<u32>> = args[idx + 1].split(',').map(assembler::parse_number).collect();
+                ports = Some(list.unwrap_or_else(|| usage(&args[0])));
+                idx += 1;
+            },
+            "-O" | "--optimise" => options.optimise = true,
+            "--report" => show_rewrites = true,
+            arg if !arg.starts_with('-') && input.is_none() => input = Some(arg.to_string()),
+            _ => usage(&args[0]),
+        }
+        idx += 1;
+    }
+    let input = input.unwrap_or_else(|| usage(&args[0]));
+    let output = output.unwrap_or_else(|| Path::new(&input).with_extension("bin").to_string_lossy().into_owned());
+
+    let mut source = String::new();
+    if let Err(err) = File::open(&input).and_then(|mut file| file.read_to_string(&mut source)) {
+        eprintln!("{}: {}", input, err);
+        process::exit(1);
+    }
+
+    let dir = Path::new(&input).parent().unwrap_or_else(|| Path::new("."));
+    let (program, rewrites) = match assembler::assemble_with_options(&source, &mut |file| assembler::read_include(dir, file), options) {
+        Ok(result) => result,
+        Err(errors) => {
+            for err in errors.iter() {
+                report(&input, &source, err);
+            }
+            eprintln!("{} error{}, nothing written", errors.len(), if errors.len() == 1 { "" } else { "s" });
+            process::exit(1);
+        },
+    };
+
+    if show_rewrites {
+        for rewrite in &rewrites {
+            eprintln!("{}:{}", input, rewrite);
+        }
+    }
+    for lint in analysis::lint(&program, ports.as_deref()) {
+        eprintln!("{}: warning: {}", input, lint);
+    }
+
+    if let Err(err) = File::create(&output).and_then(|mut file| file.write_all(&program.to_bytes())) {
+        eprintln!("{}: {}", output, err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,31 @@
+extern crate zpu;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+use zpu::disassembler;
+use zpu::encoding::Program;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: {} <image.bin>", args[0]);
+        process::exit(2);
+    }
+
+    let mut bytes = Vec::new();
+    if let Err(err) = File::open(&args[1]).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        eprintln!("{}: {}", args[1], err);
+        process::exit(1);
+    }
+
+    match Program::from_bytes(&bytes) {
+        Ok(program) => print!("{}", disassembler::listing(&program)),
+        Err(err) => {
+            eprintln!("{}: {}", args[1], err);
+            process::exit(1);
+        },
+    }
+}
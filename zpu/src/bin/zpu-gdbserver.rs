@@ -0,0 +1,60 @@
+extern crate zpu;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpListener;
+use std::process;
+
+use zpu::gdbstub;
+use zpu::zpu::ZPU;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <image.bin> [port]", args[0]);
+        process::exit(2);
+    }
+    let port = match args.get(2).map(|port| port.parse::<u16>()) {
+        None => 1234,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            eprintln!("{}: not a port number", args[2]);
+            process::exit(2);
+        },
+    };
+
+    let mut bytes = Vec::new();
+    if let Err(err) = File::open(&args[1]).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        eprintln!("{}: {}", args[1], err);
+        process::exit(1);
+    }
+    let mut zpu = match ZPU::from_bytes(&bytes) {
+        Ok(zpu) => zpu,
+        Err(err) => {
+            eprintln!("{}: {}", args[1], err);
+            process::exit(1);
+        },
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("can't listen on port {}: {}", port, err);
+            process::exit(1);
+        },
+    };
+    println!("waiting for gdb on 127.0.0.1:{}", port);
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| {
+            gdbstub::serve(&mut zpu, stream, &mut |output| println!("[OUT] port {}: {}", output.port, output.data))
+        });
+        if let Err(err) = result {
+            eprintln!("connection lost: {}", err);
+        }
+        if !zpu.running {
+            break;
+        }
+    }
+}
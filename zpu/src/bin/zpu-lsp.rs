@@ -0,0 +1,19 @@
+extern crate zpu;
+
+use std::io;
+use std::process;
+
+use zpu::lsp;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match lsp::serve(stdin.lock(), stdout.lock()) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("zpu-lsp: {}", err);
+            process::exit(1);
+        },
+    }
+}
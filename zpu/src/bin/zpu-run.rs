@@ -0,0 +1,89 @@
+extern crate zpu;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+use zpu::assembler;
+use zpu::bus::Script;
+use zpu::zpu::{RunOutcome, ZPU};
+
+/// Cycles run before giving up, unless `--cycles` says otherwise.
+const DEFAULT_CYCLES: u64 = 10_000_000;
+
+fn usage(name: &str) -> ! {
+    eprintln!("usage: {} <image.bin> [--cycles N] [--input PORT=VALUE,VALUE,...]...", name);
+    eprintln!("exits with 0 on HLT, 1 on a fault, 3 when the cycles run out");
+    process::exit(2);
+}
+
+/// `PORT=VALUE,VALUE,...`, numbers written as in assembly.
+fn parse_input(arg: &str) -> Option<(u32, Vec<u32>)> {
+    let mut parts = arg.splitn(2, '=');
+    let port = assembler::parse_number(parts.next()?)?;
+    let values = parts.next()?;
+    if values.is_empty() {
+        return Some((port, Vec::new()));
+    }
+    let values: Option<Vec<u32>> = values.split(',').map(assembler::parse_number).collect();
+    Some((port, values?))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut image = None;
+    let mut cycles = DEFAULT_CYCLES;
+    let mut inputs = Vec::new();
+    let mut idx = 1;
+    while idx < args.len() {
+        let value = args.get(idx + 1);
+        match (args[idx].as_str(), value) {
+            ("--cycles", Some(value)) => {
+                cycles = value.parse().unwrap_or_else(|_| usage(&args[0]));
+                idx += 1;
+            },
+            ("--input", Some(value)) => {
+                inputs.push(parse_input(value).unwrap_or_else(|| usage(&args[0])));
+                idx += 1;
+            },
+            (arg, _) if !arg.starts_with('-') && image.is_none() => image = Some(arg.to_string()),
+            _ => usage(&args[0]),
+        }
+        idx += 1;
+    }
+    let image = image.unwrap_or_else(|| usage(&args[0]));
+
+    let mut bytes = Vec::new();
+    if let Err(err) = File::open(&image).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        eprintln!("{}: {}", image, err);
+        process::exit(1);
+    }
+    let mut zpu = match ZPU::from_bytes(&bytes) {
+        Ok(zpu) => zpu,
+        Err(err) => {
+            eprintln!("{}: {}", image, err);
+            process::exit(1);
+        },
+    };
+    for (port, values) in inputs {
+        zpu.bus.attach(&[port], Box::new(Script::new(&values)));
+    }
+
+    loop {
+        let left = cycles.saturating_sub(zpu.cycles);
+        match zpu.run(left.min(usize::MAX as u64) as usize) {
+            RunOutcome::Output(output) => println!("port {}: {}", output.port, output.data),
+            RunOutcome::Halted => process::exit(0),
+            RunOutcome::Fault(fault) => {
+                eprintln!("fault {} at PC {}: {}", fault.code(), zpu.pc, fault);
+                process::exit(1);
+            },
+            RunOutcome::Budget => {
+                eprintln!("still running after {} cycles", zpu.cycles);
+                process::exit(3);
+            },
+            RunOutcome::Breakpoint(_) | RunOutcome::Watchpoint(..) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+/// A piece of hardware plugged into one or more ZPU ports.
+pub trait Device {
+    /// Called when the ZPU runs `IN` on one of the device's ports.
+    fn read(&mut self, port: u32) -> u32;
+    /// Called when the ZPU runs `OUT` on one of the device's ports.
+    fn write(&mut self, port: u32, data: u32);
+}
+
+/// Routes port reads and writes to the devices attached to them.
+pub struct Bus {
+    devices: Vec<Box<dyn Device>>,
+    ports: HashMap<u32, usize>,
+}
+
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus {
+            devices: Vec::new(),
+            ports: HashMap::new(),
+        }
+    }
+
+    /// Attaches a device to every port in `ports`, replacing whatever was mapped there.
+    /// A device left with no ports is dropped.
+    pub fn attach(&mut self, ports: &[u32], device: Box<dyn Device>) {
+        let idx = self.devices.len();
+        self.devices.push(device);
+        for port in ports {
+            self.ports.insert(*port, idx);
+        }
+        self.prune();
+    }
+
+    /// Unmaps a port. The device stays attached to any other ports it was given, and is
+    /// dropped once it has none.
+    pub fn detach(&mut self, port: u32) {
+        self.ports.remove(&port);
+        self.prune();
+    }
+
+    /// Drops the devices no port maps to.
+    fn prune(&mut self) {
+        let mut used = vec![false; self.devices.len()];
+        for idx in self.ports.values() {
+            used[*idx] = true;
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+
+        // Where each kept device ends up once the others are removed.
+        let moved: Vec<usize> = used.iter()
+            .scan(0, |next, used| {
+                let idx = *next;
+                *next += *used as usize;
+                Some(idx)
+            })
+            .collect();
+        let mut idx = 0;
+        self.devices.retain(|_| {
+            idx += 1;
+            used[idx - 1]
+        });
+        for idx in self.ports.values_mut() {
+            *idx = moved[*idx];
+        }
+    }
+
+    pub fn is_mapped(&self, port: u32) -> bool {
+        self.ports.contains_key(&port)
+    }
+
+    pub fn mapped_ports(&self) -> Vec<u32> {
+        let mut ports: Vec<u32> = self.ports.keys().cloned().collect();
+        ports.sort();
+        ports
+    }
+
+    /// Returns `None` when no device is mapped to the port.
+    pub fn read(&mut self, port: u32) -> Option<u32> {
+        match self.ports.get(&port) {
+            Some(idx) => Some(self.devices[*idx].read(port)),
+            None => None,
+        }
+    }
+
+    /// Returns `false` when no device is mapped to the port.
+    pub fn write(&mut self, port: u32, data: u32) -> bool {
+        match self.ports.get(&port) {
+            Some(idx) => {
+                self.devices[*idx].write(port, data);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// A single word shared between the ZPU and the host. Reads return the current value,
+/// writes replace it, and the host sees both through the `Rc<Cell<u32>>` it handed in.
+pub struct Latch {
+    value: Rc<Cell<u32>>,
+}
+
+impl Latch {
+    pub fn new(value: Rc<Cell<u32>>) -> Latch {
+        Latch {
+            value,
+        }
+    }
+}
+
+impl Device for Latch {
+    fn read(&mut self, _port: u32) -> u32 {
+        self.value.get()
+    }
+
+    fn write(&mut self, _port: u32, data: u32) {
+        self.value.set(data);
+    }
+}
+
+/// Input read from a list: each read takes the next value, and once they run out reads
+/// return 0. Writes are ignored. For running programs without the game attached.
+pub struct Script {
+    values: VecDeque<u32>,
+}
+
+impl Script {
+    pub fn new(values: &[u32]) -> Script {
+        Script {
+            values: values.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Device for Script {
+    fn read(&mut self, _port: u32) -> u32 {
+        self.values.pop_front().unwrap_or(0)
+    }
+
+    fn write(&mut self, _port: u32, _data: u32) {}
+}
@@ -0,0 +1,52 @@
+/// Cycles per second a ZPU runs at, unless its clock is changed.
+pub const DEFAULT_CLOCK_HZ: u64 = 10_000;
+
+/// Turns wall time into ZPU cycles. Time is counted in whole nanoseconds and the fractions
+/// of a cycle are carried over, so the same sequence of ticks always gives the same cycles
+/// whatever the frame rate.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    /// Cycles per second.
+    pub hz: u64,
+    /// Cycles owed to the ZPU. Negative when the last instruction ran past the budget,
+    /// and paid back from the next tick.
+    credit: i64,
+    /// Nanoseconds times `hz` that didn't add up to a whole cycle yet.
+    remainder: u128,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new(DEFAULT_CLOCK_HZ)
+    }
+}
+
+impl Clock {
+    pub fn new(hz: u64) -> Clock {
+        Clock {
+            hz,
+            credit: 0,
+            remainder: 0,
+        }
+    }
+
+    /// Adds `nanos` of wall time and returns the cycles owed. At most a second's worth is
+    /// owed at once, so a long frame doesn't make the ZPU race to catch up.
+    pub fn tick(&mut self, nanos: u64) -> i64 {
+        let total = self.remainder + nanos as u128 * self.hz as u128;
+        self.remainder = total % 1_000_000_000;
+        let cycles = (total / 1_000_000_000).min(self.hz as u128) as i64;
+        self.credit = (self.credit + cycles).min(self.hz as i64);
+        self.credit
+    }
+
+    pub fn spend(&mut self, cycles: u64) {
+        self.credit -= cycles as i64;
+    }
+
+    /// Forgets any cycles owed, for when the ZPU stops and shouldn't make up the time later.
+    pub fn reset(&mut self) {
+        self.credit = 0;
+        self.remainder = 0;
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::BTreeSet;
+
+use zpu::{Fault, Output, Register};
+
+/// A location the debugger watches for changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    Register(Register),
+    Memory(u32),
+}
+
+/// Why `ZPU::debug_step`, `step_over` or `resume` handed control back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// One instruction ran. Holds anything it wrote to an unmapped port.
+    Stepped(Option<Output>),
+    /// The PC reached a breakpoint. The instruction there has not run yet.
+    Breakpoint(u32),
+    /// A watched location changed from the first value to the second.
+    Watchpoint(Watch, u32, u32),
+    /// An OUT to an unmapped port, which the host needs to handle before continuing.
+    Output(Output),
+    Halted,
+    Fault(Fault),
+    /// The step budget ran out first.
+    Limit,
+}
+
+/// Breakpoints and watchpoints. Kept across `ZPU::load`, so they survive reassembling.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u32>,
+    pub watchpoints: Vec<Watch>,
+    /// Set when an OUT stop landed on a breakpoint, so the next `resume` stops there
+    /// before running it instead of stepping past it.
+    pub break_pending: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Returns whether there is a breakpoint at `addr` afterwards.
+    pub fn toggle_breakpoint(&mut self, addr: u32) -> bool {
+        if !self.breakpoints.remove(&addr) {
+            self.breakpoints.insert(addr);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_breakpoint(&self, addr: u32) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    pub fn watch(&mut self, watch: Watch) {
+        if !self.watchpoints.contains(&watch) {
+            self.watchpoints.push(watch);
+        }
+    }
+
+    pub fn unwatch(&mut self, watch: Watch) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|other| *other != watch);
+        self.watchpoints.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use encoding::{self, Instruction, Program, Section, Symbol};
+use zpu::{Operands, Register};
+
+/// One instruction, or one data word, of a disassembled program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub section: Section,
+    pub addr: u32,
+    /// The raw words, including any immediate.
+    pub words: Vec<u32>,
+    /// Symbols defined at `addr` in this section.
+    pub labels: Vec<String>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    /// Formats the line for a listing: address, raw words, then the assembly.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for label in self.labels.iter() {
+            writeln!(f, "{:>24}{}:", "", label)?;
+        }
+        let words: Vec<String> = self.words.iter().map(|word| format!("{:08x}", word)).collect();
+        write!(f, "{:04x}  {:<17}     {}", self.addr, words.join(" "), self.text)
+    }
+}
+
+fn symbol_at(symbols: &[Symbol], section: Section, addr: u32) -> Option<&str> {
+    symbols.iter().find(|sym| sym.section == section && sym.addr == addr).map(|sym| sym.name.as_str())
+}
+
+fn labels_at(symbols: &[Symbol], section: Section, addr: u32) -> Vec<String> {
+    symbols.iter().filter(|sym| sym.section == section && sym.addr == addr).map(|sym| sym.name.clone()).collect()
+}
+
+/// The second operand: a register if one is set, otherwise the immediate, shown as the
+/// name of a symbol in `section` if one points there.
+fn value(inst: &Instruction, symbols: &[Symbol], section: Option<Section>) -> String {
+    if inst.reg2 != Register::Null {
+        return inst.reg2.name().to_owned();
+    }
+    let imm = inst.imm.unwrap_or(0);
+    match section.and_then(|section| symbol_at(symbols, section, imm)) {
+        Some(name) => name.to_owned(),
+        None => imm.to_string(),
+    }
+}
+
+/// Formats an instruction the way the assembler would accept it. Jump targets are replaced
+/// by code labels, and memory operands by data labels, when `symbols` has one.
+pub fn format_inst(inst: &Instruction, symbols: &[Symbol]) -> String {
+    let name = inst.opcode.mnemonic();
+    match inst.opcode.operands() {
+        Operands::None => name.to_owned(),
+        Operands::Target => format!("{} {}", name, value(inst, symbols, Some(Section::Code))),
+        Operands::Reg => format!("{} {}", name, inst.reg1.name()),
+        Operands::RegValue => format!("{} {}, {}", name, inst.reg1.name(), value(inst, symbols, None)),
+        Operands::RegMem => format!("{} {}, [{}]", name, inst.reg1.name(), value(inst, symbols, Some(Section::Data))),
+        Operands::MemReg => format!("{} [{}], {}", name, value(inst, symbols, Some(Section::Data)), inst.reg1.name()),
+    }
+}
+
+/// Decodes the code section instruction by instruction, then lists the data section one
+/// word per line. Words that don't decode, such as an unknown opcode or a final instruction
+/// whose immediate is missing, are shown as a `.word`.
+pub fn disassemble(program: &Program) -> Vec<Line> {
+    let symbols = &program.symbols;
+    let mut lines = Vec::new();
+
+    let mut pc = 0;
+    while (pc as usize) < program.code.len() {
+        let (words, text) = match encoding::decode(&program.code, pc) {
+            Ok(inst) => {
+                let end = (pc + inst.size()) as usize;
+                (program.code[pc as usize..end].to_vec(), format_inst(&inst, symbols))
+            },
+            Err(_) => {
+                let word = program.code[pc as usize];
+                (vec![word], format!(".word {:#x}", word))
+            },
+        };
+        lines.push(Line {
+            section: Section::Code,
+            addr: pc,
+            labels: labels_at(symbols, Section::Code, pc),
+            text,
+            words,
+        });
+        pc += lines[lines.len() - 1].words.len() as u32;
+    }
+
+    for (addr, word) in program.data.iter().enumerate() {
+        let addr = addr as u32;
+        lines.push(Line {
+            section: Section::Data,
+            addr,
+            words: vec![*word],
+            labels: labels_at(symbols, Section::Data, addr),
+            text: format!(".word {}", word),
+        });
+    }
+
+    lines
+}
+
+/// A full listing of `program`, with a header line before each section.
+pub fn listing(program: &Program) -> String {
+    let mut out = format!("; entry {:04x}\n.code\n", program.entry);
+    let mut in_data = false;
+    for line in disassemble(program) {
+        if line.section == Section::Data && !in_data {
+            out.push_str(".data\n");
+            in_data = true;
+        }
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use zpu::{Fault, Opcode, Register};
+
+/// Set in an instruction word when the following word is an immediate operand.
+pub const IMMEDIATE_FLAG: u32 = 1 << 15;
+
+/// "ZPU1" when read as bytes.
+pub const IMAGE_MAGIC: u32 = 0x3155_505A;
+pub const IMAGE_VERSION: u32 = 1;
+
+/// A single decoded instruction. `imm` holds the operand word for instructions that take one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub reg1: Register,
+    pub reg2: Register,
+    pub imm: Option<u32>,
+}
+
+impl Instruction {
+    pub fn new(opcode: Opcode, reg1: Register, reg2: Register, imm: Option<u32>) -> Instruction {
+        Instruction {
+            opcode,
+            reg1,
+            reg2,
+            imm,
+        }
+    }
+
+    /// Size in words, including the immediate.
+    pub fn size(&self) -> u32 {
+        if self.imm.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// Appends the instruction's words to `out`.
+pub fn encode(inst: &Instruction, out: &mut Vec<u32>) {
+    let mut word = ((inst.opcode.hex_value() as u32) << 16) |
+        ((inst.reg1.hex_value() as u32 & 0xF) << 4) |
+        (inst.reg2.hex_value() as u32 & 0xF);
+    if inst.imm.is_some() {
+        word |= IMMEDIATE_FLAG;
+    }
+    out.push(word);
+    if let Some(imm) = inst.imm {
+        out.push(imm);
+    }
+}
+
+fn decode_register(value: u32) -> Result<Register, Fault> {
+    match value {
+        0 => Ok(Register::Null),
+        1..=8 => Ok(Register::from_value(value as u8)),
+        _ => Err(Fault::BadRegister(value as u8)),
+    }
+}
+
+/// Decodes the instruction starting at word `pc`, or gives the fault the ZPU would raise
+/// trying to run it: `pc` past the end of the code, an unknown opcode, a register number
+/// above 8 or a missing first register, or an immediate past the end of the code.
+pub fn decode(code: &[u32], pc: u32) -> Result<Instruction, Fault> {
+    let word = *code.get(pc as usize).ok_or(Fault::PcOutOfRange(pc))?;
+    let value = (word >> 16) as u16;
+    let opcode = Opcode::from_value(value).ok_or(Fault::InvalidOpcode(value))?;
+    let reg1 = decode_register((word >> 4) & 0xF)?;
+    let reg2 = decode_register(word & 0xF)?;
+    if reg1 == Register::Null && opcode.operands().uses_reg1() {
+        return Err(Fault::BadRegister(0));
+    }
+    let imm = if word & IMMEDIATE_FLAG != 0 {
+        Some(*code.get(pc as usize + 1).ok_or(Fault::TruncatedImmediate(pc))?)
+    } else {
+        None
+    };
+
+    Ok(Instruction::new(opcode, reg1, reg2, imm))
+}
+
+/// What `decode_program` found at one code address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodedInst {
+    Valid(Instruction),
+    /// Running this address raises the fault.
+    Invalid(Fault),
+}
+
+/// Decodes the instruction at every code address, including the immediate words of two
+/// word instructions, so a jump into the middle of one runs the same as with `decode`.
+pub fn decode_program(code: &[u32]) -> Vec<DecodedInst> {
+    (0..code.len() as u32).map(|pc| match decode(code, pc) {
+        Ok(inst) => DecodedInst::Valid(inst),
+        Err(fault) => DecodedInst::Invalid(fault),
+    }).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+impl Section {
+    fn hex_value(&self) -> u32 {
+        match *self {
+            Section::Code => 0x0,
+            Section::Data => 0x1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub addr: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageError {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    Truncated,
+    BadSymbol,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::BadMagic(magic) => write!(f, "not a ZPU image (magic {:#010x})", magic),
+            ImageError::UnsupportedVersion(version) => write!(f, "unsupported image version {}", version),
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::BadSymbol => write!(f, "symbol table is corrupt"),
+        }
+    }
+}
+
+/// An assembled program: the code the ZPU runs, the words copied into data memory before
+/// it starts, and the labels that pointed into either.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub entry: u32,
+    pub code: Vec<u32>,
+    pub data: Vec<u32>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program::default()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Serialises the program into the binary image format described in zpu/README.md.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(IMAGE_MAGIC).unwrap();
+        bytes.write_u32::<LittleEndian>(IMAGE_VERSION).unwrap();
+        bytes.write_u32::<LittleEndian>(self.entry).unwrap();
+        bytes.write_u32::<LittleEndian>(self.code.len() as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.data.len() as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.symbols.len() as u32).unwrap();
+        for word in self.code.iter().chain(self.data.iter()) {
+            bytes.write_u32::<LittleEndian>(*word).unwrap();
+        }
+        for sym in self.symbols.iter() {
+            bytes.write_u32::<LittleEndian>(sym.addr).unwrap();
+            bytes.write_u32::<LittleEndian>(sym.section.hex_value()).unwrap();
+            bytes.write_u32::<LittleEndian>(sym.name.len() as u32).unwrap();
+            bytes.extend_from_slice(sym.name.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, ImageError> {
+        let mut reader = Cursor::new(bytes);
+        let magic = read_word(&mut reader)?;
+        if magic != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+        let version = read_word(&mut reader)?;
+        if version != IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let entry = read_word(&mut reader)?;
+        let code_len = read_word(&mut reader)?;
+        let data_len = read_word(&mut reader)?;
+        let symbol_count = read_word(&mut reader)?;
+
+        let mut code = Vec::new();
+        for _ in 0..code_len {
+            code.push(read_word(&mut reader)?);
+        }
+        let mut data = Vec::new();
+        for _ in 0..data_len {
+            data.push(read_word(&mut reader)?);
+        }
+
+        let mut symbols = Vec::new();
+        for _ in 0..symbol_count {
+            let addr = read_word(&mut reader)?;
+            let section = match read_word(&mut reader)? {
+                0x0 => Section::Code,
+                0x1 => Section::Data,
+                _ => return Err(ImageError::BadSymbol),
+            };
+            let name_len = read_word(&mut reader)? as usize;
+            let start = reader.position() as usize;
+            if start + name_len > bytes.len() {
+                return Err(ImageError::Truncated);
+            }
+            let name = match String::from_utf8(bytes[start..start + name_len].to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(ImageError::BadSymbol),
+            };
+            reader.set_position((start + name_len) as u64);
+            symbols.push(Symbol { name, section, addr });
+        }
+
+        Ok(Program {
+            entry,
+            code,
+            data,
+            symbols,
+        })
+    }
+}
+
+fn read_word(reader: &mut Cursor<&[u8]>) -> Result<u32, ImageError> {
+    reader.read_u32::<LittleEndian>().map_err(|_| ImageError::Truncated)
+}
@@ -0,0 +1,436 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use debugger::Stop;
+use zpu::{Fault, Output, ZPU};
+
+/// Data memory appears to gdb at this byte address, and code at 0. Both are read as
+/// little-endian 32-bit words, so word `n` of either starts at byte `4 * n`.
+pub const DATA_BASE: u32 = 0x0100_0000;
+
+/// Registers in the order `g` and `p` use: A-E, X-Z, then PC, SP and the flags word.
+pub const REGISTER_COUNT: usize = 11;
+
+const PC_REGISTER: usize = 8;
+const SP_REGISTER: usize = 9;
+const FLAGS_REGISTER: usize = 10;
+
+/// Instructions run between checks for a ^C from gdb while continuing.
+const CONTINUE_CHUNK: usize = 10_000;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.zala.zpu.core">
+    <flags id="zpu_flags" size="4">
+      <field name="ZERO" start="0" end="0"/>
+      <field name="GT" start="1" end="1"/>
+      <field name="LT" start="2" end="2"/>
+      <field name="IE" start="3" end="3"/>
+      <field name="CARRY" start="4" end="4"/>
+      <field name="OVERFLOW" start="5" end="5"/>
+      <field name="SGT" start="6" end="6"/>
+      <field name="SLT" start="7" end="7"/>
+    </flags>
+    <reg name="a" bitsize="32" type="uint32" regnum="0"/>
+    <reg name="b" bitsize="32" type="uint32"/>
+    <reg name="c" bitsize="32" type="uint32"/>
+    <reg name="d" bitsize="32" type="uint32"/>
+    <reg name="e" bitsize="32" type="uint32"/>
+    <reg name="x" bitsize="32" type="uint32"/>
+    <reg name="y" bitsize="32" type="uint32"/>
+    <reg name="z" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="sp" bitsize="32" type="uint32"/>
+    <reg name="flags" bitsize="32" type="zpu_flags"/>
+  </feature>
+</target>
+"#;
+
+/// The signal gdb is told stopped the target when the ZPU faults.
+fn fault_signal(fault: Fault) -> u8 {
+    match fault {
+        Fault::MemoryOutOfBounds(_) | Fault::StackOverflow | Fault::StackUnderflow => 11,
+        Fault::PcOutOfRange(_) => 11,
+        Fault::DivideByZero => 8,
+        Fault::InvalidOpcode(_) | Fault::BadRegister(_) | Fault::TruncatedImmediate(_) => 4,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// A register as gdb expects it: the target's byte order, in hex.
+fn hex_word(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_word(text: &str) -> Option<u32> {
+    if text.len() != 8 {
+        return None;
+    }
+    parse_hex(text).map(|value| value.swap_bytes())
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok()).collect()
+}
+
+/// `addr,len`, as used by `m`, `M` and `Z`.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((addr, len))
+}
+
+/// Serves one gdb connection until it detaches, kills the target or hangs up.
+///
+/// The ZPU only runs while gdb has it continuing or stepping. Anything it writes to an
+/// unmapped port is passed to `output`.
+pub fn serve(zpu: &mut ZPU, stream: TcpStream, output: &mut dyn FnMut(Output)) -> io::Result<()> {
+    // Every packet waits on a reply, so don't let Nagle hold small writes back.
+    stream.set_nodelay(true)?;
+    let mut stub = GdbStub {
+        zpu,
+        stream,
+        output,
+        no_ack: false,
+        last_stop: String::from("S05"),
+    };
+    stub.run()
+}
+
+struct GdbStub<'a> {
+    zpu: &'a mut ZPU,
+    stream: TcpStream,
+    output: &'a mut dyn FnMut(Output),
+    no_ack: bool,
+    /// Reply to `?`.
+    last_stop: String,
+}
+
+impl<'a> GdbStub<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Some(reply) => {
+                    self.write_packet(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                    if packet.starts_with('D') {
+                        return Ok(());
+                    }
+                },
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next `$data#cs` packet, acknowledging it unless no-ack mode is on.
+    /// A ^C while the target is already stopped is treated as `?`.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(0x03) => return Ok(Some(String::from("?"))),
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum)?;
+
+            if !self.no_ack {
+                let expected = String::from_utf8_lossy(&sum).into_owned();
+                if parse_hex(&expected) != Some(checksum(&data) as u32) {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// The reply to a packet, or `None` if the connection should close without one.
+    /// Packets we don't support get the empty reply, as the protocol asks.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => (0..REGISTER_COUNT).map(|reg| self.register_hex(reg)).collect(),
+            "G" => self.write_registers(args),
+            "p" => match parse_hex(args) {
+                Some(reg) if (reg as usize) < REGISTER_COUNT => self.register_hex(reg as usize),
+                _ => String::from("E00"),
+            },
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "c" => self.resume(args),
+            "s" => self.step(args),
+            "H" => String::from("OK"),
+            "D" => String::from("OK"),
+            "k" => return None,
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+")
+        } else if packet == "QStartNoAckMode" {
+            String::from("OK")
+        } else if packet == "qAttached" {
+            String::from("1")
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, String::from_utf8_lossy(&xml[start..end]))
+                },
+                None => String::from("E00"),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    /// `None` for a PC too far out to give gdb as a byte address, which a jump out of
+    /// the program can leave behind.
+    fn read_register(&self, reg: usize) -> Option<u32> {
+        match reg {
+            PC_REGISTER => self.zpu.pc.checked_mul(4),
+            SP_REGISTER => Some(self.zpu.sp),
+            FLAGS_REGISTER => Some(self.zpu.flags_word()),
+            _ => Some(self.zpu.registers[reg]),
+        }
+    }
+
+    /// SP can be anywhere from an empty stack to a full one.
+    fn register_fits(&self, reg: usize, value: u32) -> bool {
+        reg != SP_REGISTER || value as usize <= self.zpu.stack.len()
+    }
+
+    /// A register as gdb expects it, or `xxxxxxxx`, which gdb shows as unavailable.
+    fn register_hex(&self, reg: usize) -> String {
+        self.read_register(reg).map_or_else(|| String::from("xxxxxxxx"), hex_word)
+    }
+
+    fn set_register(&mut self, reg: usize, value: u32) {
+        match reg {
+            PC_REGISTER => self.zpu.pc = value / 4,
+            SP_REGISTER => self.zpu.sp = value,
+            FLAGS_REGISTER => self.zpu.set_flags_word(value),
+            _ => self.zpu.registers[reg] = value,
+        }
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        if args.len() != REGISTER_COUNT * 8 {
+            return String::from("E00");
+        }
+        let mut values = Vec::new();
+        for reg in 0..REGISTER_COUNT {
+            match parse_hex_word(&args[reg * 8..reg * 8 + 8]) {
+                Some(value) => values.push(value),
+                None => return String::from("E00"),
+            }
+        }
+        if !values.iter().enumerate().all(|(reg, value)| self.register_fits(reg, *value)) {
+            return String::from("E01");
+        }
+        for (reg, value) in values.into_iter().enumerate() {
+            self.set_register(reg, value);
+        }
+        String::from("OK")
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let reg = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(parse_hex_word);
+        match (reg, value) {
+            (Some(reg), Some(value)) if (reg as usize) < REGISTER_COUNT => {
+                if !self.register_fits(reg as usize, value) {
+                    return String::from("E01");
+                }
+                self.set_register(reg as usize, value);
+                String::from("OK")
+            },
+            _ => String::from("E00"),
+        }
+    }
+
+    fn memory_byte(&self, addr: u32) -> Option<u8> {
+        let word = if addr >= DATA_BASE {
+            *self.zpu.memory.get(((addr - DATA_BASE) / 4) as usize)?
+        } else {
+            *self.zpu.program.code.get((addr / 4) as usize)?
+        };
+        Some((word >> ((addr % 4) * 8)) as u8)
+    }
+
+    /// Returns as many bytes as could be read, or an error if the first one can't be.
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_range(args) {
+            Some(range) => range,
+            None => return String::from("E00"),
+        };
+        let mut reply = String::new();
+        for offset in 0..len {
+            match self.memory_byte(addr.wrapping_add(offset)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                None => break,
+            }
+        }
+        if reply.is_empty() && len > 0 {
+            String::from("E01")
+        } else {
+            reply
+        }
+    }
+
+    /// Only data memory can be written; the code is read-only.
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_range);
+        let bytes = parts.next().and_then(parse_hex_bytes);
+        let (addr, bytes) = match (range, bytes) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => (addr, bytes),
+            _ => return String::from("E00"),
+        };
+
+        let end = addr as u64 + bytes.len() as u64;
+        if addr < DATA_BASE || end > DATA_BASE as u64 + self.zpu.memory.len() as u64 * 4 {
+            return String::from("E01");
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let addr = addr - DATA_BASE + offset as u32;
+            let shift = (addr % 4) * 8;
+            let word = &mut self.zpu.memory[(addr / 4) as usize];
+            *word = (*word & !(0xFF << shift)) | ((byte as u32) << shift);
+        }
+        String::from("OK")
+    }
+
+    /// `Z0,addr,kind` and `z0,addr,kind`. Only software breakpoints are supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let addr = match args.strip_prefix("0,").and_then(parse_range) {
+            Some((addr, _)) => addr / 4,
+            None => return String::new(),
+        };
+        if insert {
+            self.zpu.debugger.add_breakpoint(addr);
+        } else {
+            self.zpu.debugger.remove_breakpoint(addr);
+        }
+        String::from("OK")
+    }
+
+    /// `c` and `s` can take an address to resume from.
+    fn jump_to(&mut self, args: &str) {
+        if let Some(addr) = parse_hex(args) {
+            self.zpu.pc = addr / 4;
+        }
+    }
+
+    fn stop_reply(&mut self, stop: Stop) -> String {
+        let reply = match stop {
+            Stop::Halted => String::from("W00"),
+            Stop::Fault(fault) => format!("S{:02x}", fault_signal(fault)),
+            Stop::Limit => String::from("S02"),
+            Stop::Stepped(_) | Stop::Breakpoint(_) | Stop::Watchpoint(..) | Stop::Output(_) => String::from("S05"),
+        };
+        self.last_stop = reply.clone();
+        reply
+    }
+
+    fn step(&mut self, args: &str) -> String {
+        self.jump_to(args);
+        let stop = self.zpu.debug_step();
+        if let Stop::Stepped(Some(output)) = stop {
+            (self.output)(output);
+        }
+        self.stop_reply(stop)
+    }
+
+    /// Runs until something stops the ZPU, checking for a ^C from gdb every so often.
+    fn resume(&mut self, args: &str) -> String {
+        self.jump_to(args);
+        loop {
+            match self.zpu.resume(CONTINUE_CHUNK) {
+                Stop::Output(output) => (self.output)(output),
+                Stop::Limit => {
+                    if self.interrupted() {
+                        return self.stop_reply(Stop::Limit);
+                    }
+                },
+                stop => return self.stop_reply(stop),
+            }
+        }
+    }
+
+    /// Whether gdb sent a ^C, or hung up, since we last looked.
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let result = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        match result {
+            Ok(0) => true,
+            Ok(_) => byte[0] == 0x03,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => false,
+            Err(_) => true,
+        }
+    }
+}
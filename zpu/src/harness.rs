@@ -0,0 +1,308 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use assembler::{self, AssembleError};
+use bus::Script;
+use encoding::Program;
+use interrupt::IRQ_LINES;
+use zpu::{Output, Register, RunOutcome, ZPU};
+
+/// Cycles a test runs for unless it has a `@cycles` line.
+pub const DEFAULT_TEST_CYCLES: u64 = 100_000;
+
+/// How a test expects the program to end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    Halt,
+    /// Still running when the cycles ran out, like firmware sitting in its main loop.
+    Running,
+    /// Faulted with this fault code.
+    Fault(u32),
+}
+
+/// One `@test` block: what to feed the program and what it should have done by the end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    /// Source line of the `@test` annotation.
+    pub line: usize,
+    pub cycles: u64,
+    /// Values read, in order, from each port.
+    pub inputs: Vec<(u32, Vec<u32>)>,
+    /// IRQ lines raised once the ZPU has run for the given number of cycles.
+    pub irqs: Vec<(u64, u32)>,
+    /// Every OUT to an unmapped port, in order. Not checked if the test has no `@out` lines.
+    pub outputs: Option<Vec<Output>>,
+    pub registers: Vec<(Register, u32)>,
+    /// Data memory words, each addressed by a number or a data label.
+    pub memory: Vec<(String, u32)>,
+    pub end: End,
+}
+
+impl TestCase {
+    pub fn new(name: &str, line: usize) -> TestCase {
+        TestCase {
+            name: name.to_string(),
+            line,
+            cycles: DEFAULT_TEST_CYCLES,
+            inputs: Vec::new(),
+            irqs: Vec::new(),
+            outputs: None,
+            registers: Vec::new(),
+            memory: Vec::new(),
+            end: End::Halt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub line: usize,
+    /// Each expectation that didn't hold, empty if the test passed.
+    pub failures: Vec<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum HarnessError {
+    Io(io::Error),
+    Assemble(Vec<AssembleError>),
+    /// A `@` annotation that couldn't be read, with its line.
+    Annotation(usize, String),
+}
+
+impl fmt::Display for HarnessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HarnessError::Io(ref err) => write!(f, "{}", err),
+            HarnessError::Assemble(ref errors) => {
+                let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            },
+            HarnessError::Annotation(line, ref message) => write!(f, "{}: {}", line, message),
+        }
+    }
+}
+
+/// The annotation on a line, if its comment starts with `@`.
+fn annotation(line: &str) -> Option<&str> {
+    line[line.find(';')? + 1..].trim().strip_prefix('@')
+}
+
+fn number(text: &str, line: usize) -> Result<u32, HarnessError> {
+    assembler::parse_number(text).ok_or_else(|| HarnessError::Annotation(line, format!("bad number '{}'", text)))
+}
+
+/// Splits `LEFT = RIGHT`, both sides trimmed.
+fn assignment(args: &str, line: usize) -> Result<(&str, &str), HarnessError> {
+    let mut parts = args.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(left), Some(right)) => Ok((left.trim(), right.trim())),
+        _ => Err(HarnessError::Annotation(line, format!("expected 'name = value', found '{}'", args))),
+    }
+}
+
+/// Reads the `; @test` annotations in a source file. Each `@test NAME` starts a test, and the
+/// annotations after it, up to the next `@test`, belong to it:
+///
+/// * `@cycles N` runs for N cycles instead of `DEFAULT_TEST_CYCLES`.
+/// * `@input PORT = V, V, ...` is read by IN on that port, then 0 once used up.
+/// * `@irq LINE at CYCLE` raises an IRQ line after that many cycles.
+/// * `@out PORT DATA` is the next OUT the program should make.
+/// * `@reg R = V` and `@mem ADDR = V` check a register or data memory word at the end.
+/// * `@halt` (the default), `@running` or `@fault CODE` say how the program should end.
+pub fn parse_tests(source: &str) -> Result<Vec<TestCase>, HarnessError> {
+    let mut tests: Vec<TestCase> = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let annotation = match annotation(text) {
+            Some(annotation) => annotation,
+            None => continue,
+        };
+        let (name, args) = match annotation.find(char::is_whitespace) {
+            Some(split) => (&annotation[..split], annotation[split..].trim()),
+            None => (annotation, ""),
+        };
+        if name == "test" {
+            let name = if args.is_empty() { format!("line {}", line) } else { args.to_string() };
+            tests.push(TestCase::new(&name, line));
+            continue;
+        }
+        let test = tests.last_mut()
+            .ok_or_else(|| HarnessError::Annotation(line, format!("@{} before any @test", name)))?;
+        match name {
+            "cycles" => test.cycles = u64::from(number(args, line)?),
+            "input" => {
+                let (port, values) = assignment(args, line)?;
+                let values = values.split(',')
+                    .map(|value| number(value.trim(), line))
+                    .collect::<Result<Vec<u32>, HarnessError>>()?;
+                test.inputs.push((number(port, line)?, values));
+            },
+            "irq" => {
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                if parts.len() != 3 || parts[1] != "at" {
+                    return Err(HarnessError::Annotation(line, "expected '@irq LINE at CYCLE'".to_string()));
+                }
+                let irq = number(parts[0], line)?;
+                if irq >= IRQ_LINES {
+                    return Err(HarnessError::Annotation(line, format!("no IRQ line {}", irq)));
+                }
+                test.irqs.push((u64::from(number(parts[2], line)?), irq));
+            },
+            "out" => {
+                let parts: Vec<&str> = args.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                if parts.len() != 2 {
+                    return Err(HarnessError::Annotation(line, "expected '@out PORT DATA'".to_string()));
+                }
+                let output = Output::new(number(parts[0], line)?, number(parts[1], line)?);
+                test.outputs.get_or_insert_with(Vec::new).push(output);
+            },
+            "reg" => {
+                let (reg, value) = assignment(args, line)?;
+                let reg = Register::from_name(&reg.to_lowercase())
+                    .filter(|reg| *reg != Register::Null)
+                    .ok_or_else(|| HarnessError::Annotation(line, format!("no register '{}'", reg)))?;
+                test.registers.push((reg, number(value, line)?));
+            },
+            "mem" => {
+                let (addr, value) = assignment(args, line)?;
+                let addr = addr.trim_start_matches('[').trim_end_matches(']').trim();
+                test.memory.push((addr.to_string(), number(value, line)?));
+            },
+            "halt" => test.end = End::Halt,
+            "running" => test.end = End::Running,
+            "fault" => test.end = End::Fault(number(args, line)?),
+            _ => return Err(HarnessError::Annotation(line, format!("unknown annotation @{}", name))),
+        }
+    }
+    Ok(tests)
+}
+
+fn describe(outcome: RunOutcome) -> String {
+    match outcome {
+        RunOutcome::Halted => "halted".to_string(),
+        RunOutcome::Fault(fault) => format!("faulted with {} ({})", fault.code(), fault),
+        _ => "was still running".to_string(),
+    }
+}
+
+fn format_outputs(outputs: &[Output]) -> String {
+    let outputs: Vec<String> = outputs.iter().map(|out| format!("{}:{}", out.port, out.data)).collect();
+    format!("[{}]", outputs.join(", "))
+}
+
+/// Runs one test against a fresh ZPU loaded with `program`.
+pub fn run_test(program: &Program, test: &TestCase) -> TestResult {
+    let mut zpu = ZPU::from_program(program);
+    for &(port, ref values) in &test.inputs {
+        zpu.bus.attach(&[port], Box::new(Script::new(values)));
+    }
+    let mut irqs = test.irqs.clone();
+    irqs.sort_by_key(|&(cycle, _)| cycle);
+    let mut irqs = irqs.into_iter().peekable();
+
+    let mut outputs = Vec::new();
+    let outcome = loop {
+        while let Some(&(cycle, line)) = irqs.peek() {
+            if cycle > zpu.cycles {
+                break;
+            }
+            zpu.raise_irq(line);
+            irqs.next();
+        }
+        if zpu.cycles >= test.cycles {
+            break RunOutcome::Budget;
+        }
+        let until = irqs.peek().map_or(test.cycles, |&(cycle, _)| cycle.min(test.cycles));
+        match zpu.run((until - zpu.cycles) as usize) {
+            RunOutcome::Output(output) => outputs.push(output),
+            RunOutcome::Budget => {},
+            outcome => break outcome,
+        }
+    };
+
+    let mut failures = Vec::new();
+    let ended = match (test.end, outcome) {
+        (End::Halt, RunOutcome::Halted) | (End::Running, RunOutcome::Budget) => true,
+        (End::Fault(code), RunOutcome::Fault(fault)) => fault.code() == code,
+        _ => false,
+    };
+    if !ended {
+        let expected = match test.end {
+            End::Halt => "halt".to_string(),
+            End::Running => "still be running".to_string(),
+            End::Fault(code) => format!("fault with {}", code),
+        };
+        failures.push(format!("expected to {} after {} cycles, but it {} at PC {}",
+                              expected, zpu.cycles, describe(outcome), zpu.pc));
+    }
+    if let Some(ref expected) = test.outputs {
+        if *expected != outputs {
+            failures.push(format!("expected output {}, got {}", format_outputs(expected), format_outputs(&outputs)));
+        }
+    }
+    for &(reg, value) in &test.registers {
+        let actual = zpu.registers[reg.hex_value() as usize - 1];
+        if actual != value {
+            failures.push(format!("expected {} = {}, got {}", reg.name(), value, actual));
+        }
+    }
+    for &(ref addr_text, value) in &test.memory {
+        let addr = assembler::parse_number(addr_text)
+            .or_else(|| program.symbol(addr_text).map(|sym| sym.addr));
+        match addr.map(|addr| zpu.read_memory(addr)) {
+            Some(Ok(actual)) if actual == value => {},
+            Some(Ok(actual)) => failures.push(format!("expected [{}] = {}, got {}", addr_text, value, actual)),
+            Some(Err(fault)) => failures.push(format!("can't read [{}]: {}", addr_text, fault)),
+            None => failures.push(format!("no label '{}'", addr_text)),
+        }
+    }
+
+    TestResult {
+        name: test.name.clone(),
+        line: test.line,
+        failures,
+    }
+}
+
+/// Assembles `source` and runs every test annotated in it.
+pub fn run_source_with(source: &str, include: &mut dyn FnMut(&str) -> Option<String>) -> Result<Vec<TestResult>, HarnessError> {
+    let tests = parse_tests(source)?;
+    let program = assembler::assemble_with(source, include).map_err(HarnessError::Assemble)?;
+    Ok(tests.iter().map(|test| run_test(&program, test)).collect())
+}
+
+pub fn run_source(source: &str) -> Result<Vec<TestResult>, HarnessError> {
+    run_source_with(source, &mut |_| None)
+}
+
+/// Reads, assembles and tests a file, with `.include` relative to it.
+pub fn run_file(path: &Path) -> Result<Vec<TestResult>, HarnessError> {
+    let mut source = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut source)).map_err(HarnessError::Io)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    run_source_with(&source, &mut |file| assembler::read_include(dir, file))
+}
+
+/// Runs the tests in a file and panics with every failure, for calling from a `#[test]`.
+pub fn check_file<P: AsRef<Path>>(path: P) {
+    let path = path.as_ref();
+    let results = run_file(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    assert!(!results.is_empty(), "{}: no @test annotations", path.display());
+    let failures: Vec<String> = results.iter()
+        .filter(|result| !result.passed())
+        .map(|result| format!("{}:{}: {}\n    {}", path.display(), result.line, result.name, result.failures.join("\n    ")))
+        .collect();
+    assert!(failures.is_empty(), "{} of {} tests failed\n{}", failures.len(), results.len(), failures.join("\n"));
+}
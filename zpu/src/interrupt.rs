@@ -0,0 +1,74 @@
+use zpu::DEFAULT_MEMORY_SIZE;
+
+/// Number of IRQ lines the host can raise.
+pub const IRQ_LINES: u32 = 16;
+/// The vector table holds a vector for each IRQ line, then the fault vector.
+pub const VECTOR_TABLE_WORDS: u32 = IRQ_LINES + 1;
+/// The table sits in the last words of data memory, clear of the data section, which is
+/// loaded from address 0.
+pub const DEFAULT_VECTOR_BASE: u32 = DEFAULT_MEMORY_SIZE as u32 - VECTOR_TABLE_WORDS;
+
+/// Tracks which IRQ lines are pending and whether the ZPU is accepting them.
+///
+/// Handlers are found through the vector table in data memory: the word at
+/// `vector_base + line` holds the handler address for that line. A vector of 0 means
+/// no handler is installed, and interrupts raised on that line are dropped.
+#[derive(Debug, Clone)]
+pub struct InterruptController {
+    pub pending: u32,
+    pub enabled: bool,
+    pub vector_base: u32,
+}
+
+impl Default for InterruptController {
+    fn default() -> InterruptController {
+        InterruptController::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            pending: 0,
+            enabled: false,
+            vector_base: DEFAULT_VECTOR_BASE,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pending = 0;
+        self.enabled = false;
+    }
+
+    /// Marks a line as pending. Returns `false` if the line does not exist.
+    pub fn raise(&mut self, line: u32) -> bool {
+        if line >= IRQ_LINES {
+            return false;
+        }
+        self.pending |= 1 << line;
+        true
+    }
+
+    pub fn is_pending(&self, line: u32) -> bool {
+        line < IRQ_LINES && self.pending & (1 << line) != 0
+    }
+
+    /// Takes the lowest numbered pending line, if interrupts are enabled.
+    pub fn acknowledge(&mut self) -> Option<u32> {
+        if !self.enabled || self.pending == 0 {
+            return None;
+        }
+        let line = self.pending.trailing_zeros();
+        self.pending &= !(1 << line);
+        Some(line)
+    }
+
+    pub fn vector_addr(&self, line: u32) -> u32 {
+        self.vector_base.wrapping_add(line)
+    }
+
+    /// The fault vector follows the IRQ vectors. See `ZPU::trap`.
+    pub fn fault_vector_addr(&self) -> u32 {
+        self.vector_base.wrapping_add(IRQ_LINES)
+    }
+}
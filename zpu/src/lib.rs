@@ -1,4 +1,18 @@
 extern crate byteorder;
+#[macro_use]
+extern crate serde_json;
 
 pub mod zpu;
 pub mod assembler;
+pub mod bus;
+pub mod encoding;
+pub mod interrupt;
+pub mod disassembler;
+pub mod debugger;
+pub mod gdbstub;
+pub mod trace;
+pub mod snapshot;
+pub mod clock;
+pub mod harness;
+pub mod lsp;
+pub mod analysis;
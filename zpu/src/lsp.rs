@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use assembler::{self, AssembleError, Directive, Item, Operand, Origin, Span};
+use zpu::{Opcode, Register, REGISTERS};
+
+// LSP completion item kinds.
+const KIND_VARIABLE: u32 = 6;
+const KIND_CONSTANT: u32 = 21;
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Hover text for a mnemonic.
+fn mnemonic_doc(name: &str) -> Option<String> {
+    let opcode = Opcode::from_mnemonic(name)?;
+    let cycles = opcode.cycles();
+    Some(format!("**{}** `{}`\n\n{}\n\nOpcode {:#X}, {} cycle{}",
+                 opcode.mnemonic().to_uppercase(), opcode.syntax(), opcode.description(),
+                 opcode.hex_value(), cycles, if cycles == 1 { "" } else { "s" }))
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            decoded.push(u8::from_str_radix(path.get(idx + 1..idx + 3)?, 16).ok()?);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Where `chars` characters into a line ends, in the UTF-16 code units LSP counts in.
+fn utf16_column(line: &str, chars: usize) -> usize {
+    line.chars().take(chars).map(char::len_utf16).sum()
+}
+
+/// How many characters into a line a position `units` UTF-16 code units in is.
+fn char_column(line: &str, units: usize) -> usize {
+    let mut total = 0;
+    line.chars().take_while(|c| {
+        total += c.len_utf16();
+        total <= units
+    }).count()
+}
+
+/// An LSP range for a span in `text`.
+fn range(text: &str, span: Span) -> Value {
+    let line = text.lines().nth(span.line - 1).unwrap_or("");
+    let start = span.column - 1;
+    json!({
+        "start": { "line": span.line - 1, "character": utf16_column(line, start) },
+        "end": { "line": span.line - 1, "character": utf16_column(line, start + span.len) },
+    })
+}
+
+fn contains(span: Span, line: usize, character: usize) -> bool {
+    span.line == line + 1 && character + 1 >= span.column && character < span.column - 1 + span.len
+}
+
+/// The error's message without the `line:column: ` that `Display` puts first.
+fn message(err: &AssembleError) -> String {
+    let text = err.to_string();
+    let prefix = format!("{}:{}: ", err.line(), err.column());
+    text.strip_prefix(prefix.as_str()).unwrap_or(&text).to_string()
+}
+
+/// The word at a position, as the lowercased token the assembler would see.
+fn word_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_' || *c == '.';
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(&chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = start;
+    while end < chars.len() && is_word(&chars[end]) {
+        end += 1;
+    }
+    if start == end || character > end {
+        return None;
+    }
+    Some(chars[start..end].iter().collect::<String>().to_lowercase())
+}
+
+/// A label or `.equ` constant.
+struct Definition {
+    name: String,
+    /// The include file it's in, or `None` for the document itself.
+    file: Option<String>,
+    span: Span,
+    /// As written, before the assembler lowercased it.
+    written: String,
+    /// The value of a constant, when it's a number.
+    value: Option<u32>,
+    constant: bool,
+}
+
+/// What the parser found in a document: where each name is defined, and where the
+/// document itself uses them. Lines expanded from macros are left out.
+struct Analysis {
+    /// The text of each include file, by the name it was included as.
+    files: HashMap<String, String>,
+    definitions: Vec<Definition>,
+    references: Vec<(String, Span)>,
+    errors: Vec<AssembleError>,
+}
+
+fn text_at(text: &str, span: Span) -> String {
+    text.lines().nth(span.line - 1)
+        .map(|line| line.chars().skip(span.column - 1).take(span.len).collect())
+        .unwrap_or_default()
+}
+
+fn reference(operand: &Operand, span: Span, references: &mut Vec<(String, Span)>) {
+    match *operand {
+        Operand::Label(ref name) => references.push((name.clone(), span)),
+        Operand::Memory(ref inner) => {
+            reference(inner, Span::new(span.line, span.column + 1, span.len.saturating_sub(2)), references)
+        },
+        Operand::Register(_) | Operand::Immediate(_) => (),
+    }
+}
+
+impl Analysis {
+    fn new(text: &str, dir: Option<&Path>) -> Analysis {
+        let mut files = HashMap::new();
+        let (lines, _) = assembler::parse_source_with(text, &mut |file| {
+            let source = assembler::read_include(dir?, file)?;
+            files.insert(file.to_string(), source.clone());
+            Some(source)
+        });
+        // Assembling again reports the parse errors too, along with those only found once
+        // labels are resolved.
+        let errors = assembler::assemble_with(text, &mut |file| files.get(file).cloned()).err().unwrap_or_default();
+
+        let mut definitions = Vec::new();
+        let mut references = Vec::new();
+        for line in &lines {
+            let file = match line.origin.last() {
+                None => None,
+                Some(Origin::Include(_, file)) if line.origin.iter().all(|origin| matches!(*origin, Origin::Include(..))) => {
+                    Some(file.clone())
+                },
+                Some(_) => continue,
+            };
+            let source = file.as_ref().and_then(|file| files.get(file)).map_or(text, |source| source.as_str());
+
+            for &(ref name, span) in &line.labels {
+                let label = Span::new(span.line, span.column, name.chars().count());
+                definitions.push(Definition {
+                    name: name.clone(),
+                    file: file.clone(),
+                    span: label,
+                    written: text_at(source, label),
+                    value: None,
+                    constant: false,
+                });
+            }
+
+            let mut args = Vec::new();
+            match line.item {
+                Some(Item::Inst(ref inst)) => args.extend(inst.args.iter()),
+                Some(Item::Directive(Directive::Equ(ref name, ref value), span)) => {
+                    // The name is the first thing after the directive.
+                    let after = text_at(source, span);
+                    let offset = after.chars().take_while(|c| !c.is_whitespace()).count();
+                    let skipped = after.chars().skip(offset).take_while(|c| c.is_whitespace()).count();
+                    let name_span = Span::new(span.line, span.column + offset + skipped, name.chars().count());
+                    definitions.push(Definition {
+                        name: name.clone(),
+                        file: file.clone(),
+                        span: name_span,
+                        written: text_at(source, name_span),
+                        value: match value.operand {
+                            Operand::Immediate(value) => Some(value),
+                            _ => None,
+                        },
+                        constant: true,
+                    });
+                    args.push(value);
+                },
+                Some(Item::Directive(Directive::Word(ref values), _)) => args.extend(values.iter()),
+                Some(Item::Directive(Directive::Org(ref value), _)) => args.push(value),
+                _ => (),
+            }
+            if file.is_none() {
+                for arg in args {
+                    reference(&arg.operand, arg.span, &mut references);
+                }
+            }
+        }
+
+        Analysis { files, definitions, references, errors }
+    }
+
+    /// The text a definition is in: `text` for the document, or else its include file.
+    fn source<'a>(&'a self, text: &'a str, def: &Definition) -> &'a str {
+        def.file.as_ref().and_then(|file| self.files.get(file)).map_or(text, |source| source.as_str())
+    }
+
+    fn definition(&self, name: &str) -> Option<&Definition> {
+        self.definitions.iter().find(|def| def.name == name)
+    }
+
+    /// The name defined or used at a position in the document.
+    fn name_at(&self, line: usize, character: usize) -> Option<&str> {
+        self.definitions.iter()
+            .filter(|def| def.file.is_none())
+            .map(|def| (def.name.as_str(), def.span))
+            .chain(self.references.iter().map(|&(ref name, span)| (name.as_str(), span)))
+            .find(|&(_, span)| contains(span, line, character))
+            .map(|(name, _)| name)
+    }
+}
+
+/// A language server for ZPU assembly, driven one JSON-RPC message at a time.
+///
+/// Documents are synced whole. Each change re-parses the document, with `.include`s read
+/// relative to it, and publishes the assembler's errors as diagnostics.
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exited: bool,
+}
+
+fn response(id: &Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Whether the client has sent `exit`.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Whether `shutdown` came before `exit`, as the protocol asks.
+    pub fn shut_down_cleanly(&self) -> bool {
+        self.shutdown
+    }
+
+    /// Handles one request or notification, returning the messages to send back: the
+    /// response to a request, and any diagnostics it caused.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id,
+            None => return self.notify(method, params),
+        };
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "zpu-lsp" },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            },
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/completion" => self.completion(params),
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, &format!("unknown method {}", method))],
+        };
+        vec![response(id, result)]
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "exit" => self.exited = true,
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri.clone(), text);
+                return vec![self.diagnostics(&uri)];
+            },
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                return vec![self.diagnostics(&uri)];
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })];
+            },
+            _ => (),
+        }
+        Vec::new()
+    }
+
+    fn analyse(&self, uri: &str) -> Option<(&str, Analysis)> {
+        let text = self.documents.get(uri)?;
+        let path = uri_to_path(uri);
+        let dir = path.as_ref().and_then(|path| path.parent());
+        Some((text, Analysis::new(text, dir)))
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let (text, errors) = self.analyse(uri).map_or(("", Vec::new()), |(text, analysis)| (text, analysis.errors));
+        let diagnostics: Vec<Value> = errors.iter()
+            .map(|err| json!({
+                "range": range(text, err.span()),
+                "severity": 1,
+                "source": "zpu",
+                "message": message(err),
+            }))
+            .collect();
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    /// The document and position a request is about, with the position's character
+    /// counted in characters rather than UTF-16 code units.
+    fn position<'a>(&self, params: &'a Value) -> (&'a str, usize, usize) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let units = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        let text = self.documents.get(uri).and_then(|text| text.lines().nth(line)).unwrap_or("");
+        (uri, line, char_column(text, units))
+    }
+
+    fn location(uri: &str, text: &str, analysis: &Analysis, def: &Definition) -> Value {
+        let uri = match def.file {
+            None => uri.to_string(),
+            Some(ref file) => {
+                let dir = uri_to_path(uri).and_then(|path| path.parent().map(Path::to_path_buf)).unwrap_or_default();
+                let path = dir.join(file);
+                path_to_uri(&path.canonicalize().unwrap_or(path))
+            },
+        };
+        json!({ "uri": uri, "range": range(analysis.source(text, def), def.span) })
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (uri, line, character) = self.position(params);
+        let doc = self.documents.get(uri)
+            .and_then(|text| word_at(text, line, character))
+            .and_then(|word| mnemonic_doc(&word));
+        match doc {
+            Some(doc) => json!({ "contents": { "kind": "markdown", "value": doc } }),
+            None => Value::Null,
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let (uri, line, character) = self.position(params);
+        let (text, analysis) = match self.analyse(uri) {
+            Some(analysis) => analysis,
+            None => return Value::Null,
+        };
+        analysis.name_at(line, character)
+            .and_then(|name| analysis.definition(name))
+            .map_or(Value::Null, |def| Server::location(uri, text, &analysis, def))
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let (uri, line, character) = self.position(params);
+        let (text, analysis) = match self.analyse(uri) {
+            Some(analysis) => analysis,
+            None => return Value::Null,
+        };
+        let name = match analysis.name_at(line, character) {
+            Some(name) => name,
+            None => return json!([]),
+        };
+        let mut locations = Vec::new();
+        if params["context"]["includeDeclaration"].as_bool().unwrap_or(false) {
+            locations.extend(analysis.definition(name).map(|def| Server::location(uri, text, &analysis, def)));
+        }
+        locations.extend(analysis.references.iter()
+            .filter(|&(used, _)| used == name)
+            .map(|&(_, span)| json!({ "uri": uri, "range": range(text, span) })));
+        Value::Array(locations)
+    }
+
+    /// Registers, then every `.equ` constant, including the port numbers from `ports.inc`.
+    fn completion(&self, params: &Value) -> Value {
+        let (uri, _, _) = self.position(params);
+        let mut items: Vec<Value> = REGISTERS.iter()
+            .filter(|reg| **reg != Register::Null)
+            .map(|reg| json!({ "label": reg.name(), "kind": KIND_VARIABLE, "detail": "register" }))
+            .collect();
+        if let Some((_, analysis)) = self.analyse(uri) {
+            items.extend(analysis.definitions.iter()
+                .filter(|def| def.constant)
+                .map(|def| json!({
+                    "label": def.written,
+                    "kind": KIND_CONSTANT,
+                    "detail": def.value.map_or(String::new(), |value| format!("= {}", value)),
+                })));
+        }
+        Value::Array(items)
+    }
+}
+
+/// Reads one message, framed by a `Content-Length` header. Returns `None` at the end of
+/// the input, and an `InvalidData` error for a message that isn't JSON.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Serves one client until it sends `exit` or closes the input. Returns whether it asked
+/// to shut down first.
+pub fn serve<R: BufRead, W: Write>(mut reader: R, mut writer: W) -> io::Result<bool> {
+    let mut server = Server::new();
+    while !server.exited() {
+        let message = match read_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                write_message(&mut writer, &error_response(&Value::Null, PARSE_ERROR, &err.to_string()))?;
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+        for reply in server.handle(&message) {
+            write_message(&mut writer, &reply)?;
+        }
+    }
+    Ok(server.shut_down_cleanly())
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use encoding::{ImageError, Program};
+use interrupt::VECTOR_TABLE_WORDS;
+use zpu::Fault;
+
+/// "ZSN1" when read as bytes.
+pub const SNAPSHOT_MAGIC: u32 = 0x314E_535A;
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotError {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    Truncated,
+    BadFault(u32),
+    BadImage(ImageError),
+    /// The machine state can't be restored; says what is wrong with it.
+    BadState(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic(magic) => write!(f, "not a ZPU snapshot (magic {:#010x})", magic),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadFault(code) => write!(f, "unknown fault {} in snapshot", code),
+            SnapshotError::BadImage(err) => write!(f, "program in snapshot: {}", err),
+            SnapshotError::BadState(problem) => write!(f, "snapshot has {}", problem),
+        }
+    }
+}
+
+/// A fault as its code and an argument, 0 when the fault has none.
+fn fault_to_words(fault: Option<Fault>) -> (u32, u32) {
+    let arg = match fault {
+        None => return (0, 0),
+        Some(Fault::MemoryOutOfBounds(addr)) => addr,
+        Some(Fault::InvalidOpcode(opcode)) => opcode as u32,
+        Some(Fault::BadRegister(reg)) => reg as u32,
+        Some(Fault::PcOutOfRange(pc)) | Some(Fault::TruncatedImmediate(pc)) => pc,
+        Some(Fault::StackOverflow) | Some(Fault::StackUnderflow) | Some(Fault::DivideByZero) => 0,
+    };
+    (fault.map_or(0, |fault| fault.code()), arg)
+}
+
+fn fault_from_words(code: u32, arg: u32) -> Result<Option<Fault>, SnapshotError> {
+    match code {
+        0 => Ok(None),
+        1 => Ok(Some(Fault::MemoryOutOfBounds(arg))),
+        2 => Ok(Some(Fault::StackOverflow)),
+        3 => Ok(Some(Fault::StackUnderflow)),
+        4 => Ok(Some(Fault::DivideByZero)),
+        5 => Ok(Some(Fault::InvalidOpcode(arg as u16))),
+        6 => Ok(Some(Fault::BadRegister(arg as u8))),
+        7 => Ok(Some(Fault::PcOutOfRange(arg))),
+        8 => Ok(Some(Fault::TruncatedImmediate(arg))),
+        _ => Err(SnapshotError::BadFault(code)),
+    }
+}
+
+/// Everything the ZPU needs to carry on exactly where it left off: the program, registers,
+/// flags, cycle count, memory, stack and interrupt state. Attached devices, the debugger and any trace
+/// in progress belong to the host and aren't included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZpuSnapshot {
+    pub program: Program,
+    pub registers: [u32; 8],
+    pub pc: u32,
+    pub sp: u32,
+    pub cmp_flag: i32,
+    pub signed_cmp_flag: i32,
+    pub zero_flag: bool,
+    pub carry_flag: bool,
+    pub overflow_flag: bool,
+    pub running: bool,
+    pub fault: Option<Fault>,
+    pub cycles: u64,
+    pub memory: Vec<u32>,
+    pub stack: Vec<u32>,
+    pub pending_irqs: u32,
+    pub interrupts_enabled: bool,
+    pub vector_base: u32,
+}
+
+fn write_words(bytes: &mut Vec<u8>, words: &[u32]) {
+    bytes.write_u32::<LittleEndian>(words.len() as u32).unwrap();
+    for word in words {
+        bytes.write_u32::<LittleEndian>(*word).unwrap();
+    }
+}
+
+fn read_word(reader: &mut Cursor<&[u8]>) -> Result<u32, SnapshotError> {
+    reader.read_u32::<LittleEndian>().map_err(|_| SnapshotError::Truncated)
+}
+
+fn read_words(reader: &mut Cursor<&[u8]>) -> Result<Vec<u32>, SnapshotError> {
+    let len = read_word(reader)? as usize;
+    let remaining = reader.get_ref().len() - reader.position() as usize;
+    if len > remaining / 4 {
+        return Err(SnapshotError::Truncated);
+    }
+    let mut words = Vec::with_capacity(len);
+    for _ in 0..len {
+        words.push(read_word(reader)?);
+    }
+    Ok(words)
+}
+
+impl ZpuSnapshot {
+    /// Serialises the snapshot into the format described in zpu/README.md.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(SNAPSHOT_MAGIC).unwrap();
+        bytes.write_u32::<LittleEndian>(SNAPSHOT_VERSION).unwrap();
+        for reg in self.registers.iter() {
+            bytes.write_u32::<LittleEndian>(*reg).unwrap();
+        }
+        bytes.write_u32::<LittleEndian>(self.pc).unwrap();
+        bytes.write_u32::<LittleEndian>(self.sp).unwrap();
+        bytes.write_i32::<LittleEndian>(self.cmp_flag).unwrap();
+        bytes.write_i32::<LittleEndian>(self.signed_cmp_flag).unwrap();
+        bytes.write_u32::<LittleEndian>(self.zero_flag as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.carry_flag as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.overflow_flag as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.running as u32).unwrap();
+        let (code, arg) = fault_to_words(self.fault);
+        bytes.write_u32::<LittleEndian>(code).unwrap();
+        bytes.write_u32::<LittleEndian>(arg).unwrap();
+        bytes.write_u64::<LittleEndian>(self.cycles).unwrap();
+        bytes.write_u32::<LittleEndian>(self.pending_irqs).unwrap();
+        bytes.write_u32::<LittleEndian>(self.interrupts_enabled as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(self.vector_base).unwrap();
+        write_words(&mut bytes, &self.memory);
+        write_words(&mut bytes, &self.stack);
+        let image = self.program.to_bytes();
+        bytes.write_u32::<LittleEndian>(image.len() as u32).unwrap();
+        bytes.extend_from_slice(&image);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ZpuSnapshot, SnapshotError> {
+        let mut reader = Cursor::new(bytes);
+        let magic = read_word(&mut reader)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic(magic));
+        }
+        let version = read_word(&mut reader)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut registers = [0; 8];
+        for reg in registers.iter_mut() {
+            *reg = read_word(&mut reader)?;
+        }
+        let pc = read_word(&mut reader)?;
+        let sp = read_word(&mut reader)?;
+        let cmp_flag = reader.read_i32::<LittleEndian>().map_err(|_| SnapshotError::Truncated)?;
+        let signed_cmp_flag = reader.read_i32::<LittleEndian>().map_err(|_| SnapshotError::Truncated)?;
+        let zero_flag = read_word(&mut reader)? != 0;
+        let carry_flag = read_word(&mut reader)? != 0;
+        let overflow_flag = read_word(&mut reader)? != 0;
+        let running = read_word(&mut reader)? != 0;
+        let code = read_word(&mut reader)?;
+        let fault = fault_from_words(code, read_word(&mut reader)?)?;
+        let cycles = reader.read_u64::<LittleEndian>().map_err(|_| SnapshotError::Truncated)?;
+        let pending_irqs = read_word(&mut reader)?;
+        let interrupts_enabled = read_word(&mut reader)? != 0;
+        let vector_base = read_word(&mut reader)?;
+        let memory = read_words(&mut reader)?;
+        let stack = read_words(&mut reader)?;
+
+        let image_len = read_word(&mut reader)? as usize;
+        let start = reader.position() as usize;
+        if start + image_len > bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let program = Program::from_bytes(&bytes[start..start + image_len]).map_err(SnapshotError::BadImage)?;
+
+        let snapshot = ZpuSnapshot {
+            program,
+            registers,
+            pc,
+            sp,
+            cmp_flag,
+            signed_cmp_flag,
+            zero_flag,
+            carry_flag,
+            overflow_flag,
+            running,
+            fault,
+            cycles,
+            memory,
+            stack,
+            pending_irqs,
+            interrupts_enabled,
+            vector_base,
+        };
+        snapshot.check()?;
+        Ok(snapshot)
+    }
+
+    /// Checks the state is one a ZPU could have been in: SP inside the stack, and the
+    /// vector table inside a memory of at least one word.
+    pub fn check(&self) -> Result<(), SnapshotError> {
+        if self.sp as usize > self.stack.len() {
+            return Err(SnapshotError::BadState("an SP past the end of the stack"));
+        }
+        if self.memory.is_empty() {
+            return Err(SnapshotError::BadState("no data memory"));
+        }
+        if self.vector_base as usize > self.memory.len().saturating_sub(VECTOR_TABLE_WORDS as usize) {
+            return Err(SnapshotError::BadState("a vector table past the end of memory"));
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        File::create(filename)?.write_all(&self.to_bytes())
+    }
+}
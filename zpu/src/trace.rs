@@ -0,0 +1,353 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Write};
+use std::mem;
+use std::rc::Rc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bus::{Bus, Device};
+use encoding::Program;
+use zpu::{REGISTERS, Register, ZPU};
+
+/// "ZTR1" when read as bytes.
+pub const TRACE_MAGIC: u32 = 0x3152_545A;
+pub const TRACE_VERSION: u32 = 1;
+
+const STEP_RECORD: u8 = 0;
+const IRQ_RECORD: u8 = 1;
+
+// Bits of a step record's mask. The low 8 are the registers, in `REGISTERS` order.
+const FLAGS_CHANGED: u16 = 1 << 8;
+const SP_CHANGED: u16 = 1 << 9;
+const HAS_INPUT: u16 = 1 << 10;
+const HAS_OUTPUT: u16 = 1 << 11;
+
+/// FNV-1a over the entry point, code and data, so a trace is only replayed against the
+/// program it was recorded from.
+pub fn fingerprint(program: &Program) -> u32 {
+    let words = Some(program.entry).into_iter().chain(program.code.iter().cloned()).chain(program.data.iter().cloned());
+    words.fold(0x811c_9dc5, |hash, word| {
+        word.to_le_bytes().iter().fold(hash, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+    })
+}
+
+/// What one call to `ZPU::step` did: where it started, the registers and flags it changed,
+/// and the port it read or wrote.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StepRecord {
+    pub pc: u32,
+    pub registers: Vec<(Register, u32)>,
+    pub flags: Option<u32>,
+    pub sp: Option<u32>,
+    /// Port and value of an IN.
+    pub input: Option<(u32, u32)>,
+    /// Port and value of an OUT.
+    pub output: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Step(StepRecord),
+    /// An IRQ line was raised before the next step.
+    Irq(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceError {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    Truncated,
+    BadRecord(u8),
+    /// The trace was recorded from a different program.
+    ProgramMismatch,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceError::BadMagic(magic) => write!(f, "not a ZPU trace (magic {:#010x})", magic),
+            TraceError::UnsupportedVersion(version) => write!(f, "unsupported trace version {}", version),
+            TraceError::Truncated => write!(f, "trace is truncated"),
+            TraceError::BadRecord(tag) => write!(f, "unknown trace record {}", tag),
+            TraceError::ProgramMismatch => write!(f, "trace was recorded from a different program"),
+        }
+    }
+}
+
+/// The state a step record is diffed against.
+#[derive(Clone, Copy)]
+pub struct TraceState {
+    registers: [u32; 8],
+    flags: u32,
+    sp: u32,
+}
+
+impl TraceState {
+    pub fn of(zpu: &ZPU) -> TraceState {
+        TraceState {
+            registers: zpu.registers,
+            flags: zpu.flags_word(),
+            sp: zpu.sp,
+        }
+    }
+}
+
+/// Records every step and IRQ of a ZPU into the binary trace format described in
+/// zpu/README.md. Installed with `ZPU::start_trace`.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    bytes: Vec<u8>,
+    steps: u64,
+    last: Option<StepRecord>,
+    input: Option<(u32, u32)>,
+    output: Option<(u32, u32)>,
+}
+
+impl Tracer {
+    pub fn new(program: &Program) -> Tracer {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(TRACE_MAGIC).unwrap();
+        bytes.write_u32::<LittleEndian>(TRACE_VERSION).unwrap();
+        bytes.write_u32::<LittleEndian>(fingerprint(program)).unwrap();
+        Tracer {
+            bytes,
+            steps: 0,
+            last: None,
+            input: None,
+            output: None,
+        }
+    }
+
+    /// Steps recorded so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The most recent step record.
+    pub fn last_step(&self) -> Option<&StepRecord> {
+        self.last.as_ref()
+    }
+
+    pub fn record_input(&mut self, port: u32, value: u32) {
+        self.input = Some((port, value));
+    }
+
+    pub fn record_output(&mut self, port: u32, value: u32) {
+        self.output = Some((port, value));
+    }
+
+    pub fn record_irq(&mut self, line: u32) {
+        self.bytes.push(IRQ_RECORD);
+        self.bytes.push(line as u8);
+    }
+
+    /// Writes a step record for the step that started at `pc` with the ZPU in `before`.
+    pub fn record_step(&mut self, pc: u32, before: &TraceState, after: &TraceState) {
+        let mut step = StepRecord { pc, ..Default::default() };
+        for (idx, reg) in REGISTERS.iter().enumerate() {
+            if before.registers[idx] != after.registers[idx] {
+                step.registers.push((*reg, after.registers[idx]));
+            }
+        }
+        if before.flags != after.flags {
+            step.flags = Some(after.flags);
+        }
+        if before.sp != after.sp {
+            step.sp = Some(after.sp);
+        }
+        step.input = self.input.take();
+        step.output = self.output.take();
+
+        let mut mask = 0;
+        let mut values = Vec::new();
+        for (reg, value) in step.registers.iter() {
+            mask |= 1 << (reg.hex_value() - 1);
+            values.push(*value);
+        }
+        if let Some(flags) = step.flags {
+            mask |= FLAGS_CHANGED;
+            values.push(flags);
+        }
+        if let Some(sp) = step.sp {
+            mask |= SP_CHANGED;
+            values.push(sp);
+        }
+        if let Some((port, value)) = step.input {
+            mask |= HAS_INPUT;
+            values.push(port);
+            values.push(value);
+        }
+        if let Some((port, value)) = step.output {
+            mask |= HAS_OUTPUT;
+            values.push(port);
+            values.push(value);
+        }
+
+        self.bytes.push(STEP_RECORD);
+        self.bytes.write_u32::<LittleEndian>(pc).unwrap();
+        self.bytes.write_u16::<LittleEndian>(mask).unwrap();
+        for value in values {
+            self.bytes.write_u32::<LittleEndian>(value).unwrap();
+        }
+        self.steps += 1;
+        self.last = Some(step);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        File::create(filename)?.write_all(&self.bytes)
+    }
+}
+
+fn read_u32(reader: &mut Cursor<&[u8]>) -> Result<u32, TraceError> {
+    reader.read_u32::<LittleEndian>().map_err(|_| TraceError::Truncated)
+}
+
+/// Splits a trace into its program fingerprint and events.
+pub fn parse(bytes: &[u8]) -> Result<(u32, Vec<Event>), TraceError> {
+    let mut reader = Cursor::new(bytes);
+    let magic = read_u32(&mut reader)?;
+    if magic != TRACE_MAGIC {
+        return Err(TraceError::BadMagic(magic));
+    }
+    let version = read_u32(&mut reader)?;
+    if version != TRACE_VERSION {
+        return Err(TraceError::UnsupportedVersion(version));
+    }
+    let fingerprint = read_u32(&mut reader)?;
+
+    let mut events = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        match reader.read_u8().map_err(|_| TraceError::Truncated)? {
+            STEP_RECORD => {
+                let mut step = StepRecord { pc: read_u32(&mut reader)?, ..Default::default() };
+                let mask = reader.read_u16::<LittleEndian>().map_err(|_| TraceError::Truncated)?;
+                for (idx, reg) in REGISTERS.iter().enumerate() {
+                    if mask & (1 << idx) != 0 {
+                        step.registers.push((*reg, read_u32(&mut reader)?));
+                    }
+                }
+                if mask & FLAGS_CHANGED != 0 {
+                    step.flags = Some(read_u32(&mut reader)?);
+                }
+                if mask & SP_CHANGED != 0 {
+                    step.sp = Some(read_u32(&mut reader)?);
+                }
+                if mask & HAS_INPUT != 0 {
+                    step.input = Some((read_u32(&mut reader)?, read_u32(&mut reader)?));
+                }
+                if mask & HAS_OUTPUT != 0 {
+                    step.output = Some((read_u32(&mut reader)?, read_u32(&mut reader)?));
+                }
+                events.push(Event::Step(step));
+            },
+            IRQ_RECORD => {
+                let line = reader.read_u8().map_err(|_| TraceError::Truncated)?;
+                events.push(Event::Irq(line as u32));
+            },
+            tag => return Err(TraceError::BadRecord(tag)),
+        }
+    }
+
+    Ok((fingerprint, events))
+}
+
+/// The first step where a replay did something other than the trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Index of the step, counting from 0.
+    pub step: u64,
+    pub expected: StepRecord,
+    /// `None` if the ZPU had already stopped running.
+    pub actual: Option<StepRecord>,
+}
+
+/// Answers every IN during a replay with the value the trace says was read.
+struct ReplayInput {
+    value: Rc<Cell<u32>>,
+}
+
+impl Device for ReplayInput {
+    fn read(&mut self, _port: u32) -> u32 {
+        self.value.get()
+    }
+
+    fn write(&mut self, _port: u32, _data: u32) {}
+}
+
+/// Passes reads and writes on to the host's own bus while a replay has it set aside.
+struct HostPorts {
+    bus: Rc<RefCell<Bus>>,
+}
+
+impl Device for HostPorts {
+    fn read(&mut self, port: u32) -> u32 {
+        self.bus.borrow_mut().read(port).unwrap_or(0)
+    }
+
+    fn write(&mut self, port: u32, data: u32) {
+        self.bus.borrow_mut().write(port, data);
+    }
+}
+
+/// Re-runs a trace against `zpu`, which should have just been loaded with the program the
+/// trace was recorded from. IN reads the recorded values instead of the devices, and IRQs
+/// are raised at the recorded points. Returns the first divergence, if there is one.
+///
+/// The host's bus is set aside while the trace runs, with OUT still reaching its devices,
+/// and put back untouched afterwards.
+pub fn replay(zpu: &mut ZPU, bytes: &[u8]) -> Result<Option<Divergence>, TraceError> {
+    let (expected_fingerprint, events) = parse(bytes)?;
+    if expected_fingerprint != fingerprint(&zpu.program) {
+        return Err(TraceError::ProgramMismatch);
+    }
+
+    let value = Rc::new(Cell::new(0));
+    let mut ports: Vec<u32> = events.iter().filter_map(|event| match *event {
+        Event::Step(StepRecord { input: Some((port, _)), .. }) => Some(port),
+        _ => None,
+    }).collect();
+    ports.sort();
+    ports.dedup();
+    let host = Rc::new(RefCell::new(mem::take(&mut zpu.bus)));
+    let host_ports = host.borrow().mapped_ports();
+    zpu.bus.attach(&host_ports, Box::new(HostPorts { bus: host.clone() }));
+    zpu.bus.attach(&ports, Box::new(ReplayInput { value: value.clone() }));
+
+    let previous = zpu.tracer.take();
+    let mut step = 0;
+    let mut result = None;
+    for event in events {
+        match event {
+            Event::Irq(line) => {
+                zpu.raise_irq(line);
+            },
+            Event::Step(expected) => {
+                if !zpu.running {
+                    result = Some(Divergence { step, expected, actual: None });
+                    break;
+                }
+                value.set(expected.input.map_or(0, |(_, value)| value));
+                zpu.start_trace();
+                zpu.step();
+                let actual = zpu.stop_trace().and_then(|tracer| tracer.last).unwrap_or_default();
+                if actual != expected {
+                    result = Some(Divergence { step, expected, actual: Some(actual) });
+                    break;
+                }
+                step += 1;
+            },
+        }
+    }
+
+    zpu.tracer = previous;
+    // Dropping the replay bus drops its `HostPorts`, the only other owner of the host's bus.
+    zpu.bus = Bus::new();
+    zpu.bus = Rc::try_unwrap(host).ok().expect("replay still holds the host's bus").into_inner();
+    Ok(result)
+}
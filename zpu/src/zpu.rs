@@ -1,6 +1,16 @@
+use std::cmp::Ordering;
+use std::fmt;
 use std::fs::File;
-use std::io::{Cursor, Seek, SeekFrom, Read};
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{self, Read};
+
+use bus::Bus;
+use clock::Clock;
+use debugger::{Debugger, Stop, Watch};
+use disassembler;
+use encoding::{self, DecodedInst, ImageError, Program};
+use interrupt::{InterruptController, VECTOR_TABLE_WORDS};
+use snapshot::ZpuSnapshot;
+use trace::{TraceState, Tracer};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
@@ -28,8 +38,105 @@ pub enum Opcode {
     IfGreater,
     IfLess,
     Compare,
+    Call,
+    Return,
+    EnableInterrupts,
+    DisableInterrupts,
+    InterruptReturn,
+    And,
+    Or,
+    Not,
+    Modulo,
+    Negate,
+    SignedMultiply,
+    SignedDivide,
+    SignedModulo,
+    ShiftRightArithmetic,
+    IfGreaterSigned,
+    IfLessSigned,
 }
 
+/// How an instruction's operands are written in assembly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operands {
+    /// `hlt`
+    None,
+    /// `jmp addr`
+    Target,
+    /// `inc r1`
+    Reg,
+    /// `add r1, r2/val`
+    RegValue,
+    /// `mmov r1, [mem]`
+    RegMem,
+    /// `mset [mem], r1`
+    MemReg,
+}
+
+impl Operands {
+    /// Whether the instruction needs a register in REG1.
+    pub fn uses_reg1(&self) -> bool {
+        match *self {
+            Operands::Reg | Operands::RegValue | Operands::RegMem | Operands::MemReg => true,
+            Operands::None | Operands::Target => false,
+        }
+    }
+}
+
+pub const OPCODES: [Opcode; 40] = [
+    Opcode::NoOp,
+    Opcode::Jump,
+    Opcode::Halt,
+    Opcode::Increment,
+    Opcode::ShiftRight,
+    Opcode::ShiftLeft,
+    Opcode::Move,
+    Opcode::Add,
+    Opcode::Subtract,
+    Opcode::Multiply,
+    Opcode::Divide,
+    Opcode::IfEqual,
+    Opcode::IfNotEqual,
+    Opcode::MemoryMove,
+    Opcode::MemorySet,
+    Opcode::XOr,
+    Opcode::In,
+    Opcode::Out,
+    Opcode::Push,
+    Opcode::Pop,
+    Opcode::IfZero,
+    Opcode::IfGreater,
+    Opcode::IfLess,
+    Opcode::Compare,
+    Opcode::Call,
+    Opcode::Return,
+    Opcode::EnableInterrupts,
+    Opcode::DisableInterrupts,
+    Opcode::InterruptReturn,
+    Opcode::And,
+    Opcode::Or,
+    Opcode::Not,
+    Opcode::Modulo,
+    Opcode::Negate,
+    Opcode::SignedMultiply,
+    Opcode::SignedDivide,
+    Opcode::SignedModulo,
+    Opcode::ShiftRightArithmetic,
+    Opcode::IfGreaterSigned,
+    Opcode::IfLessSigned,
+];
+
+pub const REGISTERS: [Register; 8] = [
+    Register::A,
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::X,
+    Register::Y,
+    Register::Z,
+];
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Register {
     A,
@@ -58,6 +165,24 @@ impl Register {
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Register::Null => "null",
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::X => "x",
+            Register::Y => "y",
+            Register::Z => "z",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Register> {
+        REGISTERS.iter().find(|reg| reg.name() == name).cloned()
+    }
+
     pub fn from_value(value: u8) -> Register {
         match value {
             0x1 => Register::A,
@@ -100,41 +225,246 @@ impl Opcode {
             Opcode::IfGreater => 0x15,
             Opcode::IfLess => 0x16,
             Opcode::Compare => 0x17,
+            Opcode::Call => 0x18,
+            Opcode::Return => 0x19,
+            Opcode::EnableInterrupts => 0x1A,
+            Opcode::DisableInterrupts => 0x1B,
+            Opcode::InterruptReturn => 0x1C,
+            Opcode::And => 0x1D,
+            Opcode::Or => 0x1E,
+            Opcode::Not => 0x1F,
+            Opcode::Modulo => 0x20,
+            Opcode::Negate => 0x21,
+            Opcode::SignedMultiply => 0x22,
+            Opcode::SignedDivide => 0x23,
+            Opcode::SignedModulo => 0x24,
+            Opcode::ShiftRightArithmetic => 0x25,
+            Opcode::IfGreaterSigned => 0x26,
+            Opcode::IfLessSigned => 0x27,
         }
     }
 
-    pub fn from_value(value: u16) -> Opcode {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Opcode::NoOp => "nop",
+            Opcode::Jump => "jmp",
+            Opcode::Halt => "hlt",
+            Opcode::Increment => "inc",
+            Opcode::ShiftRight => "shr",
+            Opcode::ShiftLeft => "shl",
+            Opcode::Move => "mov",
+            Opcode::Add => "add",
+            Opcode::Subtract => "sub",
+            Opcode::Multiply => "mul",
+            Opcode::Divide => "div",
+            Opcode::IfEqual => "je",
+            Opcode::IfNotEqual => "jn",
+            Opcode::MemoryMove => "mmov",
+            Opcode::MemorySet => "mset",
+            Opcode::XOr => "xor",
+            Opcode::In => "in",
+            Opcode::Out => "out",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::IfZero => "jz",
+            Opcode::IfGreater => "jg",
+            Opcode::IfLess => "jl",
+            Opcode::Compare => "cmp",
+            Opcode::Call => "call",
+            Opcode::Return => "ret",
+            Opcode::EnableInterrupts => "ei",
+            Opcode::DisableInterrupts => "di",
+            Opcode::InterruptReturn => "iret",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Not => "not",
+            Opcode::Modulo => "mod",
+            Opcode::Negate => "neg",
+            Opcode::SignedMultiply => "imul",
+            Opcode::SignedDivide => "idiv",
+            Opcode::SignedModulo => "imod",
+            Opcode::ShiftRightArithmetic => "sar",
+            Opcode::IfGreaterSigned => "jgs",
+            Opcode::IfLessSigned => "jls",
+        }
+    }
+
+    /// Cycles the instruction takes, whether or not it has an immediate.
+    pub fn cycles(&self) -> u32 {
+        match *self {
+            Opcode::MemoryMove | Opcode::MemorySet | Opcode::Push | Opcode::Pop => 2,
+            Opcode::Multiply | Opcode::SignedMultiply | Opcode::Call | Opcode::Return => 3,
+            Opcode::In | Opcode::Out | Opcode::InterruptReturn => 4,
+            Opcode::Divide | Opcode::Modulo | Opcode::SignedDivide | Opcode::SignedModulo => 8,
+            _ => 1,
+        }
+    }
+
+    /// The operands as the reference table writes them.
+    pub fn syntax(&self) -> &'static str {
+        match *self {
+            Opcode::NoOp | Opcode::Halt | Opcode::Return | Opcode::EnableInterrupts |
+            Opcode::DisableInterrupts | Opcode::InterruptReturn => "(none)",
+            Opcode::Jump | Opcode::IfEqual | Opcode::IfNotEqual | Opcode::IfZero | Opcode::IfGreater |
+            Opcode::IfLess | Opcode::Call | Opcode::IfGreaterSigned | Opcode::IfLessSigned => "addr",
+            Opcode::Increment | Opcode::Push | Opcode::Pop | Opcode::Not | Opcode::Negate => "r1",
+            Opcode::ShiftRight | Opcode::ShiftLeft | Opcode::Move | Opcode::ShiftRightArithmetic => "r1, val",
+            Opcode::Add | Opcode::Subtract | Opcode::Multiply | Opcode::Divide | Opcode::XOr |
+            Opcode::Compare | Opcode::And | Opcode::Or | Opcode::Modulo | Opcode::SignedMultiply |
+            Opcode::SignedDivide | Opcode::SignedModulo => "r1, r2",
+            Opcode::MemoryMove => "r1, [mem]",
+            Opcode::MemorySet => "[mem], r1",
+            Opcode::In => "r1, [in]",
+            Opcode::Out => "[out], r1",
+        }
+    }
+
+    /// What the instruction does, as in the reference table.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Opcode::NoOp => "No Operation",
+            Opcode::Jump => "Jump to Address",
+            Opcode::Halt => "Halt",
+            Opcode::Increment => "Increment R1",
+            Opcode::ShiftRight => "(r1 >> val) -> r1",
+            Opcode::ShiftLeft => "(r1 << val) -> r1",
+            Opcode::Move => "Load Val/R2 into R1",
+            Opcode::Add => "(r1 + r2)   -> r1",
+            Opcode::Subtract => "(r1 - r2)   -> r1",
+            Opcode::Multiply => "(r1 * r2)   -> r1",
+            Opcode::Divide => "(r1 / r2)   -> r1",
+            Opcode::IfEqual => "if (cmp_flag == 0): jmp to addr, else, continue",
+            Opcode::IfNotEqual => "if (cmp_flag != 0): jmp to addr, else, continue",
+            Opcode::MemoryMove => "Move u32 in memory address into R1",
+            Opcode::MemorySet => "Move u32 in R1 into memory address",
+            Opcode::XOr => "(r1 ^ r2)   -> r1",
+            Opcode::In => "Move u32 from port [in] to r1",
+            Opcode::Out => "Move u32 from r1 to port [out]",
+            Opcode::Push => "Push u32 from r1 onto stack, incrementing SP",
+            Opcode::Pop => "Pop u32 from stack and put into r1, decrementing SP",
+            Opcode::IfZero => "if (zero_flag == 1): jmp to addr, else, continue",
+            Opcode::IfGreater => "if (cmp_flag > 0): jmp to addr, else, continue",
+            Opcode::IfLess => "if (cmp_flag < 0): jmp to addr, else, continue",
+            Opcode::Compare => {
+                "set cmp_flag to 1 if r1 > r2; -1 if r1 < r2; cmp_flag to 0, zero_flag to 1 if r1 == r2. \
+                 signed_cmp_flag is set the same way, comparing signed"
+            },
+            Opcode::Call => "Push the address of the next instruction, then jmp to addr",
+            Opcode::Return => "Pop an address from the stack and jmp to it",
+            Opcode::EnableInterrupts => "Enable interrupts",
+            Opcode::DisableInterrupts => "Disable interrupts",
+            Opcode::InterruptReturn => "Pop flags, then PC, returning from an interrupt handler",
+            Opcode::And => "(r1 & r2)   -> r1",
+            Opcode::Or => "(r1 | r2)  -> r1",
+            Opcode::Not => "(!r1)       -> r1",
+            Opcode::Modulo => "(r1 % r2)   -> r1",
+            Opcode::Negate => "(-r1)       -> r1",
+            Opcode::SignedMultiply => "(r1 * r2)   -> r1, signed",
+            Opcode::SignedDivide => "(r1 / r2)   -> r1, signed, rounding towards 0",
+            Opcode::SignedModulo => "(r1 % r2)   -> r1, signed, taking the sign of r1",
+            Opcode::ShiftRightArithmetic => "(r1 >> val) -> r1, shifting in copies of the sign bit",
+            Opcode::IfGreaterSigned => "if (signed_cmp_flag > 0): jmp to addr, else, continue",
+            Opcode::IfLessSigned => "if (signed_cmp_flag < 0): jmp to addr, else, continue",
+        }
+    }
+
+    pub fn from_mnemonic(name: &str) -> Option<Opcode> {
+        OPCODES.iter().find(|op| op.mnemonic() == name).cloned()
+    }
+
+    pub fn operands(&self) -> Operands {
+        match *self {
+            Opcode::NoOp => Operands::None,
+            Opcode::Jump => Operands::Target,
+            Opcode::Halt => Operands::None,
+            Opcode::Increment => Operands::Reg,
+            Opcode::ShiftRight => Operands::RegValue,
+            Opcode::ShiftLeft => Operands::RegValue,
+            Opcode::Move => Operands::RegValue,
+            Opcode::Add => Operands::RegValue,
+            Opcode::Subtract => Operands::RegValue,
+            Opcode::Multiply => Operands::RegValue,
+            Opcode::Divide => Operands::RegValue,
+            Opcode::IfEqual => Operands::Target,
+            Opcode::IfNotEqual => Operands::Target,
+            Opcode::MemoryMove => Operands::RegMem,
+            Opcode::MemorySet => Operands::MemReg,
+            Opcode::XOr => Operands::RegValue,
+            Opcode::In => Operands::RegValue,
+            Opcode::Out => Operands::RegValue,
+            Opcode::Push => Operands::Reg,
+            Opcode::Pop => Operands::Reg,
+            Opcode::IfZero => Operands::Target,
+            Opcode::IfGreater => Operands::Target,
+            Opcode::IfLess => Operands::Target,
+            Opcode::Compare => Operands::RegValue,
+            Opcode::Call => Operands::Target,
+            Opcode::Return => Operands::None,
+            Opcode::EnableInterrupts => Operands::None,
+            Opcode::DisableInterrupts => Operands::None,
+            Opcode::InterruptReturn => Operands::None,
+            Opcode::And => Operands::RegValue,
+            Opcode::Or => Operands::RegValue,
+            Opcode::Not => Operands::Reg,
+            Opcode::Modulo => Operands::RegValue,
+            Opcode::Negate => Operands::Reg,
+            Opcode::SignedMultiply => Operands::RegValue,
+            Opcode::SignedDivide => Operands::RegValue,
+            Opcode::SignedModulo => Operands::RegValue,
+            Opcode::ShiftRightArithmetic => Operands::RegValue,
+            Opcode::IfGreaterSigned => Operands::Target,
+            Opcode::IfLessSigned => Operands::Target,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Option<Opcode> {
         match value {
-            0x0 => Opcode::NoOp,
-            0x1 => Opcode::Jump,
-            0x2 => Opcode::Halt,
-            0x3 => Opcode::Increment,
-            0x4 => Opcode::ShiftRight,
-            0x5 => Opcode::ShiftLeft,
-            0x6 => Opcode::Move,
-            0x7 => Opcode::Add,
-            0x8 => Opcode::Subtract,
-            0x9 => Opcode::Multiply,
-            0xA => Opcode::Divide,
-            0xB => Opcode::IfEqual,
-            0xC => Opcode::IfNotEqual,
-            0xD => Opcode::MemoryMove,
-            0xE => Opcode::MemorySet,
-            0xF => Opcode::XOr,
-            0x10 => Opcode::In,
-            0x11 => Opcode::Out,
-            0x12 => Opcode::Push,
-            0x13 => Opcode::Pop,
-            0x14 => Opcode::IfZero,
-            0x15 => Opcode::IfGreater,
-            0x16 => Opcode::IfLess,
-            0x17 => Opcode::Compare,
-            _ => Opcode::NoOp,
+            0x0 => Some(Opcode::NoOp),
+            0x1 => Some(Opcode::Jump),
+            0x2 => Some(Opcode::Halt),
+            0x3 => Some(Opcode::Increment),
+            0x4 => Some(Opcode::ShiftRight),
+            0x5 => Some(Opcode::ShiftLeft),
+            0x6 => Some(Opcode::Move),
+            0x7 => Some(Opcode::Add),
+            0x8 => Some(Opcode::Subtract),
+            0x9 => Some(Opcode::Multiply),
+            0xA => Some(Opcode::Divide),
+            0xB => Some(Opcode::IfEqual),
+            0xC => Some(Opcode::IfNotEqual),
+            0xD => Some(Opcode::MemoryMove),
+            0xE => Some(Opcode::MemorySet),
+            0xF => Some(Opcode::XOr),
+            0x10 => Some(Opcode::In),
+            0x11 => Some(Opcode::Out),
+            0x12 => Some(Opcode::Push),
+            0x13 => Some(Opcode::Pop),
+            0x14 => Some(Opcode::IfZero),
+            0x15 => Some(Opcode::IfGreater),
+            0x16 => Some(Opcode::IfLess),
+            0x17 => Some(Opcode::Compare),
+            0x18 => Some(Opcode::Call),
+            0x19 => Some(Opcode::Return),
+            0x1A => Some(Opcode::EnableInterrupts),
+            0x1B => Some(Opcode::DisableInterrupts),
+            0x1C => Some(Opcode::InterruptReturn),
+            0x1D => Some(Opcode::And),
+            0x1E => Some(Opcode::Or),
+            0x1F => Some(Opcode::Not),
+            0x20 => Some(Opcode::Modulo),
+            0x21 => Some(Opcode::Negate),
+            0x22 => Some(Opcode::SignedMultiply),
+            0x23 => Some(Opcode::SignedDivide),
+            0x24 => Some(Opcode::SignedModulo),
+            0x25 => Some(Opcode::ShiftRightArithmetic),
+            0x26 => Some(Opcode::IfGreaterSigned),
+            0x27 => Some(Opcode::IfLessSigned),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Output {
     pub port: u32,
     pub data: u32,
@@ -143,58 +473,400 @@ pub struct Output {
 impl Output {
     pub fn new(port: u32, data: u32) -> Output {
         Output {
-            port: port,
-            data: data,
+            port,
+            data,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    MemoryOutOfBounds(u32),
+    StackOverflow,
+    StackUnderflow,
+    DivideByZero,
+    /// The opcode field of the instruction word isn't a known opcode.
+    InvalidOpcode(u16),
+    /// A register field above 8, or 0 where the instruction needs a register.
+    BadRegister(u8),
+    /// PC is past the end of the code.
+    PcOutOfRange(u32),
+    /// The instruction at this address is missing its immediate word.
+    TruncatedImmediate(u32),
+}
+
+impl Fault {
+    /// The number a fault is reported as, to the host and in snapshots. 0 means no fault.
+    pub fn code(&self) -> u32 {
+        match *self {
+            Fault::MemoryOutOfBounds(_) => 1,
+            Fault::StackOverflow => 2,
+            Fault::StackUnderflow => 3,
+            Fault::DivideByZero => 4,
+            Fault::InvalidOpcode(_) => 5,
+            Fault::BadRegister(_) => 6,
+            Fault::PcOutOfRange(_) => 7,
+            Fault::TruncatedImmediate(_) => 8,
+        }
+    }
+
+    /// Whether a program can catch the fault with a handler in the fault vector. Only a
+    /// divide by zero can be caught; every other fault stops the ZPU.
+    pub fn is_trappable(&self) -> bool {
+        matches!(*self, Fault::DivideByZero)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::MemoryOutOfBounds(addr) => write!(f, "memory address {} is out of bounds", addr),
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "stack underflow"),
+            Fault::DivideByZero => write!(f, "divide by zero"),
+            Fault::InvalidOpcode(opcode) => write!(f, "invalid opcode {:#x}", opcode),
+            Fault::BadRegister(0) => write!(f, "missing register"),
+            Fault::BadRegister(reg) => write!(f, "bad register {}", reg),
+            Fault::PcOutOfRange(pc) => write!(f, "PC {} is past the end of the program", pc),
+            Fault::TruncatedImmediate(pc) => write!(f, "instruction at {} is missing its immediate", pc),
+        }
+    }
+}
+
+/// Why a program file couldn't be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Image(ImageError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref err) => write!(f, "{}", err),
+            LoadError::Image(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+fn read_file(filename: &str) -> Result<Vec<u8>, LoadError> {
+    let mut bytes = Vec::new();
+    File::open(filename).and_then(|mut file| file.read_to_end(&mut bytes)).map_err(LoadError::Io)?;
+    Ok(bytes)
+}
+
 #[derive(Debug)]
 pub struct ZResult {
     pub running: bool,
     pub output: Option<Output>,
+    pub fault: Option<Fault>,
 }
 
 impl ZResult {
     pub fn new(running: bool, output: Option<Output>) -> ZResult {
         ZResult {
-            running: running,
-            output: output,
+            running,
+            output,
+            fault: None,
+        }
+    }
+
+    pub fn faulted(fault: Fault) -> ZResult {
+        ZResult {
+            running: false,
+            output: None,
+            fault: Some(fault),
         }
     }
 }
 
+/// Why `ZPU::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunOutcome {
+    /// The cycle budget ran out with the program still running.
+    Budget,
+    /// An OUT to a port with no device attached, for the host to handle.
+    Output(Output),
+    Halted,
+    Fault(Fault),
+    /// PC reached a breakpoint, which hasn't run yet.
+    Breakpoint(u32),
+    /// A watched register or memory word changed from the first value to the second.
+    Watchpoint(Watch, u32, u32),
+}
+
+/// Cycles taken to enter an interrupt or fault handler.
+pub const INTERRUPT_CYCLES: u32 = 4;
+
+/// Size of the data memory in 32-bit words, unless changed with `resize_memory`.
+pub const DEFAULT_MEMORY_SIZE: usize = 4096;
+/// Size of the stack region in 32-bit words, unless changed with `resize_stack`.
+pub const DEFAULT_STACK_SIZE: usize = 256;
+
 pub struct ZPU {
-    pub program: Cursor<Vec<u8>>,
+    /// Replace with `load`, so the decoded copy stays in step.
+    pub program: Program,
+    decoded: Vec<DecodedInst>,
     pub registers: [u32; 8],
+    pub memory: Vec<u32>,
+    pub stack: Vec<u32>,
     pub pc: u32,
+    pub sp: u32,
     pub cmp_flag: i32,
+    /// Like `cmp_flag`, but from comparing the operands as signed numbers.
+    pub signed_cmp_flag: i32,
     pub zero_flag: bool,
+    /// Set by arithmetic when the unsigned result wrapped, or by a shift to the last bit shifted out.
+    pub carry_flag: bool,
+    /// Set by arithmetic when the result, read as signed, wrapped.
+    pub overflow_flag: bool,
     pub running: bool,
+    pub fault: Option<Fault>,
+    /// Cycles run since the program was loaded.
+    pub cycles: u64,
+    /// How fast `advance` runs the ZPU.
+    pub clock: Clock,
+    pub bus: Bus,
+    pub interrupts: InterruptController,
+    pub debugger: Debugger,
+    /// Records every step while set. See `start_trace`.
+    pub tracer: Option<Tracer>,
 }
 
 impl ZPU {
-    pub fn new(filename: &str) -> ZPU {
-        let mut file = File::open(filename).unwrap();
-        let mut file_buffer = Vec::new();
-        file.read_to_end(&mut file_buffer).unwrap();
-        let program = Cursor::new(file_buffer);
-        ZPU {
-            program: program,
+    /// Builds a ZPU from a binary image file.
+    pub fn new(filename: &str) -> Result<ZPU, LoadError> {
+        ZPU::from_bytes(&read_file(filename)?).map_err(LoadError::Image)
+    }
+
+    /// Builds a ZPU from a binary image, such as the output of `Program::to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ZPU, ImageError> {
+        let program = Program::from_bytes(bytes)?;
+        Ok(ZPU::from_program(&program))
+    }
+
+    pub fn from_program(program: &Program) -> ZPU {
+        let mut zpu = ZPU {
+            program: program.clone(),
+            decoded: Vec::new(),
             registers: [0, 0, 0, 0, 0, 0, 0, 0],
+            memory: vec![0; DEFAULT_MEMORY_SIZE],
+            stack: vec![0; DEFAULT_STACK_SIZE],
             pc: 0,
+            sp: 0,
             cmp_flag: 0,
+            signed_cmp_flag: 0,
             zero_flag: false,
-            running: true,
-        }
+            carry_flag: false,
+            overflow_flag: false,
+            running: false,
+            fault: None,
+            cycles: 0,
+            clock: Clock::default(),
+            bus: Bus::new(),
+            interrupts: InterruptController::new(),
+            debugger: Debugger::new(),
+            tracer: None,
+        };
+        zpu.reset();
+        zpu
     }
 
     fn reset(&mut self) {
+        self.decoded = encoding::decode_program(&self.program.code);
         self.registers = [0, 0, 0, 0, 0, 0, 0, 0];
-        self.pc = 0;
+        for word in self.memory.iter_mut() {
+            *word = 0;
+        }
+        for word in self.stack.iter_mut() {
+            *word = 0;
+        }
+        for (word, value) in self.memory.iter_mut().zip(self.program.data.iter()) {
+            *word = *value;
+        }
+        self.pc = self.program.entry;
+        self.sp = 0;
         self.cmp_flag = 0;
+        self.signed_cmp_flag = 0;
         self.zero_flag = false;
-        self.running = true;
+        self.carry_flag = false;
+        self.overflow_flag = false;
+        self.running = !self.program.code.is_empty();
+        self.fault = None;
+        self.cycles = 0;
+        self.clock.reset();
+        self.interrupts.reset();
+        self.debugger.break_pending = false;
+    }
+
+    /// Changes the number of addressable memory words. Existing contents below the new
+    /// size are kept, anything above it is dropped. The vector table is moved down if it
+    /// would no longer fit.
+    pub fn resize_memory(&mut self, words: usize) {
+        self.memory.resize(words, 0);
+        let top = words.saturating_sub(VECTOR_TABLE_WORDS as usize) as u32;
+        if self.interrupts.vector_base > top {
+            self.interrupts.vector_base = top;
+        }
+    }
+
+    /// Changes the number of words the stack can hold. Values above the new size are
+    /// dropped, and SP is clamped so the next PUSH stays inside the stack.
+    pub fn resize_stack(&mut self, words: usize) {
+        self.stack.resize(words, 0);
+        if self.sp as usize > words {
+            self.sp = words as u32;
+        }
+    }
+
+    /// The stack grows upwards: SP is the index of the next free slot.
+    pub fn push_word(&mut self, value: u32) -> Result<(), Fault> {
+        match self.stack.get_mut(self.sp as usize) {
+            Some(slot) => {
+                *slot = value;
+                self.sp += 1;
+                Ok(())
+            },
+            None => Err(Fault::StackOverflow),
+        }
+    }
+
+    /// An SP past the end of the stack, which only the host can set, pops as an overflow.
+    pub fn pop_word(&mut self) -> Result<u32, Fault> {
+        if self.sp == 0 {
+            return Err(Fault::StackUnderflow);
+        }
+        match self.stack.get(self.sp as usize - 1) {
+            Some(value) => {
+                self.sp -= 1;
+                Ok(*value)
+            },
+            None => Err(Fault::StackOverflow),
+        }
+    }
+
+    /// Packs the flags IRET restores: bit 0 is zero_flag, bit 1 is set when cmp_flag > 0,
+    /// bit 2 when cmp_flag < 0, bit 3 when interrupts are enabled, bit 4 is carry_flag,
+    /// bit 5 overflow_flag, and bits 6 and 7 are set when signed_cmp_flag is > 0 and < 0.
+    pub fn flags_word(&self) -> u32 {
+        let mut flags = 0;
+        if self.zero_flag {
+            flags |= 0x1;
+        }
+        if self.cmp_flag > 0 {
+            flags |= 0x2;
+        } else if self.cmp_flag < 0 {
+            flags |= 0x4;
+        }
+        if self.interrupts.enabled {
+            flags |= 0x8;
+        }
+        if self.carry_flag {
+            flags |= 0x10;
+        }
+        if self.overflow_flag {
+            flags |= 0x20;
+        }
+        if self.signed_cmp_flag > 0 {
+            flags |= 0x40;
+        } else if self.signed_cmp_flag < 0 {
+            flags |= 0x80;
+        }
+        flags
+    }
+
+    pub fn set_flags_word(&mut self, flags: u32) {
+        self.zero_flag = flags & 0x1 != 0;
+        self.cmp_flag = if flags & 0x2 != 0 {
+            1
+        } else if flags & 0x4 != 0 {
+            -1
+        } else {
+            0
+        };
+        self.interrupts.enabled = flags & 0x8 != 0;
+        self.carry_flag = flags & 0x10 != 0;
+        self.overflow_flag = flags & 0x20 != 0;
+        self.signed_cmp_flag = if flags & 0x40 != 0 {
+            1
+        } else if flags & 0x80 != 0 {
+            -1
+        } else {
+            0
+        };
+    }
+
+    /// Raises an IRQ line; it is serviced at the start of the next step once interrupts are enabled.
+    pub fn raise_irq(&mut self, line: u32) -> bool {
+        let raised = self.interrupts.raise(line);
+        if let (true, Some(tracer)) = (raised, self.tracer.as_mut()) {
+            tracer.record_irq(line);
+        }
+        raised
+    }
+
+    /// The handler in a vector, or `None` if it holds 0 or is past the end of memory.
+    fn handler(&self, vector: u32) -> Option<u32> {
+        match self.read_memory(vector) {
+            Ok(0) | Err(_) => None,
+            Ok(handler) => Some(handler),
+        }
+    }
+
+    /// Enters the handler for the lowest pending IRQ, if any, returning whether it did. PC
+    /// and flags are pushed so IRET can restore them, and interrupts stay disabled until then.
+    fn service_interrupt(&mut self) -> Result<bool, Fault> {
+        if let Some(line) = self.interrupts.acknowledge() {
+            if let Some(handler) = self.handler(self.interrupts.vector_addr(line)) {
+                let pc = self.pc;
+                let flags = self.flags_word();
+                self.push_word(pc)?;
+                self.push_word(flags)?;
+                self.interrupts.enabled = false;
+                self.pc = handler;
+                self.cycles += INTERRUPT_CYCLES as u64;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Enters the handler in the fault vector, at `vector_base + IRQ_LINES`, the same way
+    /// an interrupt would. PC already points past the faulting instruction, so IRET
+    /// carries on after it. Gives the fault back if it can't be handled.
+    fn trap(&mut self, fault: Fault) -> Result<(), Fault> {
+        if !fault.is_trappable() {
+            return Err(fault);
+        }
+        let handler = match self.handler(self.interrupts.fault_vector_addr()) {
+            Some(handler) => handler,
+            None => return Err(fault),
+        };
+        let pc = self.pc;
+        let flags = self.flags_word();
+        self.push_word(pc)?;
+        self.push_word(flags)?;
+        self.interrupts.enabled = false;
+        self.pc = handler;
+        self.cycles += INTERRUPT_CYCLES as u64;
+        Ok(())
+    }
+
+    pub fn read_memory(&self, addr: u32) -> Result<u32, Fault> {
+        match self.memory.get(addr as usize) {
+            Some(value) => Ok(*value),
+            None => Err(Fault::MemoryOutOfBounds(addr)),
+        }
+    }
+
+    pub fn write_memory(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+        match self.memory.get_mut(addr as usize) {
+            Some(word) => {
+                *word = value;
+                Ok(())
+            },
+            None => Err(Fault::MemoryOutOfBounds(addr)),
+        }
     }
 
     fn jump(&mut self, value: u32) -> Option<Output> {
@@ -202,6 +874,25 @@ impl ZPU {
         None
     }
 
+    fn call(&mut self, value: u32) -> Result<Option<Output>, Fault> {
+        let ret = self.pc;
+        self.push_word(ret)?;
+        self.pc = value;
+        Ok(None)
+    }
+
+    fn ret(&mut self) -> Result<Option<Output>, Fault> {
+        self.pc = self.pop_word()?;
+        Ok(None)
+    }
+
+    fn iret(&mut self) -> Result<Option<Output>, Fault> {
+        let flags = self.pop_word()?;
+        self.pc = self.pop_word()?;
+        self.set_flags_word(flags);
+        Ok(None)
+    }
+
     fn je(&mut self, value: u32) -> Option<Output> {
         if self.cmp_flag == 0 {
             self.pc = value;
@@ -230,6 +921,20 @@ impl ZPU {
         None
     }
 
+    fn jgs(&mut self, value: u32) -> Option<Output> {
+        if self.signed_cmp_flag > 0 {
+            self.pc = value;
+        }
+        None
+    }
+
+    fn jls(&mut self, value: u32) -> Option<Output> {
+        if self.signed_cmp_flag < 0 {
+            self.pc = value;
+        }
+        None
+    }
+
     fn jz(&mut self, value: u32) -> Option<Output> {
         if self.zero_flag {
             self.pc = value;
@@ -237,6 +942,7 @@ impl ZPU {
         None
     }
 
+    /// Sets cmp_flag from comparing unsigned, and signed_cmp_flag from comparing signed.
     fn cmp(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
         if self.registers[idx] == value {
@@ -249,24 +955,32 @@ impl ZPU {
             self.zero_flag = false;
             self.cmp_flag = 1;
         }
+        self.signed_cmp_flag = match (self.registers[idx] as i32).cmp(&(value as i32)) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        };
         None
     }
 
     fn inc(&mut self, reg: Register) -> Option<Output> {
-        let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] += 1;
-        None
+        self.add(reg, 1)
     }
 
+    /// Shifting by 32 or more clears the register.
     fn shr(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] >> value;
+        let old = self.registers[idx];
+        self.carry_flag = value != 0 && value <= 32 && old >> (value - 1) & 1 != 0;
+        self.registers[idx] = old.checked_shr(value).unwrap_or(0);
         None
     }
 
     fn shl(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] << value;
+        let old = self.registers[idx];
+        self.carry_flag = value != 0 && value <= 32 && old << (value - 1) >> 31 != 0;
+        self.registers[idx] = old.checked_shl(value).unwrap_or(0);
         None
     }
 
@@ -278,81 +992,479 @@ impl ZPU {
 
     fn add(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] + value;
+        let old = self.registers[idx];
+        let (result, carry) = old.overflowing_add(value);
+        self.carry_flag = carry;
+        self.overflow_flag = (old as i32).overflowing_add(value as i32).1;
+        self.registers[idx] = result;
         None
     }
 
+    /// Carry is set when the subtraction borrowed.
     fn sub(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] - value;
+        let old = self.registers[idx];
+        let (result, carry) = old.overflowing_sub(value);
+        self.carry_flag = carry;
+        self.overflow_flag = (old as i32).overflowing_sub(value as i32).1;
+        self.registers[idx] = result;
         None
     }
 
+    /// Keeps the low 32 bits of the product.
     fn mul(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] * value;
+        let old = self.registers[idx];
+        let (result, carry) = old.overflowing_mul(value);
+        self.carry_flag = carry;
+        self.overflow_flag = (old as i32).overflowing_mul(value as i32).1;
+        self.registers[idx] = result;
         None
     }
 
-    fn div(&mut self, reg: Register, value: u32) -> Option<Output> {
+    fn div(&mut self, reg: Register, value: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        match self.registers[idx].checked_div(value) {
+            Some(result) => {
+                self.registers[idx] = result;
+                Ok(None)
+            },
+            None => Err(Fault::DivideByZero),
+        }
+    }
+
+    fn modulo(&mut self, reg: Register, value: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        match self.registers[idx].checked_rem(value) {
+            Some(result) => {
+                self.registers[idx] = result;
+                Ok(None)
+            },
+            None => Err(Fault::DivideByZero),
+        }
+    }
+
+    /// Carry is set unless the register was 0, overflow when it was the most negative number.
+    fn neg(&mut self, reg: Register) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
-        self.registers[idx] = self.registers[idx] / value;
+        let old = self.registers[idx];
+        self.carry_flag = old != 0;
+        self.overflow_flag = old == 0x8000_0000;
+        self.registers[idx] = old.wrapping_neg();
         None
     }
 
+    fn imul(&mut self, reg: Register, value: u32) -> Option<Output> {
+        let idx = (reg.hex_value() as usize) - 1;
+        let (result, overflow) = (self.registers[idx] as i32).overflowing_mul(value as i32);
+        self.carry_flag = overflow;
+        self.overflow_flag = overflow;
+        self.registers[idx] = result as u32;
+        None
+    }
+
+    /// Rounds towards zero. The most negative number divided by -1 wraps to itself and
+    /// sets overflow.
+    fn idiv(&mut self, reg: Register, value: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        if value == 0 {
+            return Err(Fault::DivideByZero);
+        }
+        let (result, overflow) = (self.registers[idx] as i32).overflowing_div(value as i32);
+        self.overflow_flag = overflow;
+        self.registers[idx] = result as u32;
+        Ok(None)
+    }
+
+    /// The remainder takes the sign of the register.
+    fn imod(&mut self, reg: Register, value: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        if value == 0 {
+            return Err(Fault::DivideByZero);
+        }
+        self.registers[idx] = (self.registers[idx] as i32).wrapping_rem(value as i32) as u32;
+        Ok(None)
+    }
+
+    /// Shifting by 32 or more fills the register with its sign bit.
+    fn sar(&mut self, reg: Register, value: u32) -> Option<Output> {
+        let idx = (reg.hex_value() as usize) - 1;
+        let old = self.registers[idx] as i32;
+        self.carry_flag = value != 0 && (old >> (value.min(32) - 1).min(31)) & 1 != 0;
+        self.registers[idx] = (old >> value.min(31)) as u32;
+        None
+    }
+
+    fn bitwise(&mut self, reg: Register, value: u32, op: fn(u32, u32) -> u32) -> Option<Output> {
+        let idx = (reg.hex_value() as usize) - 1;
+        self.registers[idx] = op(self.registers[idx], value);
+        None
+    }
+
+    fn mmov(&mut self, reg: Register, addr: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        self.registers[idx] = self.read_memory(addr)?;
+        Ok(None)
+    }
+
+    fn mset(&mut self, reg: Register, addr: u32) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        let value = self.registers[idx];
+        self.write_memory(addr, value)?;
+        Ok(None)
+    }
+
+    fn push(&mut self, reg: Register) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        let value = self.registers[idx];
+        self.push_word(value)?;
+        Ok(None)
+    }
+
+    fn pop(&mut self, reg: Register) -> Result<Option<Output>, Fault> {
+        let idx = (reg.hex_value() as usize) - 1;
+        self.registers[idx] = self.pop_word()?;
+        Ok(None)
+    }
+
+    fn input(&mut self, reg: Register, port: u32) -> Option<Output> {
+        let idx = (reg.hex_value() as usize) - 1;
+        let value = self.bus.read(port).unwrap_or(0);
+        self.registers[idx] = value;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record_input(port, value);
+        }
+        None
+    }
+
+    /// Writes to a port with a device attached go straight to the device; anything else
+    /// is handed back to the host through `ZResult::output`.
     fn out(&mut self, reg: Register, value: u32) -> Option<Output> {
         let idx = (reg.hex_value() as usize) - 1;
         let port = self.registers[idx];
-        Some(Output::new(port, value))
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record_output(port, value);
+        }
+        if self.bus.write(port, value) {
+            None
+        } else {
+            Some(Output::new(port, value))
+        }
     }
 
-    pub fn load_program(&mut self, filename: &str) {
-        let mut file = File::open(filename).unwrap();
-        let mut file_buffer = Vec::new();
-        file.read_to_end(&mut file_buffer).unwrap();
-        self.program = Cursor::new(file_buffer);
+    /// Replaces the program with a binary image file, like `load_bytes`.
+    pub fn load_program(&mut self, filename: &str) -> Result<(), LoadError> {
+        self.load_bytes(&read_file(filename)?).map_err(LoadError::Image)
+    }
+
+    /// Replaces the program with a binary image and resets the machine. On error the
+    /// current program is left alone.
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), ImageError> {
+        let program = Program::from_bytes(bytes)?;
+        self.load(&program);
+        Ok(())
+    }
 
+    /// Replaces the program and resets the machine. Attached devices stay attached.
+    pub fn load(&mut self, program: &Program) {
+        self.program = program.clone();
         self.reset();
     }
 
+    /// Like `load`, but A-E and X-Z keep their values, so a reloaded program can pick up
+    /// where the old one left off.
+    pub fn load_keeping_registers(&mut self, program: &Program) {
+        let registers = self.registers;
+        self.load(program);
+        self.registers = registers;
+    }
+
+    pub fn snapshot(&self) -> ZpuSnapshot {
+        ZpuSnapshot {
+            program: self.program.clone(),
+            registers: self.registers,
+            pc: self.pc,
+            sp: self.sp,
+            cmp_flag: self.cmp_flag,
+            signed_cmp_flag: self.signed_cmp_flag,
+            zero_flag: self.zero_flag,
+            carry_flag: self.carry_flag,
+            overflow_flag: self.overflow_flag,
+            running: self.running,
+            fault: self.fault,
+            cycles: self.cycles,
+            memory: self.memory.clone(),
+            stack: self.stack.clone(),
+            pending_irqs: self.interrupts.pending,
+            interrupts_enabled: self.interrupts.enabled,
+            vector_base: self.interrupts.vector_base,
+        }
+    }
+
+    /// Puts the machine back in the state the snapshot was taken in, including the memory
+    /// and stack sizes. Devices, breakpoints and any trace in progress are left alone.
+    pub fn restore(&mut self, snapshot: &ZpuSnapshot) {
+        self.program = snapshot.program.clone();
+        self.decoded = encoding::decode_program(&self.program.code);
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.cmp_flag = snapshot.cmp_flag;
+        self.signed_cmp_flag = snapshot.signed_cmp_flag;
+        self.zero_flag = snapshot.zero_flag;
+        self.carry_flag = snapshot.carry_flag;
+        self.overflow_flag = snapshot.overflow_flag;
+        self.running = snapshot.running;
+        self.fault = snapshot.fault;
+        self.cycles = snapshot.cycles;
+        self.memory = snapshot.memory.clone();
+        self.stack = snapshot.stack.clone();
+        self.interrupts.pending = snapshot.pending_irqs;
+        self.interrupts.enabled = snapshot.interrupts_enabled;
+        self.interrupts.vector_base = snapshot.vector_base;
+        self.clock.reset();
+        self.debugger.break_pending = false;
+    }
+
+    pub fn from_snapshot(snapshot: &ZpuSnapshot) -> ZPU {
+        let mut zpu = ZPU::from_program(&snapshot.program);
+        zpu.restore(snapshot);
+        zpu
+    }
+
+    /// Starts recording every step into a new trace, replacing any trace in progress.
+    pub fn start_trace(&mut self) {
+        self.tracer = Some(Tracer::new(&self.program));
+    }
+
+    pub fn stop_trace(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Runs one instruction, or enters an interrupt handler if one is pending. Entering a
+    /// handler is a step of its own, so its first instruction can be stopped at.
     pub fn step(&mut self) -> ZResult {
         if self.running {
-            let seek_val = (self.pc as u64) * 4;
-            self.program.seek(SeekFrom::Start(seek_val)).unwrap();
-            let value = self.program.read_u32::<LittleEndian>().unwrap();
-            let inst = Opcode::from_value((value >> 16) as u16);
-            let reg1 = Register::from_value((value >> 8) as u8);
-            let reg2 = Register::from_value((value) as u8);
-
-            let mut data = None;
-            if reg2 == Register::Null {
-                data = Some(self.program.read_u32::<LittleEndian>().unwrap());
-                //println!("{:?} {:?}, {:?}", inst, reg1, data.unwrap());
-            } else {
-                //println!("{:?} {:?}, {:?}", inst, reg1, reg2);
+            let pc = self.pc;
+            let before = self.tracer.as_ref().map(|_| TraceState::of(self));
+            let executed = match self.service_interrupt() {
+                Ok(true) => Ok(None),
+                Ok(false) => self.fetch_execute(),
+                Err(fault) => Err(fault),
+            };
+            let result = match executed.or_else(|fault| self.trap(fault).map(|_| None)) {
+                Ok(output) => ZResult::new(self.running, output),
+                Err(fault) => {
+                    self.running = false;
+                    self.fault = Some(fault);
+                    ZResult::faulted(fault)
+                },
+            };
+            if let Some(before) = before {
+                let after = TraceState::of(self);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.record_step(pc, &before, &after);
+                }
             }
-
-            let result = self.execute(inst, reg1, reg2, data);
-            return ZResult::new(self.running, result);
+            result
         } else {
-            return ZResult::new(self.running, None);
+            ZResult::new(self.running, None)
         }
     }
 
-    pub fn execute(&mut self, inst: Opcode, reg1: Register, reg2: Register, data: Option<u32>) -> Option<Output> {
-        let val;
-        if reg2 == Register::Null {
-            if data.is_some() {
-                let data = data.unwrap();
-                val = data;
-                self.pc += 2;
-            } else {
-                panic!("Invalid instruction being executed!");
+    fn watched_value(&self, watch: Watch) -> u32 {
+        match watch {
+            Watch::Register(Register::Null) => 0,
+            Watch::Register(reg) => self.registers[(reg.hex_value() - 1) as usize],
+            Watch::Memory(addr) => self.memory.get(addr as usize).cloned().unwrap_or(0),
+        }
+    }
+
+    /// Runs one step, ignoring breakpoints. Reports the first watchpoint it changed,
+    /// a halt or a fault, or else whatever the instruction output.
+    pub fn debug_step(&mut self) -> Stop {
+        if !self.running {
+            return match self.fault {
+                Some(fault) => Stop::Fault(fault),
+                None => Stop::Halted,
+            };
+        }
+
+        self.debugger.break_pending = false;
+        let before: Vec<u32> = self.debugger.watchpoints.iter().map(|watch| self.watched_value(*watch)).collect();
+        let result = self.step();
+        if let Some(fault) = result.fault {
+            return Stop::Fault(fault);
+        }
+        for (watch, old) in self.debugger.watchpoints.iter().zip(before) {
+            let new = self.watched_value(*watch);
+            if new != old {
+                return Stop::Watchpoint(*watch, old, new);
             }
-        } else {
-            self.pc += 1;
-            val = self.registers[(reg2.hex_value() - 1) as usize];
         }
+        if !result.running {
+            return Stop::Halted;
+        }
+        Stop::Stepped(result.output)
+    }
+
+    /// Steps until something the debugger cares about happens, or `max_steps` run out.
+    fn run_until(&mut self, max_steps: usize, done: &dyn Fn(&ZPU) -> bool) -> Stop {
+        if self.debugger.break_pending && self.debugger.is_breakpoint(self.pc) {
+            self.debugger.break_pending = false;
+            return Stop::Breakpoint(self.pc);
+        }
+        for _ in 0..max_steps {
+            match self.debug_step() {
+                Stop::Stepped(None) => (),
+                Stop::Stepped(Some(output)) => {
+                    self.debugger.break_pending = self.debugger.is_breakpoint(self.pc);
+                    return Stop::Output(output);
+                },
+                stop => return stop,
+            }
+            if done(self) {
+                return Stop::Stepped(None);
+            }
+            if self.debugger.is_breakpoint(self.pc) {
+                return Stop::Breakpoint(self.pc);
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Continues until a breakpoint, a watchpoint, an OUT the host has to handle, a halt or
+    /// a fault. A breakpoint at the current PC doesn't stop it straight away.
+    pub fn resume(&mut self, max_steps: usize) -> Stop {
+        self.run_until(max_steps, &|_| false)
+    }
+
+    /// Runs instructions until they have taken `max_cycles` cycles, stopping early for an
+    /// OUT the host has to handle, a halt, a fault, or a breakpoint. The last instruction
+    /// may take the total past `max_cycles`. Like `resume`, a breakpoint at the current PC
+    /// doesn't stop it straight away. Watchpoints are only checked if there are any, since
+    /// they need the slower `debug_step`.
+    pub fn run(&mut self, max_cycles: usize) -> RunOutcome {
+        let end = self.cycles.saturating_add(max_cycles as u64);
+        if !self.debugger.watchpoints.is_empty() {
+            // Every instruction takes at least a cycle, so `max_cycles` steps is always enough.
+            return match self.run_until(max_cycles, &|zpu| zpu.cycles >= end) {
+                Stop::Stepped(Some(output)) | Stop::Output(output) => RunOutcome::Output(output),
+                Stop::Stepped(None) | Stop::Limit => RunOutcome::Budget,
+                Stop::Breakpoint(addr) => RunOutcome::Breakpoint(addr),
+                Stop::Watchpoint(watch, old, new) => RunOutcome::Watchpoint(watch, old, new),
+                Stop::Halted => RunOutcome::Halted,
+                Stop::Fault(fault) => RunOutcome::Fault(fault),
+            };
+        }
+        if self.debugger.break_pending && self.debugger.is_breakpoint(self.pc) {
+            self.debugger.break_pending = false;
+            return RunOutcome::Breakpoint(self.pc);
+        }
+        self.debugger.break_pending = false;
+
+        let breakpoints = !self.debugger.breakpoints.is_empty();
+        while self.cycles < end {
+            if !self.running {
+                break;
+            }
+            let result = self.step();
+            if let Some(fault) = result.fault {
+                return RunOutcome::Fault(fault);
+            }
+            if let Some(output) = result.output {
+                self.debugger.break_pending = breakpoints && self.debugger.is_breakpoint(self.pc);
+                return RunOutcome::Output(output);
+            }
+            if breakpoints && result.running && self.debugger.is_breakpoint(self.pc) {
+                return RunOutcome::Breakpoint(self.pc);
+            }
+        }
+        match (self.running, self.fault) {
+            (true, _) => RunOutcome::Budget,
+            (false, Some(fault)) => RunOutcome::Fault(fault),
+            (false, None) => RunOutcome::Halted,
+        }
+    }
+
+    /// Runs the ZPU for `nanos` of wall time at `clock.hz`. Cycles it doesn't get to use
+    /// because it stopped for an OUT are kept for the next call; stopping for anything
+    /// else forgets them.
+    pub fn advance(&mut self, nanos: u64) -> RunOutcome {
+        let owed = self.clock.tick(nanos);
+        if owed <= 0 && self.running {
+            return RunOutcome::Budget;
+        }
+        let start = self.cycles;
+        let outcome = self.run(owed.max(0) as usize);
+        self.clock.spend(self.cycles - start);
+        match outcome {
+            RunOutcome::Budget | RunOutcome::Output(_) => (),
+            _ => self.clock.reset(),
+        }
+        outcome
+    }
+
+    /// Like `debug_step`, except a CALL runs until it has returned.
+    pub fn step_over(&mut self, max_steps: usize) -> Stop {
+        let is_call = encoding::decode(&self.program.code, self.pc).is_ok_and(|inst| inst.opcode == Opcode::Call);
+        if !is_call {
+            return self.debug_step();
+        }
+        let sp = self.sp;
+        self.run_until(max_steps, &|zpu| zpu.sp == sp)
+    }
+
+    /// The registers, flags, PC and SP, and the next instruction, one group per line.
+    pub fn dump(&self) -> String {
+        let next = match encoding::decode(&self.program.code, self.pc) {
+            Ok(inst) => disassembler::format_inst(&inst, &self.program.symbols),
+            Err(_) => String::from("??"),
+        };
+        let state = match self.fault {
+            Some(fault) => format!("FAULT {:?}", fault),
+            None if self.running => String::from("RUNNING"),
+            None => String::from("HALTED"),
+        };
+
+        let mut out = format!("PC {:04x}  SP {:04x}  {}\n", self.pc, self.sp, state);
+        out.push_str(&format!("> {}\n", next));
+        for (idx, reg) in REGISTERS.iter().enumerate() {
+            out.push_str(&format!("{} {:08x}", reg.name().to_uppercase(), self.registers[idx]));
+            out.push_str(if idx % 4 == 3 { "\n" } else { "  " });
+        }
+        out.push_str(&format!("CMP {}  SCMP {}  ZERO {}  CARRY {}  OVER {}  IE {}", self.cmp_flag, self.signed_cmp_flag,
+                              self.zero_flag as u32, self.carry_flag as u32, self.overflow_flag as u32,
+                              self.interrupts.enabled as u32));
+        out
+    }
+
+    /// Runs the instruction at PC from the decoded copy of the program.
+    fn fetch_execute(&mut self) -> Result<Option<Output>, Fault> {
+        match self.decoded.get(self.pc as usize) {
+            Some(&DecodedInst::Valid(inst)) => {
+                self.cycles += inst.opcode.cycles() as u64;
+                self.execute(inst.opcode, inst.reg1, inst.reg2, inst.imm)
+            },
+            Some(&DecodedInst::Invalid(fault)) => Err(fault),
+            None => Err(Fault::PcOutOfRange(self.pc)),
+        }
+    }
+
+    pub fn execute(&mut self, inst: Opcode, reg1: Register, reg2: Register, data: Option<u32>) -> Result<Option<Output>, Fault> {
+        if reg1 == Register::Null && inst.operands().uses_reg1() {
+            return Err(Fault::BadRegister(0));
+        }
+        let val = if let Some(data) = data {
+            self.pc = self.pc.wrapping_add(2);
+            data
+        } else if reg2 != Register::Null {
+            self.pc = self.pc.wrapping_add(1);
+            self.registers[(reg2.hex_value() - 1) as usize]
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+            0
+        };
 
         let output = match inst {
             Opcode::NoOp => None,
@@ -363,20 +1475,41 @@ impl ZPU {
             Opcode::Add => self.add(reg1, val),
             Opcode::Subtract => self.sub(reg1, val),
             Opcode::Multiply => self.mul(reg1, val),
-            Opcode::Divide => self.div(reg1, val),
+            Opcode::Divide => self.div(reg1, val)?,
+            Opcode::Modulo => self.modulo(reg1, val)?,
+            Opcode::Negate => self.neg(reg1),
+            Opcode::SignedMultiply => self.imul(reg1, val),
+            Opcode::SignedDivide => self.idiv(reg1, val)?,
+            Opcode::SignedModulo => self.imod(reg1, val)?,
+            Opcode::ShiftRightArithmetic => self.sar(reg1, val),
+            Opcode::And => self.bitwise(reg1, val, |a, b| a & b),
+            Opcode::Or => self.bitwise(reg1, val, |a, b| a | b),
+            Opcode::XOr => self.bitwise(reg1, val, |a, b| a ^ b),
+            Opcode::Not => self.bitwise(reg1, val, |a, _| !a),
             Opcode::Jump => self.jump(val),
             Opcode::IfEqual => self.je(val),
             Opcode::IfNotEqual => self.jn(val),
             Opcode::IfGreater => self.jg(val),
             Opcode::IfLess => self.jl(val),
+            Opcode::IfGreaterSigned => self.jgs(val),
+            Opcode::IfLessSigned => self.jls(val),
             Opcode::IfZero => self.jz(val),
             Opcode::Compare => self.cmp(reg1, val),
+            Opcode::MemoryMove => self.mmov(reg1, val)?,
+            Opcode::MemorySet => self.mset(reg1, val)?,
+            Opcode::Push => self.push(reg1)?,
+            Opcode::Pop => self.pop(reg1)?,
+            Opcode::Call => self.call(val)?,
+            Opcode::Return => self.ret()?,
+            Opcode::EnableInterrupts => { self.interrupts.enabled = true; None },
+            Opcode::DisableInterrupts => { self.interrupts.enabled = false; None },
+            Opcode::InterruptReturn => self.iret()?,
+            Opcode::In => self.input(reg1, val),
             Opcode::Out => self.out(reg1, val),
             Opcode::Halt => { self.running = false; None},
-            _ => { println!("[EXEC] Not yet handled!"); None},
         };
 
         //println!("[{:?}] {:?} | [PC] {} | [FLAGS] C: {}, Z: {}", inst, self.registers, self.pc, self.cmp_flag, self.zero_flag);
-        return output;
+        Ok(output)
     }
 }
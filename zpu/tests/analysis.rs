@@ -0,0 +1,196 @@
+extern crate zpu;
+
+use std::path::Path;
+use std::thread;
+
+use zpu::analysis::{self, Cfg, Lint, LintKind};
+use zpu::assembler::{self, Program};
+use zpu::encoding::{self, Instruction};
+use zpu::zpu::{Opcode, Register};
+
+const SHIP_PORTS: [u32; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+fn assemble(source: &str) -> Program {
+    assembler::assemble(source).unwrap()
+}
+
+fn kinds(source: &str) -> Vec<(u32, LintKind)> {
+    analysis::lint(&assemble(source), Some(&SHIP_PORTS)).into_iter().map(|lint| (lint.addr, lint.kind)).collect()
+}
+
+#[test]
+fn builds_blocks() {
+    let cfg = Cfg::build(&assemble("
+        mov a, 3
+    loop:
+        sub a, 1
+        cmp a, 0
+        jn loop
+        hlt
+    "));
+    let blocks: Vec<(u32, u32, Vec<u32>)> = cfg.blocks.values().map(|block| (block.start, block.end(), block.succs.clone())).collect();
+    assert_eq!(blocks, vec![(0, 2, vec![2]), (2, 8, vec![2, 8]), (8, 9, vec![])]);
+}
+
+#[test]
+fn unreachable_code() {
+    assert_eq!(kinds("
+        mov a, 1
+        hlt
+        mov b, 2
+        out a, b
+    "), vec![(3, LintKind::Unreachable(6))]);
+
+    // Handlers in the vector table and labels whose address is taken count as reachable.
+    assert_eq!(kinds("
+        mov a, handler
+        mset [4080], a
+        ei
+    spin:
+        jmp spin
+    vectored:
+        iret
+    handler:
+        iret
+    .data
+        .org 4079
+        .word vectored
+    "), vec![]);
+}
+
+#[test]
+fn read_before_write() {
+    assert_eq!(kinds("add a, 1\nmov b, c"), vec![
+        (0, LintKind::ReadBeforeWrite(Register::A)),
+        (2, LintKind::ReadBeforeWrite(Register::C)),
+    ]);
+    assert_eq!(kinds("
+        in a, 7
+        cmp a, 0
+        je skip
+        mov b, 1
+    skip:
+        out a, b
+        out a, b
+    "), vec![(8, LintKind::ReadBeforeWrite(Register::B))]);
+
+    // Registers are set up for a function by its caller, and may be changed by it.
+    assert_eq!(kinds("
+        mov a, 5
+        call double
+        out b, a
+        hlt
+    double:
+        add a, a
+        mov b, 0
+        ret
+    "), vec![]);
+}
+
+#[test]
+fn jump_into_instruction() {
+    let lints = analysis::lint(&assemble("
+    start:
+        mov a, 5
+        jmp 1
+    "), None);
+    // The immediate 5, run as an instruction, brings lints of its own.
+    let lint = lints.iter().find(|lint| lint.addr == 2).unwrap();
+    assert_eq!(*lint, Lint {
+        addr: 2,
+        label: Some("start+2".to_string()),
+        kind: LintKind::JumpIntoInstruction(1, 0),
+    });
+    assert_eq!(lint.to_string(), "0002 (start+2): jump to 0001 lands inside the instruction at 0000");
+}
+
+#[test]
+fn infinite_loops() {
+    assert_eq!(kinds("
+        mov a, 0
+    loop:
+        inc a
+        jmp loop
+    "), vec![(2, LintKind::InfiniteLoop)]);
+    assert_eq!(kinds("
+        mov a, 0
+    loop:
+        inc a
+        out a, a
+        jmp loop
+    "), vec![]);
+    assert_eq!(kinds("
+        mov a, 0
+    loop:
+        inc a
+        cmp a, 10
+        jn loop
+    "), vec![]);
+    // Waiting for an interrupt.
+    assert_eq!(kinds("ei\nspin: jmp spin"), vec![]);
+}
+
+#[test]
+fn long_chains_of_blocks() {
+    // A chain of blocks each branching to the next, searched on a small stack, which a
+    // recursive search would run out of.
+    let mut code = Vec::new();
+    for idx in 0..5_000 {
+        encoding::encode(&Instruction::new(Opcode::IfEqual, Register::Null, Register::Null, Some(idx * 2 + 2)), &mut code);
+    }
+    let end = code.len() as u32;
+    encoding::encode(&Instruction::new(Opcode::Jump, Register::Null, Register::Null, Some(end)), &mut code);
+    let program = Program { code, ..Default::default() };
+    let lints = thread::Builder::new()
+        .stack_size(128 * 1024)
+        .spawn(move || analysis::lint(&program, None))
+        .unwrap()
+        .join()
+        .unwrap();
+    let lints: Vec<(u32, LintKind)> = lints.into_iter().map(|lint| (lint.addr, lint.kind)).collect();
+    assert_eq!(lints, vec![(end, LintKind::InfiniteLoop)]);
+}
+
+#[test]
+fn unmapped_ports() {
+    let source = "
+        mov x, 9
+        mov a, 1
+        out x, a
+        mov x, 2
+        out x, a
+    ";
+    assert_eq!(kinds(source), vec![(4, LintKind::UnmappedPort(9))]);
+    assert_eq!(analysis::lint(&assemble(source), None), vec![]);
+
+    // The port isn't known after paths with different ports meet.
+    assert_eq!(kinds("
+        in a, 7
+        mov x, 8
+        cmp a, 0
+        je send
+        mov x, 9
+    send:
+        out x, a
+    "), vec![]);
+}
+
+#[test]
+fn out_without_a_port_register() {
+    // OUT to register 0 faults instead of writing a port, so there is no port to check.
+    let mut program = Program::new();
+    encoding::encode(&Instruction::new(Opcode::Out, Register::Null, Register::A, None), &mut program.code);
+    encoding::encode(&Instruction::new(Opcode::Halt, Register::Null, Register::Null, None), &mut program.code);
+    assert_eq!(analysis::lint(&program, Some(&SHIP_PORTS)), vec![]);
+}
+
+#[test]
+fn example_programs_are_clean() {
+    for path in &["../programs/hello.asm", "tests/asm/power.asm"] {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+        let source = std::fs::read_to_string(&path).unwrap();
+        let dir = path.parent().unwrap();
+        let program = assembler::assemble_with(&source, &mut |file| assembler::read_include(dir, file)).unwrap();
+        assert_eq!(analysis::lint(&program, Some(&SHIP_PORTS)), vec![], "{}", path.display());
+    }
+}
@@ -0,0 +1,191 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::zpu::Fault;
+
+use common::run_to_halt;
+
+#[test]
+fn wraps_and_sets_carry() {
+    let zpu = run_to_halt("mov a, 0xffffffff\nadd a, 2\nhlt");
+    assert_eq!(zpu.registers[0], 1);
+    assert!(zpu.carry_flag && !zpu.overflow_flag);
+
+    let zpu = run_to_halt("sub a, 1\nhlt");
+    assert_eq!(zpu.registers[0], 0xffff_ffff);
+    assert!(zpu.carry_flag && !zpu.overflow_flag);
+
+    let zpu = run_to_halt("mov a, 0x10000\nmul a, 0x10001\nhlt");
+    assert_eq!(zpu.registers[0], 0x10000);
+    assert!(zpu.carry_flag && zpu.overflow_flag);
+
+    let zpu = run_to_halt("mov a, 0xffffffff\ninc a\nhlt");
+    assert_eq!(zpu.registers[0], 0);
+    assert!(zpu.carry_flag);
+
+    let zpu = run_to_halt("mov a, 2\nadd a, 3\nhlt");
+    assert!(!zpu.carry_flag && !zpu.overflow_flag);
+}
+
+#[test]
+fn signed_overflow() {
+    let zpu = run_to_halt("mov a, 0x7fffffff\ninc a\nhlt");
+    assert_eq!(zpu.registers[0], 0x8000_0000);
+    assert!(!zpu.carry_flag && zpu.overflow_flag);
+
+    let zpu = run_to_halt("mov a, 0x80000000\nsub a, 1\nhlt");
+    assert!(!zpu.carry_flag && zpu.overflow_flag);
+}
+
+#[test]
+fn shifts_past_the_word() {
+    let zpu = run_to_halt("mov a, 0x80000001\nshl a, 1\nmov b, 0x80000001\nshr b, 1\nhlt");
+    assert_eq!((zpu.registers[0], zpu.registers[1]), (2, 0x4000_0000));
+    assert!(zpu.carry_flag);
+
+    let zpu = run_to_halt("mov a, 0xffffffff\nshl a, 32\nmov b, 0xffffffff\nshr b, 40\nhlt");
+    assert_eq!((zpu.registers[0], zpu.registers[1]), (0, 0));
+    assert!(!zpu.carry_flag);
+
+    let zpu = run_to_halt("mov a, 1\nshl a, 32\nhlt");
+    assert!(zpu.carry_flag);
+}
+
+#[test]
+fn divide_by_zero_halts_with_a_fault() {
+    let mut zpu = run_to_halt("mov a, 7\ndiv a, b\nhlt");
+    assert!(!zpu.running);
+    assert_eq!(zpu.fault, Some(Fault::DivideByZero));
+    assert_eq!(Fault::DivideByZero.code(), 4);
+    assert_eq!(zpu.registers[0], 7);
+    assert_eq!(zpu.step().fault, None);
+}
+
+#[test]
+fn data_is_not_a_fault_handler() {
+    // The string fills more than the first 17 words of memory.
+    let zpu = run_to_halt("
+        .data
+    msg:
+        .string \"hello world, this is a message\"
+        .code
+        mov a, 10
+        div a, 0
+    ");
+    assert_eq!(zpu.fault, Some(Fault::DivideByZero));
+    assert_eq!((zpu.registers[0], zpu.sp), (10, 0));
+}
+
+#[test]
+fn divide_by_zero_traps_to_the_fault_vector() {
+    let zpu = run_to_halt("
+        .data
+        .org 4095
+        .word on_fault
+        .code
+        mov a, 7
+        div a, 0
+        out a, a
+        hlt
+    on_fault:
+        inc c
+        iret
+    ");
+    assert!(!zpu.running);
+    assert_eq!(zpu.fault, None);
+    assert_eq!((zpu.registers[0], zpu.registers[2]), (7, 1));
+    assert_eq!(zpu.sp, 0);
+}
+
+#[test]
+fn bitwise() {
+    let zpu = run_to_halt("
+        mov a, 0b1100
+        and a, 0b1010
+        mov b, 0b1100
+        or b, 0b1010
+        mov c, 0b1100
+        xor c, 0b1010
+        mov d, 0x0000ffff
+        not d
+        hlt
+    ");
+    assert_eq!(zpu.registers[..4], [0b1000, 0b1110, 0b0110, 0xffff_0000]);
+}
+
+#[test]
+fn modulo_and_negate() {
+    let zpu = run_to_halt("mov a, 17\nmod a, 5\nmov b, 5\nneg b\nhlt");
+    assert_eq!((zpu.registers[0], zpu.registers[1] as i32), (2, -5));
+    assert!(zpu.carry_flag && !zpu.overflow_flag);
+
+    let zpu = run_to_halt("mov a, 0x80000000\nneg a\nhlt");
+    assert_eq!(zpu.registers[0], 0x8000_0000);
+    assert!(zpu.overflow_flag);
+
+    assert_eq!(run_to_halt("mod a, 0\nhlt").fault, Some(Fault::DivideByZero));
+}
+
+#[test]
+fn signed_multiply_and_divide() {
+    let zpu = run_to_halt("
+        mov a, -7
+        imul a, 3
+        mov b, -7
+        idiv b, 2
+        mov c, -7
+        imod c, 2
+        mov d, 7
+        imod d, -2
+        hlt
+    ");
+    let signed: Vec<i32> = zpu.registers[..4].iter().map(|reg| *reg as i32).collect();
+    assert_eq!(signed, vec![-21, -3, -1, 1]);
+
+    let zpu = run_to_halt("mov a, -0x80000000\nidiv a, -1\nhlt");
+    assert_eq!(zpu.registers[0], 0x8000_0000);
+    assert!(zpu.overflow_flag);
+
+    let zpu = run_to_halt("mov a, 0x40000000\nimul a, 2\nhlt");
+    assert!(zpu.overflow_flag && zpu.carry_flag);
+
+    assert_eq!(run_to_halt("idiv a, 0\nhlt").fault, Some(Fault::DivideByZero));
+    assert_eq!(run_to_halt("imod a, 0\nhlt").fault, Some(Fault::DivideByZero));
+}
+
+#[test]
+fn arithmetic_shift_right() {
+    let zpu = run_to_halt("mov a, -16\nsar a, 2\nmov b, -1\nsar b, 40\nmov c, 16\nsar c, 2\nhlt");
+    assert_eq!(zpu.registers[..3], [(-4i32) as u32, 0xffff_ffff, 4]);
+    assert!(!zpu.carry_flag);
+
+    let zpu = run_to_halt("mov a, 3\nsar a, 1\nhlt");
+    assert_eq!(zpu.registers[0], 1);
+    assert!(zpu.carry_flag);
+}
+
+#[test]
+fn signed_jumps() {
+    // -5 is below 3 signed, but above it unsigned.
+    let zpu = run_to_halt("
+        mov a, -5
+        cmp a, 3
+        jls less
+        hlt
+    less:
+        mov b, 1
+        jg above
+        hlt
+    above:
+        mov c, 1
+        cmp c, -1
+        jgs greater
+        hlt
+    greater:
+        mov d, 1
+        hlt
+    ");
+    assert_eq!(zpu.registers[1..4], [1, 1, 1]);
+    assert_eq!((zpu.cmp_flag, zpu.signed_cmp_flag), (-1, 1));
+}
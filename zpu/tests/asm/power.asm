@@ -0,0 +1,57 @@
+; Reports the ship's power on the terminal until it drops below LOW_POWER, then turns
+; the engine off. IRQ 0 counts ticks. Run from tests/harness.rs.
+.include "ports.inc"
+.equ LOW_POWER, 20
+
+.data
+ticks:
+    .word 0
+    .org VECTORS
+    .word tick
+
+.code
+start:
+    mov x, TERM_NUM
+    mov y, ENGINE
+    mov z, 1
+    out y, z
+    ei
+check:
+    in a, SHIP_POWER
+    out x, a
+    cmp a, LOW_POWER
+    jl shutdown
+    jmp check
+shutdown:
+    mov z, 0
+    out y, z
+    hlt
+
+tick:
+    push a
+    mmov a, [ticks]
+    inc a
+    mset [ticks], a
+    pop a
+    iret
+
+; @test shuts the engine off on low power
+; @input 7 = 80, 50, 10
+; @out 2 1
+; @out 0 80
+; @out 0 50
+; @out 0 10
+; @out 2 0
+; @reg a = 10
+; @mem ticks = 0
+
+; @test counts ticks
+; @input 7 = 90, 90, 90, 90, 5
+; @irq 0 at 10
+; @irq 0 at 30
+; @reg a = 5
+; @mem ticks = 2
+
+; @test shuts down once the power reading stops
+; @cycles 1000
+; @reg a = 0
@@ -0,0 +1,493 @@
+extern crate zpu;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use zpu::assembler::{self, AssembleError, MAX_SECTION_WORDS};
+use zpu::encoding;
+use zpu::interrupt::DEFAULT_VECTOR_BASE;
+use zpu::zpu::{Opcode, Register, OPCODES, ZPU};
+
+use common::run_to_halt;
+
+fn reg(zpu: &ZPU, reg: Register) -> u32 {
+    zpu.registers[(reg.hex_value() - 1) as usize]
+}
+
+/// A source line exercising each opcode, in `OPCODES` order.
+fn sample(op: Opcode) -> &'static str {
+    match op {
+        Opcode::NoOp => "nop",
+        Opcode::Jump => "jmp 7",
+        Opcode::Halt => "hlt",
+        Opcode::Increment => "inc a",
+        Opcode::ShiftRight => "shr a, 1",
+        Opcode::ShiftLeft => "shl a, b",
+        Opcode::Move => "mov a, 5",
+        Opcode::Add => "add a, b",
+        Opcode::Subtract => "sub a, 2",
+        Opcode::Multiply => "mul c, d",
+        Opcode::Divide => "div e, 3",
+        Opcode::IfEqual => "je 1",
+        Opcode::IfNotEqual => "jn 2",
+        Opcode::MemoryMove => "mmov a, [4]",
+        Opcode::MemorySet => "mset [b], c",
+        Opcode::XOr => "xor x, y",
+        Opcode::In => "in z, 7",
+        Opcode::Out => "out a, 1",
+        Opcode::Push => "push x",
+        Opcode::Pop => "pop y",
+        Opcode::IfZero => "jz 3",
+        Opcode::IfGreater => "jg 4",
+        Opcode::IfLess => "jl 5",
+        Opcode::Compare => "cmp a, b",
+        Opcode::Call => "call 6",
+        Opcode::Return => "ret",
+        Opcode::EnableInterrupts => "ei",
+        Opcode::DisableInterrupts => "di",
+        Opcode::InterruptReturn => "iret",
+        Opcode::And => "and a, 0xff",
+        Opcode::Or => "or b, c",
+        Opcode::Not => "not d",
+        Opcode::Modulo => "mod e, 10",
+        Opcode::Negate => "neg x",
+        Opcode::SignedMultiply => "imul y, z",
+        Opcode::SignedDivide => "idiv a, -4",
+        Opcode::SignedModulo => "imod b, a",
+        Opcode::ShiftRightArithmetic => "sar c, 3",
+        Opcode::IfGreaterSigned => "jgs 8",
+        Opcode::IfLessSigned => "jls 9",
+    }
+}
+
+#[test]
+fn every_opcode_round_trips() {
+    for op in OPCODES.iter() {
+        let source = sample(*op);
+        let program = assembler::assemble(source).unwrap();
+        let inst = encoding::decode(&program.code, 0).unwrap();
+        assert_eq!(inst.opcode, *op, "{}", source);
+
+        let halt = encoding::decode(&program.code, inst.size()).unwrap();
+        assert_eq!(halt.opcode, Opcode::Halt, "{}", source);
+        assert_eq!(program.code.len() as u32, inst.size() + 1, "{}", source);
+    }
+}
+
+#[test]
+fn operands_round_trip() {
+    let program = assembler::assemble("mov c, 1234\nmset [x], y\nmmov z, [77]").unwrap();
+
+    let mov = encoding::decode(&program.code, 0).unwrap();
+    assert_eq!((mov.reg1, mov.reg2, mov.imm), (Register::C, Register::Null, Some(1234)));
+    let mset = encoding::decode(&program.code, 2).unwrap();
+    assert_eq!((mset.reg1, mset.reg2, mset.imm), (Register::Y, Register::X, None));
+    let mmov = encoding::decode(&program.code, 3).unwrap();
+    assert_eq!((mmov.reg1, mmov.reg2, mmov.imm), (Register::Z, Register::Null, Some(77)));
+}
+
+#[test]
+fn arithmetic() {
+    let zpu = run_to_halt("mov a, 6\nmov b, 3\nadd a, b\nsub a, 1\nmul a, 4\ndiv a, b\ninc a\nshl a, 2\nshr a, 1");
+    assert_eq!(reg(&zpu, Register::A), 22);
+}
+
+#[test]
+fn memory_and_stack() {
+    let zpu = run_to_halt("mov a, 9\nmset [3], a\nmov b, 3\nmmov c, [b]\npush c\npop d");
+    assert_eq!(zpu.memory[3], 9);
+    assert_eq!(reg(&zpu, Register::C), 9);
+    assert_eq!(reg(&zpu, Register::D), 9);
+    assert_eq!(zpu.sp, 0);
+}
+
+#[test]
+fn compare_and_branch() {
+    let source = "
+        mov a, 5
+        cmp a, 5
+        je equal
+        hlt
+    equal:
+        cmp a, 9
+        jl less
+        hlt
+    less:
+        cmp a, 1
+        jg greater
+        hlt
+    greater:
+        cmp a, 3
+        jn not_equal
+        hlt
+    not_equal:
+        cmp a, a
+        jz done
+        hlt
+    done:
+        mov b, 1
+    ";
+    assert_eq!(reg(&run_to_halt(source), Register::B), 1);
+}
+
+#[test]
+fn call_and_ret() {
+    let source = "
+        mov a, 4
+        call double
+        call double
+        jmp end
+    double:
+        add a, a
+        ret
+    end:
+    ";
+    let zpu = run_to_halt(source);
+    assert_eq!(reg(&zpu, Register::A), 16);
+    assert_eq!(zpu.sp, 0);
+}
+
+#[test]
+fn out_and_in_without_devices() {
+    let program = assembler::assemble("mov a, 3\nout a, 12\nin b, 3").unwrap();
+    let mut zpu = ZPU::from_program(&program);
+    zpu.step();
+    let output = zpu.step().output.unwrap();
+    assert_eq!((output.port, output.data), (3, 12));
+    zpu.registers[1] = 55;
+    zpu.step();
+    assert_eq!(reg(&zpu, Register::B), 0);
+}
+
+#[test]
+fn interrupt_instructions() {
+    let source = "
+        mov a, handler
+        mset [4079], a
+        ei
+    spin:
+        cmp b, 0
+        je spin
+        di
+        hlt
+    handler:
+        mov b, 1
+        iret
+    ";
+    let program = assembler::assemble(source).unwrap();
+    let mut zpu = ZPU::from_program(&program);
+    for _ in 0..6 {
+        zpu.step();
+    }
+    zpu.raise_irq(0);
+    for _ in 0..20 {
+        if !zpu.step().running {
+            break;
+        }
+    }
+    assert!(!zpu.running);
+    assert_eq!(reg(&zpu, Register::B), 1);
+    assert!(!zpu.interrupts.enabled);
+}
+
+#[test]
+fn nop_and_halt() {
+    let zpu = run_to_halt("nop\nmov a, 1\nhlt\nmov a, 2");
+    assert_eq!(reg(&zpu, Register::A), 1);
+}
+
+#[test]
+fn labels_use_exact_word_addresses() {
+    let source = "
+    start:
+        jmp forward     ; 0, 2 words
+        inc a           ; 2
+    back:
+        mov b, 1        ; 3
+        hlt             ; 5
+    forward: jmp back   ; 6
+    ";
+    let program = assembler::assemble(source).unwrap();
+    assert_eq!(program.symbol("back").unwrap().addr, 3);
+    assert_eq!(program.symbol("forward").unwrap().addr, 6);
+    assert_eq!(encoding::decode(&program.code, 0).unwrap().imm, Some(6));
+    assert_eq!(encoding::decode(&program.code, 6).unwrap().imm, Some(3));
+
+    let zpu = run_to_halt(source);
+    assert_eq!(reg(&zpu, Register::A), 0);
+    assert_eq!(reg(&zpu, Register::B), 1);
+}
+
+#[test]
+fn comments_are_stripped() {
+    let zpu = run_to_halt("; header comment\nmov a, 2 ; trailing comment\n\n   ; indented\nadd a, 3;no space");
+    assert_eq!(reg(&zpu, Register::A), 5);
+}
+
+#[test]
+fn errors_are_collected_with_positions() {
+    let errors = assembler::assemble("mov a, 1\nfoo a\nmov q, 1\n  jmp nowhere\nhlt a").unwrap_err();
+    assert_eq!(errors.len(), 4);
+    assert!(matches!(errors[0], AssembleError::UnknownMnemonic(span, _) if span.line == 2 && span.column == 1));
+    assert!(matches!(errors[1], AssembleError::ExpectedRegister(span, _) if span.line == 3 && span.column == 5));
+    assert!(matches!(errors[2], AssembleError::UndefinedLabel(span, _) if span.line == 4 && span.column == 7));
+    assert!(matches!(errors[3], AssembleError::OperandCount(_, _, 0)));
+}
+
+#[test]
+fn error_messages_start_with_the_position() {
+    let errors = assembler::assemble("mov a, 1\nfoo a\nmov q, 1\n  jmp nowhere\nhlt a").unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(messages, vec![
+        "2:1: unknown instruction `foo`",
+        "3:5: expected a register, found `q`",
+        "4:7: label `nowhere` is never defined",
+        "5:1: `hlt` takes no operands",
+    ]);
+    assert_eq!((errors[2].line(), errors[2].column()), (4, 7));
+}
+
+#[test]
+fn assemble_program_writes_nothing_on_errors() {
+    let source = env::temp_dir().join(format!("zpu-assembler-{}-bad.asm", process::id()));
+    let image = source.with_extension("bin");
+    fs::write(&source, "mov a, 1\nfoo\nmov b, 2\nbar").unwrap();
+
+    let result = assembler::assemble_program(source.to_str().unwrap(), image.to_str().unwrap()).unwrap();
+    assert!(!result.is_ok());
+    assert_eq!(result.errors.iter().map(|err| err.line()).collect::<Vec<_>>(), vec![2, 4]);
+    assert!(!image.exists());
+}
+
+#[test]
+fn assemble_program_reports_io_errors() {
+    let source = env::temp_dir().join(format!("zpu-assembler-{}-missing.asm", process::id()));
+    let image = source.with_extension("bin");
+    let err = assembler::assemble_program(source.to_str().unwrap(), image.to_str().unwrap()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    fs::write(&source, "hlt").unwrap();
+    let dir = env::temp_dir().join(format!("zpu-assembler-{}-missing", process::id())).join("out.bin");
+    assert!(assembler::assemble_program(source.to_str().unwrap(), dir.to_str().unwrap()).is_err());
+    fs::remove_file(&source).unwrap();
+}
+
+#[test]
+fn duplicate_labels_are_rejected() {
+    let errors = assembler::assemble("a1:\na1:\n").unwrap_err();
+    assert!(matches!(errors[0], AssembleError::DuplicateLabel(span, _) if span.line == 2));
+}
+
+#[test]
+fn number_literals() {
+    let zpu = run_to_halt("mov a, 0x1F\nmov b, 0b101\nmov c, 'A'\nmov d, ' '\nmov e, '\\n'\nmov x, 1_000");
+    assert_eq!(reg(&zpu, Register::A), 31);
+    assert_eq!(reg(&zpu, Register::B), 5);
+    assert_eq!(reg(&zpu, Register::C), 65);
+    assert_eq!(reg(&zpu, Register::D), 32);
+    assert_eq!(reg(&zpu, Register::E), 10);
+    assert_eq!(reg(&zpu, Register::X), 1000);
+
+    let zpu = run_to_halt("mov a, -1\nmov b, -0x10\nmov c, -0x80000000");
+    assert_eq!(reg(&zpu, Register::A), 0xffff_ffff);
+    assert_eq!(reg(&zpu, Register::B), (-16i32) as u32);
+    assert_eq!(reg(&zpu, Register::C), 0x8000_0000);
+    for bad in ["-", "--1", "-0x80000001"].iter() {
+        assert!(assembler::assemble(&format!("mov a, {}", bad)).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn constants() {
+    let source = "
+        .equ TERM_NUM, 0
+        .define LIMIT 0x10
+        .equ ALIAS, LIMIT
+        mov a, TERM_NUM
+        mov b, alias
+    ";
+    let zpu = run_to_halt(source);
+    assert_eq!(reg(&zpu, Register::A), 0);
+    assert_eq!(reg(&zpu, Register::B), 16);
+}
+
+#[test]
+fn data_section() {
+    let source = "
+        .data
+    table:
+        .word 1, 0x2, three
+    msg:
+        .string \"Hi, there\"
+        .org 32
+    late:
+        .word late
+        .code
+        .equ three, 3
+    start:
+        mov a, table
+        mmov b, [msg]
+        mmov c, [late]
+    ";
+    let program = assembler::assemble(source).unwrap();
+    assert_eq!(&program.data[..3], &[1, 2, 3]);
+    assert_eq!(program.data[3], 'H' as u32);
+    assert_eq!(program.data[5], ',' as u32);
+    assert_eq!(program.data[12], 0);
+    assert_eq!(program.data[32], 32);
+    assert_eq!(program.entry, 0);
+
+    let zpu = run_to_halt(source);
+    assert_eq!(reg(&zpu, Register::A), 0);
+    assert_eq!(reg(&zpu, Register::B), 'H' as u32);
+    assert_eq!(reg(&zpu, Register::C), 32);
+}
+
+#[test]
+fn org_in_code() {
+    let program = assembler::assemble("jmp start\n.org 8\nstart: mov a, 1").unwrap();
+    assert_eq!(program.entry, 8);
+    assert_eq!(encoding::decode(&program.code, 8).unwrap().opcode, Opcode::Move);
+    assert_eq!(encoding::decode(&program.code, 10).unwrap().opcode, Opcode::Halt);
+
+    let errors = assembler::assemble("mov a, 1\n.org 1").unwrap_err();
+    assert!(matches!(errors[0], AssembleError::OrgBackwards(_, 1)));
+}
+
+#[test]
+fn sections_are_limited() {
+    let errors = assembler::assemble(".org 0xFFFFFFFF\nnop").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], AssembleError::SectionTooLarge(span, 0xFFFF_FFFF) if span.line == 1));
+
+    let errors = assembler::assemble("jmp far\n.org 0x40000000\nfar: hlt").unwrap_err();
+    assert!(matches!(errors[0], AssembleError::SectionTooLarge(span, 0x4000_0000) if span.line == 2));
+
+    // Running off the end is reported once, at the line that does it.
+    let source = format!(".org {}\nnop\nmov a, 1\nnop\nnop", MAX_SECTION_WORDS - 2);
+    let errors = assembler::assemble(&source).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], AssembleError::SectionTooLarge(span, end) if span.line == 3 && end == MAX_SECTION_WORDS + 1));
+    assert_eq!(errors[0].to_string(), format!("3:1: address {} is past the end of the section, which holds {} words",
+                                              MAX_SECTION_WORDS + 1, MAX_SECTION_WORDS));
+
+    let errors = assembler::assemble(&format!(".data\n.org {}\n.word 1, 2", MAX_SECTION_WORDS - 1)).unwrap_err();
+    assert!(matches!(errors[0], AssembleError::SectionTooLarge(span, _) if span.line == 3));
+
+    // Filling a section exactly is fine.
+    let program = assembler::assemble(&format!(".data\n.org {}\n.word 7\n.code\n.org {}", MAX_SECTION_WORDS - 1, MAX_SECTION_WORDS)).unwrap();
+    assert_eq!(program.data.len() as u32, MAX_SECTION_WORDS);
+    assert_eq!(program.data[MAX_SECTION_WORDS as usize - 1], 7);
+}
+
+#[test]
+fn data_stays_out_of_the_vector_table() {
+    let errors = assembler::assemble(&format!(".data\n.org {}\n.word 1\n.word 2, 3\n.word 4", DEFAULT_VECTOR_BASE - 2)).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], AssembleError::IntoVectorTable(span, DEFAULT_VECTOR_BASE) if span.line == 4));
+    assert_eq!(errors[0].to_string(), "4:1: data runs into the interrupt vector table at 4079, use `.org 4079` to fill it in");
+
+    // Filling the table in on purpose, and code, are fine.
+    let program = assembler::assemble(&format!("
+        .org {}
+        hlt
+    handler:
+        iret
+        .data
+        .word 1
+        .org {}
+        .word handler, handler
+    ", DEFAULT_VECTOR_BASE + 1, DEFAULT_VECTOR_BASE)).unwrap();
+    assert_eq!(program.data[DEFAULT_VECTOR_BASE as usize + 1], DEFAULT_VECTOR_BASE + 2);
+}
+
+#[test]
+fn includes() {
+    let mut files = |name: &str| match name {
+        "ports.inc" => Some(String::from(".equ TURRET_ON, 5\n.include \"more.inc\"")),
+        "more.inc" => Some(String::from(".equ ENGINE, 2\nbad a, b")),
+        _ => None,
+    };
+    let errors = assembler::assemble_with(".include \"ports.inc\"\nmov a, ENGINE\n.include \"gone.inc\"", &mut files).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    match errors[0] {
+        AssembleError::InInclude(span, ref file, ref inner) => {
+            assert_eq!((span.line, file.as_str()), (1, "ports.inc"));
+            assert!(matches!(**inner, AssembleError::InInclude(span, _, ref inner)
+                if span.line == 2 && matches!(**inner, AssembleError::UnknownMnemonic(span, _) if span.line == 2)));
+        },
+        ref err => panic!("unexpected error {:?}", err),
+    }
+    assert!(matches!(errors[1], AssembleError::IncludeNotFound(span, _) if span.line == 3));
+
+    let program = assembler::assemble_with(".include \"ports.inc\"", &mut |name: &str| match name {
+        "ports.inc" => Some(String::from(".equ ENGINE, 2\nmov a, ENGINE")),
+        _ => None,
+    }).unwrap();
+    assert_eq!(encoding::decode(&program.code, 0).unwrap().imm, Some(2));
+}
+
+#[test]
+fn macros() {
+    let source = "
+        .macro pulse port
+            out \\port, 1
+            out \\port, 0
+        .endm
+        .macro count_down reg, from
+            mov \\reg, \\from
+        %%loop:
+            sub \\reg, 1
+            cmp \\reg, 0
+            jn %%loop
+        .endm
+        mov d, 6
+    top: pulse d
+        count_down a, 3
+        count_down b, 2
+    ";
+    let program = assembler::assemble(source).unwrap();
+    assert_eq!(program.symbol("top").unwrap().addr, 2);
+    assert!(program.symbol("count_down__2_loop").is_some());
+    assert!(program.symbol("count_down__3_loop").is_some());
+
+    let mut zpu = ZPU::from_program(&program);
+    zpu.step();
+    let pulses: Vec<u32> = (0..2).map(|_| zpu.step().output.unwrap().data).collect();
+    assert_eq!(pulses, vec![1, 0]);
+
+    let zpu = run_to_halt(source);
+    assert_eq!(reg(&zpu, Register::A), 0);
+    assert_eq!(reg(&zpu, Register::B), 0);
+}
+
+#[test]
+fn macro_errors() {
+    let source = "
+.macro load reg, value
+    mov \\reg, \\value
+.endm
+    load q, 1
+    load a
+.macro forever
+    forever
+.endm
+    forever
+.macro open";
+    let errors = assembler::assemble(source).unwrap_err();
+    assert_eq!(errors.len(), 4);
+    match errors[0] {
+        AssembleError::InMacro(call, def, ref name, ref inner) => {
+            assert_eq!((call.line, def.line, name.as_str()), (5, 2, "load"));
+            assert!(matches!(**inner, AssembleError::ExpectedRegister(span, _) if span.line == 3));
+        },
+        ref err => panic!("unexpected error {:?}", err),
+    }
+    assert!(matches!(errors[1], AssembleError::OperandCount(span, _, 2) if span.line == 6));
+    assert!(matches!(errors[2], AssembleError::InMacro(call, _, _, _) if call.line == 10));
+    assert!(matches!(errors[3], AssembleError::UnterminatedMacro(span, _) if span.line == 11));
+}
@@ -0,0 +1,93 @@
+extern crate zpu;
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use zpu::bus::{Bus, Device, Latch};
+
+use common::{load, run};
+
+/// Answers each read with its port number, and logs every write.
+struct Recorder {
+    writes: Rc<RefCell<Vec<(u32, u32)>>>,
+}
+
+impl Device for Recorder {
+    fn read(&mut self, port: u32) -> u32 {
+        port * 10
+    }
+
+    fn write(&mut self, port: u32, data: u32) {
+        self.writes.borrow_mut().push((port, data));
+    }
+}
+
+#[test]
+fn in_and_out_go_to_the_device() {
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    let mut zpu = load("mov c, 4\nin a, 4\nin b, 5\nout c, a\nmov c, 5\nout c, 9");
+    zpu.bus.attach(&[4, 5], Box::new(Recorder { writes: writes.clone() }));
+    assert!(run(&mut zpu).output.is_none());
+    assert_eq!(&zpu.registers[..2], &[40, 50]);
+    assert_eq!(*writes.borrow(), vec![(4, 40), (5, 9)]);
+}
+
+#[test]
+fn unmapped_ports() {
+    let mut zpu = load("mov a, 1\nin a, 3\nmov c, 3\nout c, 7");
+    let output = run(&mut zpu).output.unwrap();
+    assert_eq!((output.port, output.data), (3, 7));
+    assert_eq!(zpu.registers[0], 0);
+}
+
+#[test]
+fn latch_is_shared_with_the_host() {
+    let value = Rc::new(Cell::new(12));
+    let mut zpu = load("in a, 2\nadd a, 1\nmov c, 2\nout c, a");
+    zpu.bus.attach(&[2], Box::new(Latch::new(value.clone())));
+    assert!(run(&mut zpu).output.is_none());
+    assert_eq!(zpu.registers[0], 13);
+    assert_eq!(value.get(), 13);
+}
+
+#[test]
+fn attach_and_detach() {
+    let first = Rc::new(Cell::new(1));
+    let second = Rc::new(Cell::new(2));
+    let mut bus = Bus::new();
+    bus.attach(&[1, 2], Box::new(Latch::new(first.clone())));
+    bus.attach(&[2], Box::new(Latch::new(second.clone())));
+    assert_eq!(bus.mapped_ports(), vec![1, 2]);
+    assert_eq!(bus.read(1), Some(1));
+    assert_eq!(bus.read(2), Some(2));
+
+    bus.detach(2);
+    assert!(!bus.is_mapped(2));
+    assert_eq!(bus.read(2), None);
+    assert!(!bus.write(2, 5));
+    assert!(bus.write(1, 5));
+    assert_eq!(first.get(), 5);
+    assert_eq!(second.get(), 2);
+}
+
+#[test]
+fn devices_without_ports_are_dropped() {
+    let first = Rc::new(Cell::new(1));
+    let second = Rc::new(Cell::new(2));
+    let mut bus = Bus::new();
+    bus.attach(&[1, 2], Box::new(Latch::new(first.clone())));
+    bus.attach(&[3], Box::new(Latch::new(second.clone())));
+
+    bus.detach(1);
+    assert_eq!(Rc::strong_count(&first), 2);
+    bus.detach(2);
+    assert_eq!(Rc::strong_count(&first), 1);
+    assert_eq!(bus.read(3), Some(2));
+
+    bus.attach(&[3], Box::new(Latch::new(first.clone())));
+    assert_eq!(Rc::strong_count(&second), 1);
+    assert_eq!(bus.read(3), Some(1));
+    assert_eq!(bus.mapped_ports(), vec![3]);
+}
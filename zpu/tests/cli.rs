@@ -0,0 +1,128 @@
+extern crate zpu;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use zpu::assembler;
+use zpu::bus::{Device, Script};
+
+/// A scratch path for this test, so tests running in parallel don't share files.
+fn scratch(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("zpu-cli-{}-{}", std::process::id(), name))
+}
+
+fn asm_with(source: &str, name: &str, args: &[&str]) -> (Output, PathBuf) {
+    let input = scratch(&format!("{}.asm", name));
+    let output = scratch(&format!("{}.bin", name));
+    fs::write(&input, source).unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_zpu-asm"))
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .args(args)
+        .output()
+        .unwrap();
+    (result, output)
+}
+
+fn asm(source: &str, name: &str) -> (Output, PathBuf) {
+    asm_with(source, name, &[])
+}
+
+fn run(image: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_zpu-run")).arg(image).args(args).output().unwrap()
+}
+
+#[test]
+fn script_device_reads_in_order() {
+    let mut script = Script::new(&[4, 5]);
+    assert_eq!(script.read(0), 4);
+    assert_eq!(script.read(0), 5);
+    assert_eq!(script.read(0), 0);
+}
+
+#[test]
+fn assembles_and_runs() {
+    let (result, image) = asm("
+        in a, 7
+        in b, 7
+        add a, b
+        mov c, 0
+        out c, a
+        inc c
+        out c, b
+        hlt
+    ", "runs");
+    assert!(result.status.success());
+
+    let result = run(&image, &["--input", "7=0x10,-1"]);
+    assert_eq!(result.status.code(), Some(0));
+    assert_eq!(String::from_utf8(result.stdout).unwrap(), "port 0: 15\nport 1: 4294967295\n");
+}
+
+#[test]
+fn reports_assembly_errors() {
+    let (result, image) = asm("mov a, 1\nfrob a\n", "errors");
+    assert_eq!(result.status.code(), Some(1));
+    assert!(!image.exists());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let mut lines = stderr.lines();
+    assert!(lines.next().unwrap().contains("errors.asm:2:1: "));
+    assert_eq!(lines.next(), Some("    frob a"));
+    assert_eq!(lines.next(), Some("    ^^^^"));
+}
+
+#[test]
+fn exit_codes() {
+    let (_, image) = asm("spin: jmp spin", "spin");
+    let result = run(&image, &["--cycles", "100"]);
+    assert_eq!(result.status.code(), Some(3));
+
+    let (_, image) = asm("mov a, 1\ndiv a, 0", "fault");
+    let result = run(&image, &[]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8(result.stderr).unwrap().contains("fault 4"));
+
+    let result = run(&image, &["--cycles"]);
+    assert_eq!(result.status.code(), Some(2));
+    let result = run(&scratch("missing.bin"), &[]);
+    assert_eq!(result.status.code(), Some(1));
+}
+
+#[test]
+fn prints_warnings() {
+    let source = "mov x, 9\nout x, a";
+    let (result, image) = asm_with(source, "warnings", &["--ports", "0,1,2"]);
+    assert!(result.status.success());
+    assert!(image.exists());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let warnings: Vec<&str> = stderr.lines().collect();
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].ends_with("warnings.asm: warning: 0002: OUT to port 9, which has nothing mapped to it"));
+    assert!(warnings[1].ends_with("warnings.asm: warning: 0002: `a` is read before anything is written to it"));
+
+    let (result, _) = asm(source, "warnings-no-ports");
+    assert_eq!(String::from_utf8(result.stderr).unwrap().lines().count(), 1);
+}
+
+#[test]
+fn optimises_only_when_asked() {
+    let source = "mov a, 0\nadd a, 5\nout a, a";
+    let (result, image) = asm_with(source, "optimised", &["--optimise", "--report"]);
+    assert!(String::from_utf8(result.stderr).unwrap().contains("optimised.asm:1:1: folded `mov` of 0 and `add` into one `mov`"));
+    let optimised = fs::read(&image).unwrap();
+    let (result, image) = asm_with(source, "optimised-short", &["-O"]);
+    assert!(result.status.success());
+    assert_eq!(fs::read(&image).unwrap(), optimised);
+
+    let (result, image) = asm_with(source, "unoptimised", &["--report"]);
+    assert!(result.stderr.is_empty());
+    let unoptimised = fs::read(&image).unwrap();
+    assert!(unoptimised.len() > optimised.len());
+    assert_eq!(unoptimised, assembler::assemble(source).unwrap().to_bytes());
+
+    let (result, _) = asm_with(source, "no-optimise", &["--no-optimise"]);
+    assert_eq!(result.status.code(), Some(2));
+}
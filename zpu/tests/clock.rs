@@ -0,0 +1,145 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::clock::Clock;
+use zpu::debugger::Watch;
+use zpu::snapshot::ZpuSnapshot;
+use zpu::zpu::{Opcode, Output, Register, RunOutcome, ZPU, INTERRUPT_CYCLES};
+
+use common::load;
+
+const MS: u64 = 1_000_000;
+
+#[test]
+fn instructions_cost_cycles() {
+    assert_eq!(Opcode::Add.cycles(), 1);
+    assert_eq!(Opcode::Push.cycles(), 2);
+    assert_eq!(Opcode::Multiply.cycles(), 3);
+    assert_eq!(Opcode::Out.cycles(), 4);
+    assert_eq!(Opcode::Divide.cycles(), 8);
+
+    let mut zpu = load("mov a, 10\ndiv a, 2\npush a\nhlt");
+    zpu.run(1000);
+    assert_eq!(zpu.cycles, 1 + 8 + 2 + 1);
+
+    // The DIV starts inside the budget and finishes past it.
+    let mut zpu = load("mov a, 10\ndiv a, 2\npush a\nhlt");
+    assert_eq!(zpu.run(2), RunOutcome::Budget);
+    assert_eq!(zpu.cycles, 9);
+}
+
+#[test]
+fn interrupts_cost_cycles() {
+    let mut zpu = load("
+        mov a, handler
+        mset [4079], a
+        ei
+    spin:
+        jmp spin
+    handler:
+        iret
+    ");
+    zpu.run(4);
+    let before = zpu.cycles;
+    zpu.raise_irq(0);
+    zpu.step();
+    assert_eq!(zpu.cycles - before, INTERRUPT_CYCLES as u64);
+    zpu.step();
+    assert_eq!(zpu.cycles - before, INTERRUPT_CYCLES as u64 + Opcode::InterruptReturn.cycles() as u64);
+}
+
+#[test]
+fn clock_turns_time_into_cycles() {
+    let mut clock = Clock::new(1000);
+    assert_eq!(clock.tick(MS / 2), 0);
+    assert_eq!(clock.tick(MS / 2), 1);
+    clock.spend(3);
+    assert_eq!(clock.tick(MS), -1);
+    // At most a second is owed, less the cycle still owed back.
+    assert_eq!(clock.tick(10_000 * MS), 999);
+    clock.reset();
+    assert_eq!(clock.tick(0), 0);
+}
+
+#[test]
+fn advance_is_independent_of_frame_times() {
+    let source = "spin: inc a\njmp spin";
+    let mut steady = load(source);
+    steady.clock.hz = 50_000;
+    for _ in 0..60 {
+        steady.advance(16_666_667);
+    }
+    let mut uneven = load(source);
+    uneven.clock.hz = 50_000;
+    for frame in 0..120 {
+        uneven.advance(if frame % 2 == 0 { 3_333_333 } else { 13_333_334 });
+    }
+    assert_eq!(steady.cycles, 50_000);
+    assert_eq!(uneven.cycles, steady.cycles);
+    assert_eq!(uneven.registers, steady.registers);
+
+    let mut fast = load(source);
+    fast.clock.hz = 100_000;
+    for _ in 0..60 {
+        fast.advance(16_666_667);
+    }
+    assert_eq!(fast.cycles, 2 * steady.cycles);
+}
+
+#[test]
+fn output_keeps_the_cycles_owed() {
+    let mut zpu = load("
+    loop:
+        out a, a
+        inc a
+        jmp loop
+    ");
+    zpu.clock.hz = 1000;
+    assert_eq!(zpu.advance(12 * MS), RunOutcome::Output(Output::new(0, 0)));
+    assert_eq!(zpu.advance(0), RunOutcome::Output(Output::new(1, 1)));
+    assert_eq!(zpu.advance(0), RunOutcome::Budget);
+    assert_eq!(zpu.cycles, 12);
+
+    let mut zpu = load("hlt");
+    assert_eq!(zpu.advance(100 * MS), RunOutcome::Halted);
+    assert_eq!(zpu.advance(100 * MS), RunOutcome::Halted);
+}
+
+#[test]
+fn watchpoints_keep_the_cycle_budget() {
+    let source = "mov a, 10\ndiv a, 2\npush a\nmov b, 1\nhlt";
+    let mut zpu = load(source);
+    zpu.debugger.watch(Watch::Register(Register::C));
+    assert_eq!(zpu.run(2), RunOutcome::Budget);
+    assert_eq!(zpu.cycles, 9);
+    assert_eq!(zpu.run(0), RunOutcome::Budget);
+    assert_eq!(zpu.cycles, 9);
+
+    zpu.debugger.watch(Watch::Register(Register::B));
+    assert_eq!(zpu.run(1000), RunOutcome::Watchpoint(Watch::Register(Register::B), 0, 1));
+    assert_eq!(zpu.cycles, 9 + 2 + 1);
+}
+
+#[test]
+fn cycles_survive_a_snapshot() {
+    let mut zpu = load("
+    loop:
+        inc a
+        mul a, 3
+        out a, a
+        jmp loop
+    ");
+    zpu.clock.hz = 1000;
+    while zpu.advance(7 * MS) != RunOutcome::Budget {}
+    let snapshot = ZpuSnapshot::from_bytes(&zpu.snapshot().to_bytes()).unwrap();
+    let mut restored = ZPU::from_snapshot(&snapshot);
+    restored.clock.hz = 1000;
+    assert_eq!(restored.cycles, zpu.cycles);
+
+    zpu.restore(&snapshot);
+    for _ in 0..20 {
+        assert_eq!(restored.advance(3 * MS), zpu.advance(3 * MS));
+        assert_eq!(restored.cycles, zpu.cycles);
+    }
+}
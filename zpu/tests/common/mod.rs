@@ -0,0 +1,31 @@
+//! Fixtures shared by the integration tests. Each test file only uses some of them.
+#![allow(dead_code)]
+
+use zpu::assembler;
+use zpu::zpu::{ZResult, ZPU};
+
+pub fn load(source: &str) -> ZPU {
+    ZPU::from_program(&assembler::assemble(source).unwrap())
+}
+
+/// Steps until the ZPU halts or hands a write back to the host.
+pub fn run(zpu: &mut ZPU) -> ZResult {
+    for _ in 0..1000 {
+        let result = zpu.step();
+        if result.output.is_some() || !result.running {
+            return result;
+        }
+    }
+    panic!("program did not halt");
+}
+
+/// Assembles `source` and steps it until it halts, carrying on past any output.
+pub fn run_to_halt(source: &str) -> ZPU {
+    let mut zpu = load(source);
+    for _ in 0..1000 {
+        if !zpu.step().running {
+            return zpu;
+        }
+    }
+    panic!("program did not halt");
+}
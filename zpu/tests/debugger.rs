@@ -0,0 +1,114 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::debugger::{Stop, Watch};
+use zpu::zpu::{Fault, Output, Register, ZPU};
+
+use common::load;
+
+const SOURCE: &str = "
+    mov a, 1
+    call bump
+    out b, a
+    mov c, 3
+loop:
+    inc c
+    mset [10], c
+    cmp c, 6
+    jn loop
+    hlt
+bump:
+    inc a
+    inc a
+    ret
+";
+
+fn addr(zpu: &ZPU, label: &str) -> u32 {
+    zpu.program.symbol(label).unwrap().addr
+}
+
+#[test]
+fn breakpoints() {
+    let mut zpu = load(SOURCE);
+    let target = addr(&zpu, "loop");
+    zpu.debugger.add_breakpoint(target);
+
+    assert_eq!(zpu.resume(100), Stop::Output(Output::new(0, 3)));
+    assert_eq!(zpu.resume(100), Stop::Breakpoint(target));
+    assert_eq!(zpu.registers[2], 3);
+    assert_eq!(zpu.resume(100), Stop::Breakpoint(target));
+    assert_eq!(zpu.registers[2], 4);
+
+    assert!(!zpu.debugger.toggle_breakpoint(target));
+    assert_eq!(zpu.resume(100), Stop::Halted);
+    assert_eq!(zpu.registers[2], 6);
+    assert_eq!(zpu.resume(100), Stop::Halted);
+}
+
+#[test]
+fn watchpoints() {
+    let mut zpu = load(SOURCE);
+    zpu.debugger.watch(Watch::Register(Register::A));
+    zpu.debugger.watch(Watch::Memory(10));
+
+    assert_eq!(zpu.resume(100), Stop::Watchpoint(Watch::Register(Register::A), 0, 1));
+    assert_eq!(zpu.resume(100), Stop::Watchpoint(Watch::Register(Register::A), 1, 2));
+    assert_eq!(zpu.resume(100), Stop::Watchpoint(Watch::Register(Register::A), 2, 3));
+    assert!(zpu.debugger.unwatch(Watch::Register(Register::A)));
+    assert_eq!(zpu.resume(100), Stop::Output(Output::new(0, 3)));
+    assert_eq!(zpu.resume(100), Stop::Watchpoint(Watch::Memory(10), 0, 4));
+}
+
+#[test]
+fn step_and_step_over() {
+    let mut zpu = load(SOURCE);
+    assert_eq!(zpu.debug_step(), Stop::Stepped(None));
+    assert_eq!(zpu.step_over(100), Stop::Stepped(None));
+    assert_eq!(zpu.registers[0], 3);
+    assert_eq!((zpu.pc, zpu.sp), (4, 0));
+    assert_eq!(zpu.step_over(100), Stop::Stepped(Some(Output::new(0, 3))));
+
+    let mut zpu = load(SOURCE);
+    zpu.debug_step();
+    assert_eq!(zpu.debug_step(), Stop::Stepped(None));
+    assert_eq!(zpu.pc, addr(&zpu, "bump"));
+
+    let mut zpu = load(SOURCE);
+    zpu.debug_step();
+    let inside = addr(&zpu, "bump") + 1;
+    zpu.debugger.add_breakpoint(inside);
+    assert_eq!(zpu.step_over(100), Stop::Breakpoint(inside));
+    assert_eq!(zpu.step_over(1), Stop::Stepped(None));
+    assert_eq!(zpu.resume(1), Stop::Limit);
+}
+
+#[test]
+fn faults_stop_the_debugger() {
+    let mut zpu = load("pop a");
+    assert_eq!(zpu.resume(10), Stop::Fault(Fault::StackUnderflow));
+    assert_eq!(zpu.debug_step(), Stop::Fault(Fault::StackUnderflow));
+}
+
+#[test]
+fn dump() {
+    let mut zpu = load(SOURCE);
+    zpu.debug_step();
+    let dump = zpu.dump();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], "PC 0002  SP 0000  RUNNING");
+    assert_eq!(lines[1], "> call bump");
+    assert_eq!(lines[2], "A 00000001  B 00000000  C 00000000  D 00000000");
+    assert_eq!(lines[4], "CMP 0  SCMP 0  ZERO 0  CARRY 0  OVER 0  IE 0");
+}
+
+#[test]
+fn breakpoint_after_output() {
+    let mut zpu = load(SOURCE);
+    zpu.debugger.add_breakpoint(5);
+    assert_eq!(zpu.resume(100), Stop::Output(Output::new(0, 3)));
+    assert_eq!(zpu.resume(100), Stop::Breakpoint(5));
+    assert_eq!(zpu.registers[2], 0);
+    assert_eq!(zpu.resume(1), Stop::Limit);
+    assert_eq!(zpu.registers[2], 3);
+}
@@ -0,0 +1,76 @@
+extern crate zpu;
+
+use zpu::assembler;
+use zpu::disassembler;
+use zpu::encoding::{Instruction, Section};
+use zpu::zpu::{Opcode, Register};
+
+const SOURCE: &str = "
+    .data
+msg:
+    .string \"ok\"
+    .code
+start:
+    mov a, 42
+    mmov b, [msg]
+    mset [c], d
+loop:
+    add a, b
+    jmp loop
+";
+
+#[test]
+fn instructions_use_symbols() {
+    let program = assembler::assemble(SOURCE).unwrap();
+    let lines = disassembler::disassemble(&program);
+    let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+    assert_eq!(text, vec![
+        "mov a, 42", "mmov b, [msg]", "mset [c], d", "add a, b", "jmp loop", "hlt",
+        ".word 111", ".word 107", ".word 0",
+    ]);
+
+    assert_eq!((lines[1].addr, lines[1].words.len()), (2, 2));
+    assert_eq!(lines[0].labels, vec![String::from("start")]);
+    assert_eq!(lines[4].labels, Vec::<String>::new());
+    assert_eq!(lines[3].labels, vec![String::from("loop")]);
+    assert_eq!(lines[6].section, Section::Data);
+    assert_eq!(lines[6].labels, vec![String::from("msg")]);
+}
+
+#[test]
+fn without_symbols() {
+    let mut program = assembler::assemble(SOURCE).unwrap();
+    program.symbols.clear();
+    let listing = disassembler::listing(&program);
+    assert!(listing.contains("jmp 5"));
+    assert!(listing.contains("mmov b, [0]"));
+    assert!(listing.contains("0002  000d8020 00000000     mmov"));
+}
+
+#[test]
+fn reassembles_to_the_same_code() {
+    let program = assembler::assemble(SOURCE).unwrap();
+    let mut source = String::new();
+    for line in disassembler::disassemble(&program).iter().filter(|line| line.section == Section::Code) {
+        for label in line.labels.iter() {
+            source.push_str(&format!("{}:\n", label));
+        }
+        source.push_str(&line.text);
+        source.push('\n');
+    }
+    source.push_str(".data\nmsg:\n");
+
+    let again = assembler::assemble(&source).unwrap();
+    assert_eq!(again.code[..again.code.len() - 1], program.code[..]);
+}
+
+#[test]
+fn truncated_immediate() {
+    let mut code = Vec::new();
+    zpu::encoding::encode(&Instruction::new(Opcode::Move, Register::A, Register::Null, Some(7)), &mut code);
+    code.pop();
+    let program = zpu::encoding::Program { code, ..Default::default() };
+    let lines = disassembler::disassemble(&program);
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].text.starts_with(".word 0x6"));
+}
@@ -0,0 +1,80 @@
+extern crate zpu;
+
+use zpu::encoding::{self, ImageError, Instruction, Program, Section, Symbol, IMAGE_VERSION, IMMEDIATE_FLAG};
+use zpu::zpu::{Fault, Opcode, Register};
+
+#[test]
+fn instructions_round_trip() {
+    let opcodes = [Opcode::NoOp, Opcode::Move, Opcode::MemorySet, Opcode::Call, Opcode::InterruptReturn];
+    let registers = [Register::A, Register::C, Register::X, Register::Z];
+    for (idx, opcode) in opcodes.iter().enumerate() {
+        let reg1 = registers[idx % 4];
+        let reg2 = registers[(idx + 1) % 4];
+        for inst in &[
+            Instruction::new(*opcode, reg1, reg2, None),
+            Instruction::new(*opcode, reg1, Register::Null, Some(0xdead_beef ^ idx as u32)),
+        ] {
+            let mut code = Vec::new();
+            encoding::encode(inst, &mut code);
+            assert_eq!(code.len() as u32, inst.size());
+            assert_eq!(code[0] & IMMEDIATE_FLAG != 0, inst.imm.is_some());
+            assert_eq!(encoding::decode(&code, 0), Ok(*inst));
+        }
+    }
+}
+
+#[test]
+fn instruction_word_layout() {
+    let mut code = Vec::new();
+    encoding::encode(&Instruction::new(Opcode::Move, Register::C, Register::Null, Some(7)), &mut code);
+    encoding::encode(&Instruction::new(Opcode::Add, Register::A, Register::Z, None), &mut code);
+    let mov = (Opcode::Move.hex_value() as u32) << 16 | IMMEDIATE_FLAG | 0x30;
+    let add = (Opcode::Add.hex_value() as u32) << 16 | 0x18;
+    assert_eq!(code, vec![mov, 7, add]);
+    assert_eq!(encoding::decode(&code, 2).unwrap().reg2, Register::Z);
+    assert_eq!(encoding::decode(&code[..1], 0), Err(Fault::TruncatedImmediate(0)));
+}
+
+#[test]
+fn images_round_trip() {
+    let program = Program {
+        entry: 2,
+        code: vec![1, 2, 3, 4],
+        data: vec![5, 6],
+        symbols: vec![
+            Symbol { name: "start".to_string(), section: Section::Code, addr: 2 },
+            Symbol { name: "msg".to_string(), section: Section::Data, addr: 1 },
+        ],
+    };
+    let bytes = program.to_bytes();
+    assert_eq!(&bytes[..4], b"ZPU1");
+    assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    assert_eq!(Program::from_bytes(&Program::new().to_bytes()), Ok(Program::new()));
+}
+
+#[test]
+fn bad_images() {
+    let program = Program {
+        code: vec![1],
+        symbols: vec![Symbol { name: "x".to_string(), section: Section::Code, addr: 0 }],
+        ..Default::default()
+    };
+    let bytes = program.to_bytes();
+    for len in 0..bytes.len() {
+        assert!(Program::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+    }
+    assert_eq!(Program::from_bytes(&bytes[..bytes.len() - 1]), Err(ImageError::Truncated));
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    assert!(matches!(Program::from_bytes(&magic), Err(ImageError::BadMagic(_))));
+
+    let mut version = bytes.clone();
+    version[4] = IMAGE_VERSION as u8 + 1;
+    assert_eq!(Program::from_bytes(&version), Err(ImageError::UnsupportedVersion(IMAGE_VERSION + 1)));
+
+    // The symbol's section follows the header, the code word and its address.
+    let mut section = bytes.clone();
+    section[4 * 8] = 9;
+    assert_eq!(Program::from_bytes(&section), Err(ImageError::BadSymbol));
+}
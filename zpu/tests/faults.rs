@@ -0,0 +1,92 @@
+extern crate zpu;
+
+use zpu::assembler;
+use zpu::disassembler;
+use zpu::encoding::{self, Program, IMMEDIATE_FLAG};
+use zpu::snapshot::ZpuSnapshot;
+use zpu::zpu::{Fault, Opcode, Register, ZPU};
+
+fn word(opcode: u32, reg1: u32, reg2: u32) -> u32 {
+    (opcode << 16) | (reg1 << 4) | reg2
+}
+
+/// Steps a program made of raw words until it stops, returning the ZPU and the last result's fault.
+fn run(code: Vec<u32>) -> (ZPU, Option<Fault>) {
+    let mut zpu = ZPU::from_program(&Program { code, ..Default::default() });
+    for _ in 0..100 {
+        let result = zpu.step();
+        if !result.running {
+            return (zpu, result.fault);
+        }
+    }
+    panic!("still running");
+}
+
+#[test]
+fn decode_faults() {
+    let inc = Opcode::Increment.hex_value() as u32;
+    assert_eq!(run(vec![word(0x7777, 1, 0)]).1, Some(Fault::InvalidOpcode(0x7777)));
+    assert_eq!(run(vec![word(inc, 9, 0)]).1, Some(Fault::BadRegister(9)));
+    assert_eq!(run(vec![word(inc, 0, 0)]).1, Some(Fault::BadRegister(0)));
+    assert_eq!(run(vec![word(Opcode::Add.hex_value() as u32, 1, 15)]).1, Some(Fault::BadRegister(15)));
+    assert_eq!(run(vec![word(inc, 1, 0), word(Opcode::Move.hex_value() as u32, 1, 0) | IMMEDIATE_FLAG]).1,
+               Some(Fault::TruncatedImmediate(1)));
+
+    // No trailing HLT.
+    let (zpu, fault) = run(vec![word(inc, 1, 0), word(inc, 1, 0)]);
+    assert_eq!(fault, Some(Fault::PcOutOfRange(2)));
+    assert_eq!(zpu.fault, fault);
+    assert_eq!(zpu.registers[0], 2);
+
+    // Instructions that don't take a register can leave REG1 empty.
+    assert_eq!(run(vec![word(Opcode::Halt.hex_value() as u32, 0, 0)]).1, None);
+}
+
+#[test]
+fn jumping_out_of_the_program() {
+    let mut zpu = ZPU::from_program(&assembler::assemble("mov a, 0xffff\njmp a").unwrap());
+    let result = (0..3).map(|_| zpu.step()).last().unwrap();
+    assert!(!result.running);
+    assert_eq!(result.fault, Some(Fault::PcOutOfRange(0xffff)));
+    assert!(zpu.dump().starts_with("PC ffff  SP 0000  FAULT PcOutOfRange(65535)\n> ??"));
+}
+
+#[test]
+fn execute_checks_registers() {
+    let mut zpu = ZPU::from_program(&assembler::assemble("hlt").unwrap());
+    assert_eq!(zpu.execute(Opcode::Increment, Register::Null, Register::Null, None), Err(Fault::BadRegister(0)));
+    assert_eq!(zpu.execute(Opcode::Jump, Register::Null, Register::Null, Some(0)), Ok(None));
+}
+
+#[test]
+fn fault_codes_and_messages() {
+    let faults = [
+        Fault::MemoryOutOfBounds(5000),
+        Fault::StackOverflow,
+        Fault::StackUnderflow,
+        Fault::DivideByZero,
+        Fault::InvalidOpcode(0x7777),
+        Fault::BadRegister(9),
+        Fault::PcOutOfRange(12),
+        Fault::TruncatedImmediate(3),
+    ];
+    for (idx, fault) in faults.iter().enumerate() {
+        assert_eq!(fault.code(), idx as u32 + 1);
+
+        let mut snapshot = ZPU::from_program(&assembler::assemble("hlt").unwrap()).snapshot();
+        snapshot.fault = Some(*fault);
+        assert_eq!(ZpuSnapshot::from_bytes(&snapshot.to_bytes()).unwrap().fault, Some(*fault));
+    }
+    assert_eq!(Fault::InvalidOpcode(0x7777).to_string(), "invalid opcode 0x7777");
+    assert_eq!(Fault::BadRegister(0).to_string(), "missing register");
+    assert_eq!(Fault::PcOutOfRange(12).to_string(), "PC 12 is past the end of the program");
+}
+
+#[test]
+fn undecodable_words_disassemble_as_data() {
+    let code = vec![word(0x7777, 1, 0), word(Opcode::Halt.hex_value() as u32, 0, 0)];
+    assert_eq!(encoding::decode(&code, 0), Err(Fault::InvalidOpcode(0x7777)));
+    let lines = disassembler::disassemble(&Program { code, ..Default::default() });
+    assert_eq!(lines[0].text, ".word 0x77770010");
+    assert_eq!(lines[1].text, "hlt");
+}
@@ -0,0 +1,200 @@
+extern crate zpu;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use zpu::assembler;
+use zpu::gdbstub::{self, DATA_BASE};
+use zpu::zpu::{Output, ZPU};
+
+/// A scripted gdb: sends one packet at a time and returns the stub's reply.
+struct Client {
+    stream: TcpStream,
+    no_ack: bool,
+}
+
+impl Client {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    fn send_raw(&mut self, data: &str, checksum: u8) {
+        self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes()).unwrap();
+    }
+
+    fn reply(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        let sum = [self.read_byte(), self.read_byte()];
+        let expected = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        assert_eq!(String::from_utf8_lossy(&sum), format!("{:02x}", expected));
+        if !self.no_ack {
+            self.stream.write_all(b"+").unwrap();
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    fn send(&mut self, data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.send_raw(data, sum);
+        if !self.no_ack {
+            assert_eq!(self.read_byte(), b'+');
+        }
+        self.reply()
+    }
+}
+
+/// Runs the stub against `source` on the test thread while `script` plays gdb on another.
+fn session<F>(source: &str, script: F) -> (ZPU, Vec<Output>)
+    where F: FnOnce(&mut Client) + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut client = Client { stream, no_ack: false };
+        script(&mut client);
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut zpu = ZPU::from_program(&assembler::assemble(source).unwrap());
+    let mut outputs = Vec::new();
+    gdbstub::serve(&mut zpu, stream, &mut |output| outputs.push(output)).unwrap();
+    client.join().unwrap();
+    (zpu, outputs)
+}
+
+const SOURCE: &str = "
+    mov a, 5
+    .data
+value:
+    .word 0x11223344
+    .code
+loop:
+    out a, a
+    sub a, 1
+    cmp a, 0
+    jn loop
+done:
+    hlt
+";
+
+#[test]
+fn registers_and_memory() {
+    let (zpu, _) = session(SOURCE, |gdb| {
+        assert!(gdb.send("qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = gdb.send("qXfer:features:read:target.xml:0,2000");
+        assert!(xml.starts_with('l'));
+        for name in ["a", "b", "c", "d", "e", "x", "y", "z", "pc", "flags"].iter() {
+            assert!(xml.contains(&format!("<reg name=\"{}\"", name)), "{}", name);
+        }
+        for (bit, name) in ["ZERO", "GT", "LT", "IE", "CARRY", "OVERFLOW", "SGT", "SLT"].iter().enumerate() {
+            assert!(xml.contains(&format!("<field name=\"{}\" start=\"{}\" end=\"{}\"/>", name, bit, bit)), "{}", name);
+        }
+        assert!(gdb.send("qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+        assert_eq!(gdb.send("?"), "S05");
+        assert_eq!(gdb.send("g").len(), gdbstub::REGISTER_COUNT * 8);
+        assert_eq!(gdb.send("P1=2a000000"), "OK");
+        assert_eq!(gdb.send("p1"), "2a000000");
+        assert_eq!(gdb.send("p8"), "00000000");
+        assert_eq!(gdb.send("p20"), "E00");
+
+        assert_eq!(gdb.send(&format!("m{:x},4", DATA_BASE)), "44332211");
+        assert_eq!(gdb.send(&format!("M{:x},3:abcdef", DATA_BASE + 5)), "OK");
+        assert_eq!(gdb.send(&format!("m{:x},8", DATA_BASE + 4)), "00abcdef00000000");
+        assert_eq!(gdb.send("m4,4"), "05000000");
+        assert_eq!(gdb.send("M0,4:00000000"), "E01");
+        assert_eq!(gdb.send("m100000,4"), "E01");
+
+        gdb.send_raw("g", 0);
+        assert_eq!(gdb.read_byte(), b'-');
+        assert_eq!(gdb.send("QStartNoAckMode"), "OK");
+        gdb.no_ack = true;
+        assert_eq!(gdb.send("D"), "OK");
+    });
+    assert_eq!(zpu.registers[1], 42);
+    assert_eq!(zpu.memory[1], 0xefcd_ab00);
+}
+
+#[test]
+fn breakpoints_continue_and_step() {
+    let (zpu, outputs) = session(SOURCE, |gdb| {
+        assert_eq!(gdb.send("Z0,8,4"), "OK");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p8"), "08000000");
+        assert_eq!(gdb.send("p0"), "05000000");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p0"), "04000000");
+
+        assert_eq!(gdb.send("z0,8,4"), "OK");
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p8"), "0c000000");
+        assert_eq!(gdb.send("c"), "W00");
+        assert_eq!(gdb.send("Z1,0,4"), "");
+        gdb.send_raw("k", b'k');
+        assert_eq!(gdb.read_byte(), b'+');
+    });
+    assert!(!zpu.running);
+    let data: Vec<u32> = outputs.iter().map(|output| output.data).collect();
+    assert_eq!(data, vec![5, 4, 3, 2, 1]);
+}
+
+#[test]
+fn interrupt_while_running() {
+    let (zpu, _) = session("spin: inc a\njmp spin", |gdb| {
+        gdb.send_raw("c", b'c');
+        assert_eq!(gdb.read_byte(), b'+');
+        thread::sleep(Duration::from_millis(50));
+        gdb.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(gdb.reply(), "S02");
+        assert_eq!(gdb.send("?"), "S02");
+        assert_eq!(gdb.send("D"), "OK");
+    });
+    assert!(zpu.running);
+    assert!(zpu.registers[0] > 0);
+}
+
+#[test]
+fn pc_too_far_out_for_a_byte_address() {
+    let (zpu, _) = session("mov a, 0x40000000\njmp a", |gdb| {
+        assert_eq!(gdb.send("c"), "S0b");
+        assert_eq!(gdb.send("p8"), "xxxxxxxx");
+        let registers = gdb.send("g");
+        assert_eq!(&registers[..8], "00000040");
+        assert_eq!(&registers[64..72], "xxxxxxxx");
+        assert_eq!(registers.len(), gdbstub::REGISTER_COUNT * 8);
+        assert_eq!(gdb.send("p0"), "00000040");
+        assert_eq!(gdb.send("D"), "OK");
+    });
+    assert_eq!(zpu.pc, 0x4000_0000);
+}
+
+#[test]
+fn sp_past_the_stack_is_rejected() {
+    let (zpu, _) = session("push a\npop b", |gdb| {
+        assert_eq!(gdb.send("P9=00010000"), "OK");
+        assert_eq!(gdb.send("P9=01010000"), "E01");
+        assert_eq!(gdb.send("p9"), "00010000");
+
+        let mut registers = gdb.send("g");
+        registers.replace_range(0..8, "07000000");
+        registers.replace_range(72..80, "ffffffff");
+        assert_eq!(gdb.send(&format!("G{}", registers)), "E01");
+        assert_eq!(gdb.send("p0"), "00000000");
+        registers.replace_range(72..80, "00000000");
+        assert_eq!(gdb.send(&format!("G{}", registers)), "OK");
+        assert_eq!(gdb.send("D"), "OK");
+    });
+    assert_eq!((zpu.registers[0], zpu.sp), (7, 0));
+}
@@ -0,0 +1,119 @@
+extern crate zpu;
+
+use zpu::assembler;
+use zpu::harness::{self, End, TestCase};
+use zpu::zpu::{Output, Register};
+
+#[test]
+fn firmware_tests_pass() {
+    harness::check_file(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/asm/power.asm"));
+}
+
+#[test]
+fn parses_annotations() {
+    let tests = harness::parse_tests("
+        mov a, 1   ; @test first
+        ; @cycles 50
+        ; @input 7 = 1, 0x2, -1
+        ; @irq 3 at 20
+        ; @out 0, 5
+        ; @reg A = 1
+        ; @mem [flag] = 2
+        ; @running
+        hlt
+        ; @test
+        ; @fault 4
+    ").unwrap();
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0], TestCase {
+        name: "first".to_string(),
+        line: 2,
+        cycles: 50,
+        inputs: vec![(7, vec![1, 2, 0xFFFF_FFFF])],
+        irqs: vec![(20, 3)],
+        outputs: Some(vec![Output::new(0, 5)]),
+        registers: vec![(Register::A, 1)],
+        memory: vec![("flag".to_string(), 2)],
+        end: End::Running,
+    });
+    assert_eq!(tests[1].name, "line 11");
+    assert_eq!(tests[1].end, End::Fault(4));
+    assert_eq!(tests[1].outputs, None);
+}
+
+#[test]
+fn bad_annotations() {
+    for (source, message) in [
+        ("; @reg a = 1", "1: @reg before any @test"),
+        ("; @test\n; @reg q = 1", "2: no register 'q'"),
+        ("; @test\n; @irq 16 at 0", "2: no IRQ line 16"),
+        ("; @test\n; @input 7", "2: expected 'name = value', found '7'"),
+        ("; @test\n; @out 1", "2: expected '@out PORT DATA'"),
+        ("; @test\n; @cycles lots", "2: bad number 'lots'"),
+        ("; @test\n; @expect", "2: unknown annotation @expect"),
+    ].iter() {
+        assert_eq!(harness::parse_tests(source).unwrap_err().to_string(), *message);
+    }
+}
+
+#[test]
+fn reports_failures() {
+    let source = "
+        mov c, 1
+        out c, c
+        mov a, 7
+        hlt
+        ; @test wrong
+        ; @out 1 2
+        ; @reg a = 8
+        ; @mem 0 = 1
+        ; @mem missing = 1
+        ; @mem 5000 = 1
+        ; @running
+    ";
+    let results = harness::run_source(source).unwrap();
+    assert!(!results[0].passed());
+    assert_eq!(results[0].failures, vec![
+        "expected to still be running after 7 cycles, but it halted at PC 6".to_string(),
+        "expected output [1:2], got [1:1]".to_string(),
+        "expected a = 8, got 7".to_string(),
+        "expected [0] = 1, got 0".to_string(),
+        "no label 'missing'".to_string(),
+        "can't read [5000]: memory address 5000 is out of bounds".to_string(),
+    ]);
+}
+
+#[test]
+fn ends() {
+    let results = harness::run_source("
+    spin:
+        inc a
+        jmp spin
+        ; @test runs out of cycles
+        ; @cycles 10
+        ; @running
+        ; @reg a = 5
+        ; @test halts
+    ").unwrap();
+    assert!(results[0].passed(), "{:?}", results[0].failures);
+    assert_eq!(results[1].failures, vec!["expected to halt after 100000 cycles, but it was still running at PC 0".to_string()]);
+
+    let results = harness::run_source("mov a, 1\ndiv a, 0\n; @test\n; @fault 4").unwrap();
+    assert!(results[0].passed(), "{:?}", results[0].failures);
+    let results = harness::run_source("mov a, 1\ndiv a, 0\n; @test\n; @fault 1").unwrap();
+    assert!(!results[0].passed());
+}
+
+#[test]
+fn assembly_errors() {
+    let err = harness::run_source("frob a\n; @test").unwrap_err();
+    assert_eq!(err.to_string(), assembler::assemble("frob a").unwrap_err()[0].to_string());
+}
+
+#[test]
+#[should_panic(expected = "1 of 1 tests failed")]
+fn check_file_panics_on_failure() {
+    let path = std::env::temp_dir().join(format!("zpu-harness-{}.asm", std::process::id()));
+    std::fs::write(&path, "mov a, 1\n; @test\n; @reg a = 2").unwrap();
+    harness::check_file(&path);
+}
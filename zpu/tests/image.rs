@@ -0,0 +1,71 @@
+extern crate zpu;
+
+mod common;
+
+use std::{env, fs, process};
+
+use zpu::assembler;
+use zpu::encoding::ImageError;
+use zpu::zpu::{LoadError, Output, RunOutcome, ZPU};
+
+use common::run;
+
+const SOURCE: &str = "
+    mov a, 6
+    mul a, 7
+    mov c, 2
+    out c, a
+";
+
+#[test]
+fn assembled_bytes_run() {
+    let bytes = assembler::assemble(SOURCE).unwrap().to_bytes();
+    let mut zpu = ZPU::from_bytes(&bytes).unwrap();
+    let output = run(&mut zpu).output.unwrap();
+    assert_eq!((output.port, output.data), (2, 42));
+    assert!(!run(&mut zpu).running);
+}
+
+#[test]
+fn load_bytes_resets_the_machine() {
+    let mut zpu = ZPU::from_bytes(&assembler::assemble("mov b, 9\nmset [4], b").unwrap().to_bytes()).unwrap();
+    assert!(!run(&mut zpu).running);
+
+    zpu.load_bytes(&assembler::assemble(SOURCE).unwrap().to_bytes()).unwrap();
+    assert!(zpu.running);
+    assert_eq!((zpu.pc, zpu.registers[1], zpu.memory[4]), (0, 0, 0));
+    assert_eq!(run(&mut zpu).output.unwrap().data, 42);
+}
+
+#[test]
+fn bad_images_are_rejected() {
+    assert!(matches!(ZPU::from_bytes(b"not a program"), Err(ImageError::BadMagic(_))));
+
+    let bytes = assembler::assemble(SOURCE).unwrap().to_bytes();
+    let mut zpu = ZPU::from_bytes(&bytes).unwrap();
+    zpu.step();
+    assert_eq!(zpu.load_bytes(&bytes[..bytes.len() - 2]), Err(ImageError::Truncated));
+    // The program that was loaded is left alone.
+    assert_eq!((zpu.pc, zpu.registers[0]), (2, 6));
+    assert_eq!(run(&mut zpu).output.unwrap().data, 42);
+}
+
+#[test]
+fn program_files() {
+    let path = env::temp_dir().join(format!("zpu-image-{}.bin", process::id()));
+    let name = path.to_str().unwrap();
+    assert!(matches!(ZPU::new(name), Err(LoadError::Io(_))));
+
+    fs::write(&path, assembler::assemble(SOURCE).unwrap().to_bytes()).unwrap();
+    let mut zpu = ZPU::new(name).unwrap();
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(2, 42)));
+    zpu.load_program(name).unwrap();
+    assert_eq!(zpu.pc, 0);
+
+    fs::write(&path, b"not a program").unwrap();
+    assert!(matches!(ZPU::new(name), Err(LoadError::Image(ImageError::BadMagic(_)))));
+    assert!(matches!(zpu.load_program(name), Err(LoadError::Image(_))));
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(zpu.load_program(name), Err(LoadError::Io(_))));
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(2, 42)));
+}
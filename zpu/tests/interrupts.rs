@@ -0,0 +1,96 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::interrupt::{InterruptController, DEFAULT_VECTOR_BASE, IRQ_LINES};
+use zpu::zpu::{RunOutcome, DEFAULT_MEMORY_SIZE};
+
+use common::load;
+
+#[test]
+fn controller_takes_the_lowest_line_once_enabled() {
+    let mut irqs = InterruptController::new();
+    assert!(irqs.raise(9));
+    assert!(irqs.raise(3));
+    assert!(!irqs.raise(IRQ_LINES));
+    assert!(irqs.is_pending(3) && irqs.is_pending(9) && !irqs.is_pending(IRQ_LINES));
+    assert_eq!(irqs.acknowledge(), None);
+
+    irqs.enabled = true;
+    assert_eq!(irqs.acknowledge(), Some(3));
+    assert_eq!(irqs.acknowledge(), Some(9));
+    assert_eq!(irqs.acknowledge(), None);
+
+    assert_eq!(irqs.fault_vector_addr() as usize, DEFAULT_MEMORY_SIZE - 1);
+    irqs.vector_base = 100;
+    assert_eq!(irqs.vector_addr(2), 102);
+    assert_eq!(irqs.fault_vector_addr(), 116);
+
+    irqs.raise(1);
+    irqs.reset();
+    assert_eq!((irqs.pending, irqs.enabled, irqs.vector_base), (0, false, 100));
+}
+
+#[test]
+fn handler_runs_and_iret_returns() {
+    let source = "
+        .data
+        .org 4080
+        .word handler
+        .code
+        ei
+        cmp a, 1
+    spin:
+        cmp b, 0
+        je spin
+        hlt
+    handler:
+        mov b, 1
+        iret
+    ";
+    assert_eq!(DEFAULT_VECTOR_BASE, 4079);
+    let mut zpu = load(source);
+    assert_eq!(zpu.run(10), RunOutcome::Budget);
+    zpu.raise_irq(1);
+    zpu.step();
+    assert!(!zpu.interrupts.enabled);
+    assert_eq!(zpu.sp, 2);
+    assert_eq!(zpu.run(100), RunOutcome::Halted);
+    assert_eq!(zpu.registers[1], 1);
+    assert_eq!(zpu.sp, 0);
+    assert!(zpu.interrupts.enabled);
+    assert_eq!(zpu.cmp_flag, 1);
+}
+
+#[test]
+fn interrupts_without_a_handler_are_dropped() {
+    let mut zpu = load("ei\nspin: jmp spin");
+    zpu.raise_irq(4);
+    assert_eq!(zpu.run(100), RunOutcome::Budget);
+    assert!(!zpu.interrupts.is_pending(4));
+    assert_eq!(zpu.sp, 0);
+
+    // Pending lines wait while interrupts are off.
+    let mut zpu = load("spin: jmp spin");
+    zpu.raise_irq(4);
+    assert_eq!(zpu.run(100), RunOutcome::Budget);
+    assert!(zpu.interrupts.is_pending(4));
+}
+
+#[test]
+fn data_is_not_a_vector_table() {
+    let source = "
+        .data
+        .word 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18
+        .code
+        ei
+    spin:
+        jmp spin
+    ";
+    let mut zpu = load(source);
+    zpu.raise_irq(0);
+    zpu.raise_irq(15);
+    assert_eq!(zpu.run(100), RunOutcome::Budget);
+    assert_eq!(zpu.sp, 0);
+    assert_eq!(zpu.interrupts.pending, 0);
+}
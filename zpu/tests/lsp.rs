@@ -0,0 +1,188 @@
+#[macro_use]
+extern crate serde_json;
+extern crate zpu;
+
+use std::io::Cursor;
+
+use serde_json::Value;
+use zpu::lsp::{self, Server};
+use zpu::zpu::OPCODES;
+
+const SOURCE: &str = "\
+.include \"ports.inc\"
+start:
+    in a, SHIP_POWER
+    mmov b, [count]
+    cmp a, b
+    jmp start
+.data
+count: .word 0
+";
+
+fn uri() -> String {
+    format!("file://{}/tests/asm/ship.asm", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn open(server: &mut Server, text: &str) -> Vec<Value> {
+    server.handle(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": { "textDocument": { "uri": uri(), "languageId": "zpu", "version": 1, "text": text } },
+    }))
+}
+
+fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Value {
+    let mut replies = server.handle(&json!({
+        "jsonrpc": "2.0",
+        "id": 7,
+        "method": method,
+        "params": {
+            "textDocument": { "uri": uri() },
+            "position": { "line": line, "character": character },
+            "context": { "includeDeclaration": true },
+        },
+    }));
+    assert_eq!(replies.len(), 1);
+    let reply = replies.remove(0);
+    assert_eq!(reply["id"], 7);
+    reply["result"].clone()
+}
+
+fn range(line: u32, start: u32, end: u32) -> Value {
+    json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+}
+
+#[test]
+fn initialize() {
+    let mut server = Server::new();
+    let reply = server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }));
+    let capabilities = &reply[0]["result"]["capabilities"];
+    assert_eq!(capabilities["textDocumentSync"], 1);
+    assert_eq!(capabilities["definitionProvider"], true);
+
+    let reply = server.handle(&json!({ "jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {} }));
+    assert_eq!(reply[0]["error"]["code"], -32601);
+}
+
+#[test]
+fn diagnostics() {
+    let mut server = Server::new();
+    let published = open(&mut server, SOURCE);
+    assert_eq!(published[0]["method"], "textDocument/publishDiagnostics");
+    assert_eq!(published[0]["params"]["diagnostics"], json!([]));
+
+    let published = server.handle(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didChange",
+        "params": {
+            "textDocument": { "uri": uri(), "version": 2 },
+            "contentChanges": [{ "text": "frob a\njmp nowhere\n" }],
+        },
+    }));
+    let diagnostics = &published[0]["params"]["diagnostics"];
+    assert_eq!(diagnostics[0]["range"], range(0, 0, 4));
+    assert_eq!(diagnostics[0]["message"], "unknown instruction `frob`");
+    assert_eq!(diagnostics[1]["range"], range(1, 4, 11));
+    assert_eq!(diagnostics[1]["message"], "label `nowhere` is never defined");
+}
+
+#[test]
+fn definitions_and_references() {
+    let mut server = Server::new();
+    open(&mut server, SOURCE);
+
+    assert_eq!(request(&mut server, "textDocument/definition", 5, 9), json!({ "uri": uri(), "range": range(1, 0, 5) }));
+    assert_eq!(request(&mut server, "textDocument/definition", 3, 14), json!({ "uri": uri(), "range": range(7, 0, 5) }));
+
+    let ports = request(&mut server, "textDocument/definition", 2, 12);
+    assert!(ports["uri"].as_str().unwrap().ends_with("/tests/asm/ports.inc"));
+    assert_eq!(ports["range"], range(9, 5, 15));
+
+    assert_eq!(request(&mut server, "textDocument/references", 7, 2), json!([
+        { "uri": uri(), "range": range(7, 0, 5) },
+        { "uri": uri(), "range": range(3, 13, 18) },
+    ]));
+    assert_eq!(request(&mut server, "textDocument/definition", 4, 4), Value::Null);
+}
+
+#[test]
+fn positions_count_utf16_code_units() {
+    // U+3000 is one code unit of whitespace, and each of 𝑘, 𝑙 and 𝑝 takes two.
+    let source = ".equ\u{3000}𝑘, 5\n𝑙𝑝: add a, 𝑘\n    jmp 𝑙𝑝\n";
+    let mut server = Server::new();
+    assert_eq!(open(&mut server, source)[0]["params"]["diagnostics"], json!([]));
+
+    assert_eq!(request(&mut server, "textDocument/references", 1, 13), json!([
+        { "uri": uri(), "range": range(0, 5, 7) },
+        { "uri": uri(), "range": range(1, 13, 15) },
+    ]));
+    assert_eq!(request(&mut server, "textDocument/definition", 2, 9), json!({ "uri": uri(), "range": range(1, 0, 4) }));
+    assert_eq!(request(&mut server, "textDocument/definition", 2, 3), Value::Null);
+    assert!(request(&mut server, "textDocument/hover", 1, 6)["contents"]["value"].as_str().unwrap().starts_with("**ADD**"));
+
+    let diagnostics = &open(&mut server, "𝑙𝑝: jmp 𝑞\n")[0]["params"]["diagnostics"];
+    assert_eq!(diagnostics[0]["range"], range(0, 10, 12));
+}
+
+#[test]
+fn hover() {
+    let mut server = Server::new();
+    open(&mut server, SOURCE);
+    let hover = request(&mut server, "textDocument/hover", 3, 6);
+    assert_eq!(hover["contents"]["value"],
+               "**MMOV** `r1, [mem]`\n\nMove u32 in memory address into R1\n\nOpcode 0xD, 2 cycles");
+    let hover = request(&mut server, "textDocument/hover", 4, 4);
+    assert!(hover["contents"]["value"].as_str().unwrap().starts_with("**CMP**"));
+    assert_eq!(request(&mut server, "textDocument/hover", 1, 2), Value::Null);
+}
+
+#[test]
+fn opcode_docs_match_the_readme() {
+    let readme = include_str!("../README.md");
+    for opcode in OPCODES.iter() {
+        let row = format!("| {:<7} | {:<9} | {}", opcode.mnemonic().to_uppercase(), opcode.syntax(),
+                          opcode.description().replace('|', "\\|"));
+        assert!(readme.contains(&row), "{}", row);
+    }
+}
+
+#[test]
+fn completion() {
+    let mut server = Server::new();
+    open(&mut server, SOURCE);
+    let items = request(&mut server, "textDocument/completion", 2, 10);
+    let labels: Vec<&str> = items.as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap()).collect();
+    assert_eq!(&labels[..8], &["a", "b", "c", "d", "e", "x", "y", "z"]);
+    assert!(labels.contains(&"SHIP_POWER"));
+    assert!(!labels.contains(&"start"));
+    let power = items.as_array().unwrap().iter().find(|item| item["label"] == "SHIP_POWER").unwrap();
+    assert_eq!(power["detail"], "= 7");
+}
+
+#[test]
+fn serves_framed_messages() {
+    let mut input = Vec::new();
+    for message in &[
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+        json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+        json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+        json!({ "jsonrpc": "2.0", "method": "exit" }),
+    ] {
+        lsp::write_message(&mut input, message).unwrap();
+    }
+    input.extend_from_slice(b"Content-Length: 3\r\n\r\n{]}");
+
+    let mut output = Vec::new();
+    assert!(lsp::serve(Cursor::new(input), &mut output).unwrap());
+    let mut output = Cursor::new(output);
+    let first = lsp::read_message(&mut output).unwrap().unwrap();
+    assert_eq!(first["id"], 1);
+    let second = lsp::read_message(&mut output).unwrap().unwrap();
+    assert_eq!(second, json!({ "jsonrpc": "2.0", "id": 2, "result": null }));
+    // Nothing is read after `exit`.
+    assert!(lsp::read_message(&mut output).unwrap().is_none());
+
+    let mut output = Vec::new();
+    assert!(!lsp::serve(Cursor::new(&b"Content-Length: 3\r\n\r\n{]}"[..]), &mut output).unwrap());
+    assert_eq!(lsp::read_message(&mut Cursor::new(output)).unwrap().unwrap()["error"]["code"], -32700);
+}
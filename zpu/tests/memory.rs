@@ -0,0 +1,54 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::zpu::{Fault, DEFAULT_MEMORY_SIZE};
+
+use common::{load, run};
+
+#[test]
+fn direct_and_register_addresses() {
+    let mut zpu = load("mov a, 7\nmset [10], a\nmov b, 10\nmmov c, [b]\nmov d, 4095\nmset [d], c\nmmov e, [4095]");
+    assert_eq!(run(&mut zpu).fault, None);
+    assert_eq!(zpu.memory[10], 7);
+    assert_eq!(zpu.memory[4095], 7);
+    assert_eq!(&zpu.registers[2..5], &[7, 4095, 7]);
+}
+
+#[test]
+fn reading_past_memory_faults() {
+    let mut zpu = load("mov a, 1\nmmov a, [4096]\nmov a, 2");
+    assert_eq!(run(&mut zpu).fault, Some(Fault::MemoryOutOfBounds(4096)));
+    assert_eq!(zpu.fault, Some(Fault::MemoryOutOfBounds(4096)));
+    assert!(!zpu.running);
+    assert_eq!(zpu.registers[0], 1);
+
+    let mut zpu = load("mov b, 4294967295\nmmov a, [b]");
+    assert_eq!(run(&mut zpu).fault, Some(Fault::MemoryOutOfBounds(0xffff_ffff)));
+}
+
+#[test]
+fn writing_past_memory_faults() {
+    let mut zpu = load("mov a, 5\nmov b, 4096\nmset [b], a\nmov a, 6");
+    assert_eq!(run(&mut zpu).fault, Some(Fault::MemoryOutOfBounds(4096)));
+    assert_eq!(zpu.registers[0], 5);
+    assert!(zpu.memory.iter().all(|word| *word == 0));
+    assert_eq!(zpu.memory.len(), DEFAULT_MEMORY_SIZE);
+}
+
+#[test]
+fn resizing_memory() {
+    let mut zpu = load("mov a, 3\nmset [20], a\nmmov b, [20]");
+    zpu.resize_memory(16);
+    assert_eq!(run(&mut zpu).fault, Some(Fault::MemoryOutOfBounds(20)));
+
+    zpu.resize_memory(32);
+    assert_eq!(zpu.write_memory(31, 9), Ok(()));
+    assert_eq!(zpu.read_memory(31), Ok(9));
+    assert_eq!(zpu.read_memory(32), Err(Fault::MemoryOutOfBounds(32)));
+    assert_eq!(zpu.write_memory(32, 9), Err(Fault::MemoryOutOfBounds(32)));
+
+    zpu.resize_memory(8);
+    zpu.resize_memory(32);
+    assert_eq!(zpu.read_memory(31), Ok(0));
+}
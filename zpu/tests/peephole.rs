@@ -0,0 +1,138 @@
+extern crate zpu;
+
+use zpu::assembler::{self, Options, Program, Rewrite, Span};
+use zpu::zpu::{Output, RunOutcome, ZPU};
+
+fn optimised(source: &str) -> (Program, Vec<Rewrite>) {
+    assembler::assemble_with_options(source, &mut |_| None, Options { optimise: true }).unwrap()
+}
+
+fn assemble(source: &str) -> Program {
+    assembler::assemble(source).unwrap()
+}
+
+/// Runs to the end, returning the outputs and the cycles it took.
+fn run(program: &Program) -> (Vec<Output>, u64) {
+    let mut zpu = ZPU::from_program(program);
+    let mut outputs = Vec::new();
+    loop {
+        match zpu.run(100_000) {
+            RunOutcome::Output(output) => outputs.push(output),
+            RunOutcome::Halted => return (outputs, zpu.cycles),
+            outcome => panic!("{:?}", outcome),
+        }
+    }
+}
+
+#[test]
+fn folds_move_and_add() {
+    let (program, rewrites) = optimised("mov a, 0\nadd a, 5\nout a, a");
+    assert_eq!(program.code, assemble("mov a, 5\nout a, a").code);
+    assert_eq!(rewrites, vec![Rewrite::FoldedMoveAdd(Span::new(1, 1, 3))]);
+
+    let (program, _) = optimised("mov a, 0\nadd a, b");
+    assert_eq!(program.code, assemble("mov a, b").code);
+
+    for source in &["mov a, 0\nagain: add a, 5\njmp again", "mov a, 0\nadd a, a", "mov a, 0\nadd b, 5", "mov a, 1\nadd a, 5"] {
+        let (program, rewrites) = optimised(source);
+        assert_eq!(rewrites, vec![], "{}", source);
+        assert_eq!(program, assemble(source));
+    }
+}
+
+#[test]
+fn removes_jumps_to_next() {
+    let (program, rewrites) = optimised("jmp next\nnext: out a, a");
+    assert_eq!(program, assemble("next: out a, a"));
+    assert_eq!(rewrites, vec![Rewrite::JumpToNext(Span::new(1, 1, 3))]);
+
+    let (program, rewrites) = optimised("cmp a, 0\nje done\n; nothing here\ndone:\n\nhlt");
+    assert_eq!(program, assemble("cmp a, 0\ndone: hlt"));
+    assert_eq!(rewrites.len(), 1);
+
+    let (program, _) = optimised("mov a, 1\njmp end\nend:");
+    assert_eq!(program, assemble("mov a, 1\nend:"));
+
+    let source = "jmp skip\nmov a, 1\nskip: hlt";
+    assert_eq!(optimised(source), (assemble(source), vec![]));
+    let source = "call next\nnext: hlt";
+    assert_eq!(optimised(source), (assemble(source), vec![]));
+}
+
+#[test]
+fn removes_repeated_compares() {
+    let (program, rewrites) = optimised("
+        cmp a, 1
+        je one
+        cmp a, 1
+        jg big
+        hlt
+    one:
+    big:
+        hlt
+    ");
+    assert_eq!(program, assemble("
+        cmp a, 1
+        je one
+        jg big
+        hlt
+    one:
+    big:
+        hlt
+    "));
+    assert_eq!(rewrites, vec![Rewrite::RepeatedCompare(Span::new(4, 9, 3))]);
+
+    for source in &["cmp a, 1\nadd a, 1\ncmp a, 1", "cmp a, 1\nagain: cmp a, 1\njmp again", "cmp a, 1\ncmp a, 2"] {
+        assert_eq!(optimised(source), (assemble(source), vec![]), "{}", source);
+    }
+}
+
+#[test]
+fn keeps_behaviour() {
+    let source = "
+        mov a, 0
+        add a, 3
+    loop:
+        mov c, 0
+        out c, a
+        sub a, 1
+        cmp a, 0
+        jz done
+        cmp a, 0
+        jmp next
+    next:
+        jmp loop
+    done:
+        hlt
+    ";
+    let (program, rewrites) = optimised(source);
+    assert_eq!(rewrites.len(), 3);
+    let (outputs, cycles) = run(&program);
+    let (expected, unoptimised) = run(&assemble(source));
+    assert_eq!(outputs, expected);
+    // The fold runs once, and the last time round the loop leaves before the second `cmp`.
+    assert_eq!(unoptimised - cycles, 1 + 2 * 2);
+    assert_eq!(program.symbol("done").unwrap().addr, assemble(source).symbol("done").unwrap().addr - 6);
+}
+
+#[test]
+fn off_by_default() {
+    let source = "mov a, 0\nadd a, 5\njmp next\nnext: hlt";
+    assert_ne!(optimised(source).0, assemble(source));
+    let (program, rewrites) = assembler::assemble_with_options(source, &mut |_| None, Options::default()).unwrap();
+    assert_eq!(program, assemble(source));
+    assert_eq!(rewrites, vec![]);
+}
+
+#[test]
+fn reports_where_code_came_from() {
+    let (_, rewrites) = optimised("
+    .macro skip
+        jmp %%over
+    %%over:
+    .endm
+        skip
+    ");
+    assert_eq!(rewrites, vec![Rewrite::JumpToNext(Span::new(6, 9, 4))]);
+    assert_eq!(rewrites[0].to_string(), "6:9: removed jump to the next instruction");
+}
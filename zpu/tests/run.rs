@@ -0,0 +1,143 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::assembler;
+use zpu::debugger::{Stop, Watch};
+use zpu::encoding::{self, DecodedInst, Instruction, Program};
+use zpu::zpu::{Fault, Opcode, Output, Register, RunOutcome, ZPU};
+
+use common::load;
+
+const COUNTDOWN: &str = "
+    mov a, 3
+loop:
+    out b, a
+    sub a, 1
+    cmp a, 0
+    jn loop
+done:
+    hlt
+";
+
+#[test]
+fn stops_for_output_and_halt() {
+    let mut zpu = load(COUNTDOWN);
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 3)));
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 2)));
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 1)));
+    assert_eq!(zpu.run(1000), RunOutcome::Halted);
+    assert_eq!(zpu.run(1000), RunOutcome::Halted);
+}
+
+#[test]
+fn budget() {
+    let mut zpu = load("spin: inc a\njmp spin");
+    assert_eq!(zpu.run(100), RunOutcome::Budget);
+    assert_eq!(zpu.registers[0], 50);
+    assert_eq!(zpu.run(0), RunOutcome::Budget);
+    assert_eq!(zpu.registers[0], 50);
+
+    // No limit at all, once some cycles have already run.
+    let mut zpu = load(COUNTDOWN);
+    zpu.run(1);
+    assert_eq!(zpu.run(usize::MAX), RunOutcome::Output(Output::new(0, 3)));
+}
+
+#[test]
+fn faults_and_breakpoints() {
+    let mut zpu = load("pop a");
+    assert_eq!(zpu.run(10), RunOutcome::Fault(Fault::StackUnderflow));
+    assert_eq!(zpu.run(10), RunOutcome::Fault(Fault::StackUnderflow));
+
+    let mut zpu = load(COUNTDOWN);
+    let done = zpu.program.symbol("done").unwrap().addr;
+    let sub = zpu.program.symbol("loop").unwrap().addr + 1;
+    zpu.debugger.add_breakpoint(done);
+    zpu.debugger.add_breakpoint(sub);
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 3)));
+    assert_eq!(zpu.run(1000), RunOutcome::Breakpoint(sub));
+    zpu.debugger.remove_breakpoint(sub);
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 2)));
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 1)));
+    assert_eq!(zpu.run(1000), RunOutcome::Breakpoint(done));
+    assert_eq!(zpu.run(1000), RunOutcome::Halted);
+}
+
+#[test]
+fn breakpoint_on_an_interrupt_handler() {
+    let source = "
+        mov a, handler
+        mset [4079], a
+        ei
+    spin:
+        jmp spin
+    handler:
+        inc b
+        iret
+    ";
+    let mut zpu = load(source);
+    let handler = zpu.program.symbol("handler").unwrap().addr;
+    zpu.debugger.add_breakpoint(handler);
+    assert_eq!(zpu.run(20), RunOutcome::Budget);
+    zpu.raise_irq(0);
+    assert_eq!(zpu.run(50), RunOutcome::Breakpoint(handler));
+    assert_eq!(zpu.registers[1], 0);
+
+    let mut zpu = load(source);
+    zpu.debugger.add_breakpoint(handler);
+    zpu.run(20);
+    zpu.raise_irq(0);
+    assert_eq!(zpu.resume(50), Stop::Breakpoint(handler));
+    assert_eq!(zpu.registers[1], 0);
+}
+
+#[test]
+fn watchpoints() {
+    let mut zpu = load(COUNTDOWN);
+    zpu.debugger.watch(Watch::Register(Register::A));
+    assert_eq!(zpu.run(1000), RunOutcome::Watchpoint(Watch::Register(Register::A), 0, 3));
+    assert_eq!(zpu.run(1000), RunOutcome::Output(Output::new(0, 3)));
+    assert_eq!(zpu.run(1000), RunOutcome::Watchpoint(Watch::Register(Register::A), 3, 2));
+}
+
+#[test]
+fn runs_the_same_as_decoding_every_step() {
+    let source = "
+        mov a, 10
+    loop:
+        mov b, a
+        mul b, b
+        add c, b
+        sub a, 1
+        cmp a, 0
+        jn loop
+        hlt
+    ";
+    let mut cached = load(source);
+    assert_eq!(cached.run(10_000), RunOutcome::Halted);
+
+    let mut uncached = load(source);
+    while uncached.running {
+        let inst = encoding::decode(&uncached.program.code, uncached.pc).unwrap();
+        uncached.execute(inst.opcode, inst.reg1, inst.reg2, inst.imm).unwrap();
+    }
+    assert_eq!(cached.registers, uncached.registers);
+    assert_eq!(cached.registers[2], 385);
+}
+
+#[test]
+fn running_off_the_end_faults() {
+    let mut code = Vec::new();
+    encoding::encode(&Instruction::new(Opcode::Increment, Register::A, Register::Null, None), &mut code);
+    encoding::encode(&Instruction::new(Opcode::Jump, Register::Null, Register::Null, Some(0)), &mut code);
+    code.pop();
+    assert_eq!(encoding::decode_program(&code)[1], DecodedInst::Invalid(Fault::TruncatedImmediate(1)));
+
+    let mut zpu = ZPU::from_program(&Program { code, ..Default::default() });
+    assert_eq!(zpu.run(10), RunOutcome::Fault(Fault::TruncatedImmediate(1)));
+    assert_eq!(zpu.registers[0], 1);
+
+    zpu.load(&assembler::assemble("jmp 100").unwrap());
+    assert_eq!(zpu.run(10), RunOutcome::Fault(Fault::PcOutOfRange(100)));
+}
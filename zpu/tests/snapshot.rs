@@ -0,0 +1,131 @@
+extern crate zpu;
+
+use zpu::assembler;
+use zpu::snapshot::{SnapshotError, ZpuSnapshot, SNAPSHOT_VERSION};
+use zpu::zpu::{Output, ZPU};
+
+const COUNTER: &str = "
+    mov a, handler
+    mset [4080], a
+    ei
+loop:
+    push b
+    inc b
+    mset [8], b
+    cmp b, 3
+    jl loop
+    out b, b
+    pop b
+    cmp b, 0
+    jn loop
+    hlt
+handler:
+    inc c
+    iret
+";
+
+fn run(zpu: &mut ZPU, steps: usize) -> Vec<Output> {
+    let mut outputs = Vec::new();
+    for _ in 0..steps {
+        let result = zpu.step();
+        outputs.extend(result.output);
+        if !result.running {
+            break;
+        }
+    }
+    outputs
+}
+
+#[test]
+fn disk_round_trip_resumes_exactly() {
+    let mut zpu = ZPU::from_program(&assembler::assemble(COUNTER).unwrap());
+    run(&mut zpu, 10);
+    zpu.raise_irq(1);
+    zpu.raise_irq(5);
+    assert!(zpu.sp > 0);
+
+    let bytes = zpu.snapshot().to_bytes();
+    let snapshot = ZpuSnapshot::from_bytes(&bytes).unwrap();
+    assert_eq!(snapshot, zpu.snapshot());
+    assert_eq!(snapshot.to_bytes(), bytes);
+    let mut restored = ZPU::from_snapshot(&snapshot);
+    assert!(zpu.cycles > 0);
+    assert_eq!(restored.cycles, zpu.cycles);
+
+    let expected = run(&mut zpu, 200);
+    assert_eq!(run(&mut restored, 200), expected);
+    assert!(!expected.is_empty());
+    assert_eq!(restored.snapshot(), zpu.snapshot());
+    assert_eq!(restored.registers[2], 1);
+}
+
+#[test]
+fn restore_rewinds_a_running_zpu() {
+    let mut zpu = ZPU::from_program(&assembler::assemble(COUNTER).unwrap());
+    run(&mut zpu, 7);
+    let snapshot = zpu.snapshot();
+    let first = run(&mut zpu, 20);
+    zpu.restore(&snapshot);
+    assert_eq!(zpu.snapshot(), snapshot);
+    assert_eq!(run(&mut zpu, 20), first);
+
+    let mut other = ZPU::from_program(&assembler::assemble("hlt").unwrap());
+    other.restore(&snapshot);
+    assert_eq!(other.snapshot(), snapshot);
+}
+
+#[test]
+fn reload_keeping_registers() {
+    let mut zpu = ZPU::from_program(&assembler::assemble("mov a, 7\nmov x, 9\npush a\nhlt").unwrap());
+    run(&mut zpu, 10);
+    let program = assembler::assemble("add a, x\nout a, a\nhlt").unwrap();
+
+    zpu.load_keeping_registers(&program);
+    assert_eq!((zpu.pc, zpu.sp, zpu.running), (0, 0, true));
+    let outputs = run(&mut zpu, 10);
+    assert_eq!(outputs[0].data, 16);
+
+    zpu.load(&program);
+    assert_eq!(run(&mut zpu, 10)[0].data, 0);
+}
+
+#[test]
+fn rejects_bad_snapshots() {
+    let zpu = ZPU::from_program(&assembler::assemble(COUNTER).unwrap());
+    let bytes = zpu.snapshot().to_bytes();
+    assert_eq!(ZpuSnapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated));
+    assert_eq!(ZpuSnapshot::from_bytes(&[0; 8]), Err(SnapshotError::BadMagic(0)));
+
+    let mut newer = bytes.clone();
+    newer[4] = SNAPSHOT_VERSION as u8 + 1;
+    assert_eq!(ZpuSnapshot::from_bytes(&newer), Err(SnapshotError::UnsupportedVersion(SNAPSHOT_VERSION + 1)));
+
+    // The fault code follows the magic, version, registers, PC, SP and six flag words.
+    let mut faulted = bytes.clone();
+    faulted[4 * 18] = 99;
+    assert_eq!(ZpuSnapshot::from_bytes(&faulted), Err(SnapshotError::BadFault(99)));
+}
+
+#[test]
+fn rejects_states_a_zpu_cant_be_in() {
+    let mut zpu = ZPU::from_program(&assembler::assemble(COUNTER).unwrap());
+    zpu.resize_memory(10);
+    assert_eq!(zpu.interrupts.vector_base, 0);
+    let good = zpu.snapshot();
+    assert_eq!(ZpuSnapshot::from_bytes(&good.to_bytes()), Ok(good.clone()));
+
+    let mut sp = good.clone();
+    sp.sp = sp.stack.len() as u32 + 1;
+    assert!(matches!(ZpuSnapshot::from_bytes(&sp.to_bytes()), Err(SnapshotError::BadState(_))));
+
+    let mut memory = good.clone();
+    memory.memory.clear();
+    assert!(matches!(ZpuSnapshot::from_bytes(&memory.to_bytes()), Err(SnapshotError::BadState(_))));
+
+    let mut vectors = good.clone();
+    vectors.memory = vec![0; 40];
+    vectors.vector_base = 24;
+    assert!(matches!(ZpuSnapshot::from_bytes(&vectors.to_bytes()), Err(SnapshotError::BadState(_))));
+    vectors.vector_base = 23;
+    assert_eq!(ZpuSnapshot::from_bytes(&vectors.to_bytes()), Ok(vectors));
+}
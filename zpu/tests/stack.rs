@@ -0,0 +1,72 @@
+extern crate zpu;
+
+mod common;
+
+use zpu::zpu::{Fault, DEFAULT_STACK_SIZE};
+
+use common::{load, run};
+
+#[test]
+fn push_and_pop_are_last_in_first_out() {
+    let mut zpu = load("mov a, 1\nmov b, 2\npush a\npush b\nmov c, 3\npush c\npop c\npop d\npop e");
+    assert_eq!(run(&mut zpu).fault, None);
+    assert_eq!(&zpu.registers[2..5], &[3, 2, 1]);
+    assert_eq!(zpu.sp, 0);
+    assert_eq!(&zpu.stack[..3], &[1, 2, 3]);
+}
+
+#[test]
+fn call_pushes_the_return_address() {
+    let mut zpu = load("call sub\nsub:\nret");
+    zpu.step();
+    assert_eq!(zpu.sp, 1);
+    assert_eq!(zpu.stack[0], 2);
+    assert_eq!(zpu.pc, 2);
+    zpu.step();
+    assert_eq!(zpu.sp, 0);
+    assert_eq!(zpu.pc, 2);
+}
+
+#[test]
+fn overflow() {
+    let mut zpu = load("push a\npush a\npush a\npush a\npush a\nmov a, 1");
+    zpu.resize_stack(4);
+    assert_eq!(run(&mut zpu).fault, Some(Fault::StackOverflow));
+    assert_eq!(zpu.sp, 4);
+    assert!(!zpu.running);
+
+    let mut zpu = load("mov a, 1");
+    for value in 0..DEFAULT_STACK_SIZE {
+        zpu.push_word(value as u32).unwrap();
+    }
+    assert_eq!(zpu.push_word(0), Err(Fault::StackOverflow));
+    assert_eq!(zpu.sp as usize, DEFAULT_STACK_SIZE);
+}
+
+#[test]
+fn underflow() {
+    assert_eq!(run(&mut load("pop a")).fault, Some(Fault::StackUnderflow));
+    assert_eq!(run(&mut load("ret")).fault, Some(Fault::StackUnderflow));
+    assert_eq!(run(&mut load("push a\npop a\npop a")).fault, Some(Fault::StackUnderflow));
+}
+
+#[test]
+fn resizing_clamps_sp() {
+    let mut zpu = load("mov a, 1");
+    for value in 0..6 {
+        zpu.push_word(value).unwrap();
+    }
+    zpu.resize_stack(3);
+    assert_eq!(zpu.sp, 3);
+    assert_eq!(zpu.push_word(9), Err(Fault::StackOverflow));
+    assert_eq!(zpu.pop_word(), Ok(2));
+}
+
+#[test]
+fn sp_past_the_stack_faults() {
+    let mut zpu = load("pop a");
+    zpu.sp = 1000;
+    assert_eq!(zpu.pop_word(), Err(Fault::StackOverflow));
+    assert_eq!(zpu.sp, 1000);
+    assert_eq!(run(&mut zpu).fault, Some(Fault::StackOverflow));
+}
@@ -0,0 +1,130 @@
+extern crate zpu;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use zpu::assembler;
+use zpu::bus::Latch;
+use zpu::trace::{self, Event, StepRecord, TraceError};
+use zpu::zpu::{Register, ZPU};
+
+const TURRET: &str = "
+    mov a, handler
+    mset [4079], a
+    mov d, 3
+    ei
+loop:
+    in b, 7
+    out d, b
+    cmp c, 0
+    je loop
+    hlt
+handler:
+    mov c, 1
+    iret
+";
+
+/// Runs `TURRET` with the ship power latch reading `power`, raising IRQ 0 after `irq_at` steps.
+fn record(power: u32, irq_at: usize) -> (ZPU, Vec<u8>) {
+    let program = assembler::assemble(TURRET).unwrap();
+    let mut zpu = ZPU::from_program(&program);
+    zpu.bus.attach(&[7], Box::new(Latch::new(Rc::new(Cell::new(power)))));
+    zpu.start_trace();
+    for n in 0..100 {
+        if n == irq_at {
+            zpu.raise_irq(0);
+        }
+        if !zpu.step().running {
+            break;
+        }
+    }
+    let bytes = zpu.stop_trace().unwrap().to_bytes();
+    (zpu, bytes)
+}
+
+#[test]
+fn records_steps_io_and_irqs() {
+    let (zpu, bytes) = record(42, 8);
+    assert!(!zpu.running);
+    let (_, events) = trace::parse(&bytes).unwrap();
+
+    let handler = zpu.program.symbol("handler").unwrap().addr;
+    match events[0] {
+        Event::Step(ref step) => assert_eq!(*step, StepRecord {
+            pc: 0,
+            registers: vec![(Register::A, handler)],
+            ..Default::default()
+        }),
+        ref event => panic!("unexpected event {:?}", event),
+    }
+    assert!(events.contains(&Event::Irq(0)));
+    let io: Vec<&StepRecord> = events.iter().filter_map(|event| match *event {
+        Event::Step(ref step) if step.input.is_some() || step.output.is_some() => Some(step),
+        _ => None,
+    }).collect();
+    assert_eq!((io[0].input, io[0].output), (Some((7, 42)), None));
+    assert_eq!((io[1].input, io[1].output), (None, Some((3, 42))));
+
+    // A 12 byte header, then a tag, PC and mask per step, plus only what changed.
+    let steps = events.iter().filter(|event| matches!(**event, Event::Step(_))).count();
+    assert!(bytes.len() < 12 + steps * 16, "{} bytes for {} steps", bytes.len(), steps);
+}
+
+#[test]
+fn replay_matches_without_the_devices() {
+    let (recorded, bytes) = record(42, 8);
+    let mut zpu = ZPU::from_program(&recorded.program);
+    assert_eq!(trace::replay(&mut zpu, &bytes), Ok(None));
+    assert_eq!(zpu.registers, recorded.registers);
+    assert_eq!(zpu.pc, recorded.pc);
+    assert!(!zpu.bus.is_mapped(7));
+}
+
+#[test]
+fn replay_leaves_the_host_devices_attached() {
+    let (recorded, bytes) = record(42, 8);
+    let mut zpu = ZPU::from_program(&recorded.program);
+    let power = Rc::new(Cell::new(99));
+    let turret = Rc::new(Cell::new(0));
+    zpu.bus.attach(&[7], Box::new(Latch::new(power.clone())));
+    zpu.bus.attach(&[3], Box::new(Latch::new(turret.clone())));
+    assert_eq!(trace::replay(&mut zpu, &bytes), Ok(None));
+
+    // IN saw the recorded power, and OUT still reached the host's turret.
+    assert_eq!(turret.get(), 42);
+    assert_eq!(zpu.bus.mapped_ports(), vec![3, 7]);
+    assert_eq!(zpu.bus.read(7), Some(99));
+    for _ in 0..3 {
+        trace::replay(&mut ZPU::from_program(&recorded.program), &bytes).unwrap();
+        zpu.load(&recorded.program);
+        trace::replay(&mut zpu, &bytes).unwrap();
+    }
+    assert_eq!(Rc::strong_count(&power), 2);
+}
+
+#[test]
+fn replay_reports_the_first_divergence() {
+    let (recorded, bytes) = record(42, 8);
+    let mut zpu = ZPU::from_program(&recorded.program);
+    zpu.registers[2] = 1;
+    let divergence = trace::replay(&mut zpu, &bytes).unwrap().unwrap();
+    assert_eq!(divergence.step, 6);
+    assert_eq!(divergence.expected.pc, zpu.program.symbol("loop").unwrap().addr + 3);
+    assert_eq!(divergence.expected.flags, Some(0b1001));
+    assert_eq!(divergence.actual.unwrap().flags, Some(0b100_1010));
+
+    // A trace that outlives the program.
+    let mut zpu = ZPU::from_program(&recorded.program);
+    zpu.running = false;
+    let divergence = trace::replay(&mut zpu, &bytes).unwrap().unwrap();
+    assert_eq!((divergence.step, divergence.actual), (0, None));
+}
+
+#[test]
+fn replay_checks_the_trace() {
+    let (_, bytes) = record(1, 8);
+    let mut other = ZPU::from_program(&assembler::assemble("hlt").unwrap());
+    assert_eq!(trace::replay(&mut other, &bytes), Err(TraceError::ProgramMismatch));
+    assert_eq!(trace::parse(&bytes[..bytes.len() - 1]).map(|_| ()), Err(TraceError::Truncated));
+    assert_eq!(trace::parse(&[0; 12]).map(|_| ()), Err(TraceError::BadMagic(0)));
+}